@@ -0,0 +1,514 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::util::{self, WrittenFile};
+
+/// How long a single provisioning command may run before it is killed.
+pub const DEFAULT_STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Executes commands on behalf of a [`Runner`].
+///
+/// [`SystemCommandRunner`] runs them for real; tests can give a runner a
+/// [`RecordingCommandRunner`] instead to check which commands provisioning
+/// would run without changing the system.
+pub trait CommandRunner: std::fmt::Debug + Send + Sync {
+    /// Run `command` to completion, writing `input` to its stdin if given,
+    /// and killing it if it runs longer than `timeout`. Its stdout and
+    /// stderr are captured in the returned output only if `capture` is set.
+    fn run(
+        &self,
+        command: &mut Command,
+        input: Option<&[u8]>,
+        capture: bool,
+        timeout: Duration,
+    ) -> Result<Output, Error>;
+}
+
+/// Runs the external commands provisioning relies on, such as `useradd`
+/// and `hostnamectl`, killing any that exceed the step timeout.
+///
+/// In dry-run mode commands are logged instead of run, and provisioning
+/// steps that change the system without a command check
+/// [`Runner::is_dry_run`] to do the same.
+///
+/// The runner also names the root directory of the system being
+/// provisioned, `/` unless set with [`Runner::root_dir`]. Steps write files
+/// under it with [`Runner::path`] and point commands at it, e.g. with
+/// `useradd --root`.
+///
+/// Files written with [`Runner::write_file`] are remembered, by the runner
+/// and its clones, for the provisioning report.
+#[derive(Debug, Clone)]
+pub struct Runner {
+    step_timeout: Duration,
+    dry_run: bool,
+    root_dir: PathBuf,
+    command_runner: Arc<dyn CommandRunner>,
+    written_files: Arc<Mutex<Vec<WrittenFile>>>,
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Self {
+            step_timeout: DEFAULT_STEP_TIMEOUT,
+            dry_run: false,
+            root_dir: PathBuf::from("/"),
+            command_runner: Arc::new(SystemCommandRunner),
+            written_files: Arc::default(),
+        }
+    }
+}
+
+impl Runner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step_timeout(mut self, step_timeout: Duration) -> Self {
+        self.step_timeout = step_timeout;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Provision the system whose root filesystem is mounted at `root_dir`,
+    /// such as an image being built, rather than the running system.
+    pub fn root_dir(mut self, root_dir: impl Into<PathBuf>) -> Self {
+        self.root_dir = root_dir.into();
+        self
+    }
+
+    /// Execute commands with `command_runner` instead of
+    /// [`SystemCommandRunner`].
+    pub fn command_runner(
+        mut self,
+        command_runner: Arc<dyn CommandRunner>,
+    ) -> Self {
+        self.command_runner = command_runner;
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// The root directory of the system being provisioned.
+    pub fn root(&self) -> &Path {
+        &self.root_dir
+    }
+
+    /// Whether the running system is being provisioned, rather than one
+    /// under another root directory.
+    pub fn is_system_root(&self) -> bool {
+        self.root_dir == Path::new("/")
+    }
+
+    /// Where `path`, as seen from the system being provisioned, is found
+    /// from the running system.
+    pub fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        self.root_dir.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// Write a file with [`util::write_file`], remembering its size and
+    /// checksum for [`Runner::written_files`].
+    pub fn write_file(
+        &self,
+        path: &Path,
+        bytes: &[u8],
+        mode: u32,
+        owner: Option<(u32, u32)>,
+    ) -> Result<(), Error> {
+        let written = util::write_file(path, bytes, mode, owner)?;
+        self.record_written_file(written);
+
+        Ok(())
+    }
+
+    // Remember a file written other than with [`Runner::write_file`], e.g.
+    // one validated before being renamed into place.
+    pub(crate) fn record_written_file(&self, written: WrittenFile) {
+        self.written_files.lock().unwrap().push(written);
+    }
+
+    /// The files written through this runner or its clones, in order.
+    pub fn written_files(&self) -> Vec<WrittenFile> {
+        self.written_files.lock().unwrap().clone()
+    }
+
+    /// Run `command` to completion and return its exit status.
+    pub fn status(&self, command: &mut Command) -> Result<ExitStatus, Error> {
+        Ok(self.run(command, None, false)?.status)
+    }
+
+    /// Like [`Runner::status`], but write `input` to the command's stdin.
+    pub fn status_with_input(
+        &self,
+        command: &mut Command,
+        input: &[u8],
+    ) -> Result<ExitStatus, Error> {
+        Ok(self.run(command, Some(input), false)?.status)
+    }
+
+    /// Run `command` to completion, capturing its stdout and stderr.
+    ///
+    /// Use this rather than [`Runner::status`] where a failure should be
+    /// reported with what the command printed; see
+    /// [`Error::SubprocessFailed`].
+    pub fn output(&self, command: &mut Command) -> Result<Output, Error> {
+        self.run(command, None, true)
+    }
+
+    /// Like [`Runner::output`], but run `command` in dry-run mode too.
+    ///
+    /// This is only for commands whose effects are undone before
+    /// provisioning ends, such as mounting the provisioning media
+    /// read-only to read the OVF environment from it.
+    pub fn output_even_in_dry_run(
+        &self,
+        command: &mut Command,
+    ) -> Result<Output, Error> {
+        self.command_runner
+            .run(command, None, true, self.step_timeout)
+    }
+
+    /// Like [`Runner::output`], but write `input` to the command's stdin.
+    pub fn output_with_input(
+        &self,
+        command: &mut Command,
+        input: &[u8],
+    ) -> Result<Output, Error> {
+        self.run(command, Some(input), true)
+    }
+
+    fn run(
+        &self,
+        command: &mut Command,
+        input: Option<&[u8]>,
+        capture: bool,
+    ) -> Result<Output, Error> {
+        if self.dry_run {
+            tracing::info!(?command, "Dry run, not running command");
+            return Ok(success());
+        }
+
+        self.command_runner
+            .run(command, input, capture, self.step_timeout)
+    }
+}
+
+/// Runs commands as child processes of azure-init.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn run(
+        &self,
+        command: &mut Command,
+        input: Option<&[u8]>,
+        capture: bool,
+        timeout: Duration,
+    ) -> Result<Output, Error> {
+        if input.is_some() {
+            command.stdin(Stdio::piped());
+        }
+        if capture {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let mut child = command.spawn()?;
+
+        // Drain the pipes while waiting, so a chatty command can't block
+        // on a full pipe and be mistaken for a hung one.
+        let stdout = child.stdout.take().map(read_to_end);
+        let stderr = child.stderr.take().map(read_to_end);
+
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input)?;
+        }
+
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                let join = |reader: Option<thread::JoinHandle<Vec<u8>>>| {
+                    reader
+                        .and_then(|reader| reader.join().ok())
+                        .unwrap_or_default()
+                };
+                return Ok(Output {
+                    status,
+                    stdout: join(stdout),
+                    stderr: join(stderr),
+                });
+            }
+
+            if start.elapsed() >= timeout {
+                let program =
+                    command.get_program().to_string_lossy().into_owned();
+                tracing::warn!(
+                    command = program,
+                    ?timeout,
+                    "Command timed out, killing it"
+                );
+                let _ = child.kill();
+                let _ = child.wait();
+
+                return Err(Error::CommandTimeout {
+                    command: program,
+                    timeout,
+                });
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// A command run through a [`RecordingCommandRunner`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// What was written to the command's stdin, if anything.
+    pub input: Option<Vec<u8>>,
+}
+
+/// A [`CommandRunner`] that records the commands it is given instead of
+/// running them.
+///
+/// Every command succeeds unless [`RecordingCommandRunner::fail`] set an
+/// exit code for its program.
+#[derive(Debug, Default)]
+pub struct RecordingCommandRunner {
+    commands: Mutex<Vec<RecordedCommand>>,
+    failures: Mutex<HashMap<String, (i32, String)>>,
+}
+
+impl RecordingCommandRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make every later run of `program` exit with `code`, printing
+    /// `stderr`.
+    pub fn fail(&self, program: &str, code: i32, stderr: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .insert(program.to_string(), (code, stderr.to_string()));
+    }
+
+    /// The commands run so far, in order.
+    pub fn commands(&self) -> Vec<RecordedCommand> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for RecordingCommandRunner {
+    fn run(
+        &self,
+        command: &mut Command,
+        input: Option<&[u8]>,
+        capture: bool,
+        _timeout: Duration,
+    ) -> Result<Output, Error> {
+        let program = command.get_program().to_string_lossy().into_owned();
+        let args = command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        self.commands.lock().unwrap().push(RecordedCommand {
+            program: program.clone(),
+            args,
+            input: input.map(<[u8]>::to_vec),
+        });
+
+        match self.failures.lock().unwrap().get(&program) {
+            Some((code, stderr)) => Ok(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: Vec::new(),
+                stderr: if capture {
+                    stderr.clone().into_bytes()
+                } else {
+                    Vec::new()
+                },
+            }),
+            None => Ok(success()),
+        }
+    }
+}
+
+fn success() -> Output {
+    Output {
+        status: ExitStatus::from_raw(0),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+    }
+}
+
+fn read_to_end(
+    mut pipe: impl Read + Send + 'static,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::process::Command;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use super::{RecordedCommand, RecordingCommandRunner, Runner};
+    use crate::error::Error;
+
+    #[test]
+    fn test_status() {
+        let runner = Runner::new();
+
+        assert!(runner.status(&mut Command::new("true")).unwrap().success());
+        assert!(!runner.status(&mut Command::new("false")).unwrap().success());
+    }
+
+    #[test]
+    fn test_status_with_input() {
+        let runner = Runner::new();
+        let mut command = Command::new("grep");
+        command.arg("-q").arg("expected");
+
+        let status = runner
+            .status_with_input(&mut command, b"some expected input\n")
+            .unwrap();
+
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_output() {
+        let runner = Runner::new();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo out; echo err >&2; exit 3");
+
+        let output = runner.output(&mut command).unwrap();
+
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+    }
+
+    #[test]
+    fn test_output_with_input() {
+        let runner = Runner::new();
+
+        let output = runner
+            .output_with_input(&mut Command::new("cat"), b"echoed\n")
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"echoed\n");
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let runner = Runner::new().dry_run(true);
+        let mut command = Command::new("false");
+
+        assert!(runner.is_dry_run());
+        assert!(runner.status(&mut command).unwrap().success());
+    }
+
+    #[test]
+    fn test_root_dir() {
+        let runner = Runner::new();
+        assert!(runner.is_system_root());
+        assert_eq!(runner.path("/etc/passwd"), Path::new("/etc/passwd"));
+
+        let runner = runner.root_dir("/mnt/image");
+        assert!(!runner.is_system_root());
+        assert_eq!(
+            runner.path("/etc/passwd"),
+            Path::new("/mnt/image/etc/passwd")
+        );
+        assert_eq!(
+            runner.path("home/azureuser"),
+            Path::new("/mnt/image/home/azureuser")
+        );
+    }
+
+    #[test]
+    fn test_step_timeout() {
+        let runner = Runner::new().step_timeout(Duration::from_millis(100));
+        let mut command = Command::new("sleep");
+        command.arg("10");
+
+        let start = Instant::now();
+        match runner.status(&mut command) {
+            Err(Error::CommandTimeout { command, timeout }) => {
+                assert_eq!(command, "sleep");
+                assert_eq!(timeout, Duration::from_millis(100));
+            }
+            _ => panic!("The command should have timed out"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_recording_command_runner() {
+        let recorder = Arc::new(RecordingCommandRunner::new());
+        recorder.fail("false", 1, "failed on purpose");
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        let status = runner
+            .status_with_input(Command::new("cat").arg("-"), b"input")
+            .unwrap();
+        assert!(status.success());
+        let output = runner.output(&mut Command::new("false")).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stderr, b"failed on purpose");
+
+        assert_eq!(
+            recorder.commands(),
+            [
+                RecordedCommand {
+                    program: "cat".to_string(),
+                    args: vec!["-".to_string()],
+                    input: Some(b"input".to_vec()),
+                },
+                RecordedCommand {
+                    program: "false".to_string(),
+                    args: Vec::new(),
+                    input: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dry_run_skips_command_runner() {
+        let recorder = Arc::new(RecordingCommandRunner::new());
+        let runner =
+            Runner::new().dry_run(true).command_runner(recorder.clone());
+
+        runner.status(&mut Command::new("useradd")).unwrap();
+
+        assert!(recorder.commands().is_empty());
+    }
+}
@@ -0,0 +1,473 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::distro::Distributions;
+use crate::error::Error;
+#[cfg(feature = "imds")]
+use crate::goalstate::WireserverConfig;
+use crate::hostname;
+#[cfg(feature = "imds")]
+use crate::http::{self, HttpConfig};
+#[cfg(feature = "imds")]
+use crate::imds::{self, ImdsConfig};
+use crate::machine_id::MachineIdOptions;
+use crate::media::MediaConfig;
+use crate::provision::{MetadataSource, SkipOptions};
+use crate::ssh::SshdOptions;
+use crate::user::{self, PublicKeys, SshKeyOptions, SudoersOptions, User};
+
+/// Where the configuration file is read from unless another is given.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/azure-init.toml";
+/// How many seconds a whole provisioning run may take by default.
+pub const DEFAULT_PROVISIONING_TIMEOUT_SECS: u64 = 300;
+
+/// Provisioning settings read from a TOML file.
+///
+/// Every field is optional in the file; missing fields keep the defaults,
+/// which match azure-init's behavior without a configuration file. The
+/// `http`, `imds` and `wireserver` tables are only accepted when
+/// libazureinit is built with the `imds` feature.
+///
+/// ```toml
+/// distribution = "debian"
+/// metadata_source = "auto"
+/// root_dir = "/"
+/// hostname_provisioners = ["etc_hostname"]
+/// hostname_case = "lowercase"
+/// update_etc_hosts = true
+/// user_provisioners = ["useradd"]
+/// groups = ["adm", "sudo"]
+/// lock_root = true
+/// home_base = "/home"
+/// admin_ssh_keys = ["ssh-ed25519 AAAA... ops@example"]
+/// custom_data_ssh_keys = true
+/// log_level = "debug"
+/// provisioning_timeout_secs = 300
+///
+/// [http]
+/// proxy = "http://proxy.example.com:3128"
+/// no_proxy = [".internal.example.com"]
+/// ca_certs = ["/etc/pki/proxy-ca.pem"]
+/// timeout_secs = 30
+/// user_agent = "acme-provisioner/1.2 (azure-init 0.1)"
+///
+/// [imds]
+/// endpoint = "http://169.254.169.254"
+/// api_version = "2021-02-01"
+///
+/// [imds.retry]
+/// max_attempts = 5
+///
+/// [machine_id]
+/// reset = true
+/// provisioners = ["systemd_machine_id_setup", "truncate"]
+///
+/// [media]
+/// config_drive_paths = ["/media/configdrive"]
+/// max_ovf_env_size = 4194304
+/// scan_timeout_secs = 10
+///
+/// [media.retry]
+/// max_attempts = 3
+///
+/// [skip]
+/// password = true
+///
+/// [sudoers]
+/// enabled = true
+/// nopasswd = true
+///
+/// [ssh]
+/// strict = false
+/// overwrite = false
+/// authorized_keys_dir = "/etc/ssh/authorized_keys.d"
+///
+/// [sshd]
+/// manage_password_authentication = true
+/// regenerate_host_keys = true
+///
+/// [wireserver]
+/// endpoint = "http://168.63.129.16"
+///
+/// [wireserver.retry]
+/// max_attempts = 5
+///
+/// [[users]]
+/// name = "svc-backup"
+/// groups = ["backup"]
+/// ssh_keys = ["ssh-ed25519 AAAA... backup@example"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Distribution to provision for; detected from `/etc/os-release`
+    /// when unset.
+    pub distribution: Option<Distributions>,
+    /// Where the admin user, hostname and SSH keys are read from.
+    pub metadata_source: MetadataSource,
+    /// Provisioning phases to leave out.
+    pub skip: SkipOptions,
+    /// Root directory of the system to provision. Set it to provision a
+    /// mounted image rather than the running system; the hostname can then
+    /// only be set with the `etc_hostname` provisioner.
+    pub root_dir: PathBuf,
+    /// How the HTTP client reaching the platform endpoints is set up.
+    #[cfg(feature = "imds")]
+    pub http: HttpConfig,
+    #[cfg(feature = "imds")]
+    pub imds: ImdsConfig,
+    #[cfg(feature = "imds")]
+    pub wireserver: WireserverConfig,
+    /// How the OVF environment is read from provisioning media.
+    pub media: MediaConfig,
+    /// Backends tried, in order, to set the hostname.
+    pub hostname_provisioners: Vec<hostname::Provisioner>,
+    /// Whether the hostname is lowercased before it is set.
+    pub hostname_case: hostname::Case,
+    /// Whether to map 127.0.1.1 to the hostname in `/etc/hosts` once it
+    /// is set.
+    pub update_etc_hosts: bool,
+    /// Backends tried, in order, to create the user.
+    pub user_provisioners: Vec<user::Provisioner>,
+    /// Supplementary groups for the provisioned user; the distribution's
+    /// default groups are used when unset.
+    pub groups: Option<Vec<String>>,
+    /// Directory in which users' home directories are created.
+    pub home_base: PathBuf,
+    /// SSH public keys authorized for the admin user in addition to those
+    /// from the instance metadata, as `authorized_keys` lines. Keys the
+    /// metadata already has are dropped.
+    pub admin_ssh_keys: Vec<String>,
+    /// Whether the admin user's SSH keys are also read from the
+    /// `ssh_authorized_keys` array of JSON custom data from IMDS, after
+    /// those in `publicKeys`. Keys IMDS already has are dropped.
+    pub custom_data_ssh_keys: bool,
+    /// Whether to lock the root account's password once the admin user
+    /// has been created.
+    pub lock_root: bool,
+    /// Whether the admin user is granted sudo with a sudoers file.
+    pub sudoers: SudoersOptions,
+    /// How SSH public keys are written.
+    pub ssh: SshKeyOptions,
+    /// How the SSH daemon is configured.
+    pub sshd: SshdOptions,
+    /// Whether the machine ID inherited from the image is replaced.
+    pub machine_id: MachineIdOptions,
+    /// Maximum level of log events to emit, e.g. `info` or `debug`.
+    pub log_level: Option<String>,
+    /// How many seconds provisioning may take in total before it is
+    /// aborted and reported as failed.
+    pub provisioning_timeout_secs: u64,
+    /// Accounts provisioned alongside the admin user from the instance
+    /// metadata.
+    pub users: Vec<UserConfig>,
+}
+
+/// An account to provision in addition to the admin user.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    /// Supplementary groups; the distribution's default groups are used
+    /// when unset.
+    #[serde(default)]
+    pub groups: Option<Vec<String>>,
+    #[serde(default)]
+    pub shell: Option<String>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// A crypt(3) password hash; the user has no password when empty.
+    #[serde(default)]
+    pub password: String,
+    /// SSH public keys in `authorized_keys` format, optionally preceded by
+    /// options such as `no-port-forwarding`.
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+}
+
+impl UserConfig {
+    pub fn user(&self) -> User {
+        let mut user = User::new(self.name.as_str());
+        if let Some(groups) = &self.groups {
+            user = user.with_groups(groups.clone());
+        }
+        if let Some(shell) = &self.shell {
+            user = user.with_shell(shell.as_str());
+        }
+        if let Some(uid) = self.uid {
+            user = user.with_uid(uid);
+        }
+        user
+    }
+
+    pub fn public_keys(&self) -> Vec<PublicKeys> {
+        self.ssh_keys
+            .iter()
+            .map(|key| PublicKeys::from_authorized_keys_line(key))
+            .collect()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            distribution: None,
+            metadata_source: MetadataSource::default(),
+            skip: SkipOptions::default(),
+            root_dir: PathBuf::from("/"),
+            #[cfg(feature = "imds")]
+            http: HttpConfig::default(),
+            #[cfg(feature = "imds")]
+            imds: ImdsConfig::default(),
+            #[cfg(feature = "imds")]
+            wireserver: WireserverConfig::default(),
+            media: MediaConfig::default(),
+            hostname_provisioners: hostname::DEFAULT_PROVISIONERS.to_vec(),
+            hostname_case: hostname::Case::default(),
+            update_etc_hosts: false,
+            user_provisioners: user::DEFAULT_PROVISIONERS.to_vec(),
+            groups: None,
+            home_base: PathBuf::from(user::DEFAULT_HOME_BASE),
+            admin_ssh_keys: Vec::new(),
+            custom_data_ssh_keys: false,
+            lock_root: false,
+            sudoers: SudoersOptions::default(),
+            ssh: SshKeyOptions::default(),
+            sshd: SshdOptions::default(),
+            machine_id: MachineIdOptions::default(),
+            log_level: None,
+            provisioning_timeout_secs: DEFAULT_PROVISIONING_TIMEOUT_SECS,
+            users: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let contents = fs::read_to_string(path)?;
+
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// The admin user's SSH public keys from `admin_ssh_keys`.
+    pub fn admin_public_keys(&self) -> Vec<PublicKeys> {
+        self.admin_ssh_keys
+            .iter()
+            .map(|key| PublicKeys::from_authorized_keys_line(key))
+            .collect()
+    }
+
+    /// The deadline for the whole provisioning run.
+    pub fn provisioning_timeout(&self) -> Duration {
+        Duration::from_secs(self.provisioning_timeout_secs)
+    }
+
+    /// The settings of the client reaching the platform endpoints: `http`,
+    /// with the hosts of the IMDS and wireserver endpoints added to
+    /// `no_proxy` unless `http.proxy_platform_endpoints` is set. Those may
+    /// be configured away from the addresses in
+    /// [`http::PLATFORM_NO_PROXY`](crate::http::PLATFORM_NO_PROXY), e.g. to
+    /// reach IMDS over IPv6.
+    #[cfg(feature = "imds")]
+    pub fn http_config(&self) -> HttpConfig {
+        let mut config = self.http.clone();
+        if config.proxy_platform_endpoints {
+            return config;
+        }
+
+        let imds_urls = std::iter::once(&self.imds.endpoint)
+            .chain(&self.imds.fallback_endpoints)
+            .filter_map(|endpoint| imds::parse_endpoint(endpoint).ok())
+            .map(|endpoint| endpoint.url);
+        let urls = imds_urls.chain([self.wireserver.endpoint.clone()]);
+        for host in urls.filter_map(|url| http::url_host(&url)) {
+            if !config.no_proxy.contains(&host) {
+                config.no_proxy.push(host);
+            }
+        }
+
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::Config;
+    use crate::distro::Distributions;
+    use crate::error::Error;
+    use crate::hostname::{self, Provisioner};
+    #[cfg(feature = "imds")]
+    use crate::imds;
+    use crate::provision::MetadataSource;
+    use crate::user;
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let config: Config = toml::from_str("").unwrap();
+
+        assert_eq!(config, Config::default());
+        #[cfg(feature = "imds")]
+        assert_eq!(config.imds.endpoint, imds::DEFAULT_IMDS_ENDPOINT);
+    }
+
+    #[cfg(feature = "imds")]
+    #[test]
+    fn test_http_config_skips_proxy_for_endpoints() {
+        let mut config = Config::default();
+        config.http.no_proxy = vec!["168.63.129.16".to_string()];
+        config.imds.endpoint = "http://169.254.169.254/".to_string();
+        config.imds.fallback_endpoints =
+            vec!["[fd00::254]:80".to_string(), "fe80::254%eth0".to_string()];
+
+        assert_eq!(
+            config.http_config().no_proxy,
+            ["168.63.129.16", "169.254.169.254", "fd00::254", "fe80::254"]
+        );
+
+        config.http.proxy_platform_endpoints = true;
+        assert_eq!(config.http_config().no_proxy, ["168.63.129.16"]);
+    }
+
+    #[test]
+    fn test_load_config() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(
+            br#"
+            distribution = "rhel"
+            metadata_source = "imds_with_ovf_fallback"
+            root_dir = "/mnt/image"
+            hostname_provisioners = ["etc-hostname"]
+            hostname_case = "lowercase"
+            update_etc_hosts = true
+            user_provisioners = ["direct_etc_files"]
+            groups = ["wheel"]
+            lock_root = true
+            log_level = "debug"
+            provisioning_timeout_secs = 60
+            home_base = "/var/home"
+
+            [skip]
+            hostname = true
+
+            [sudoers]
+            enabled = true
+
+            [sshd]
+            manage_password_authentication = true
+
+            [media.retry]
+            max_attempts = 4
+
+            [[users]]
+            name = "svc-backup"
+            groups = ["backup"]
+            uid = 2000
+            ssh_keys = ["ssh-ed25519 AAAA backup"]
+
+            [[users]]
+            name = "svc-monitor"
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::load(file.path()).unwrap();
+
+        assert_eq!(config.distribution, Some(Distributions::Rhel));
+        assert_eq!(config.metadata_source, MetadataSource::ImdsWithOvfFallback);
+        assert_eq!(config.root_dir, std::path::Path::new("/mnt/image"));
+        assert_eq!(config.hostname_provisioners, [Provisioner::EtcHostname]);
+        assert_eq!(config.hostname_case, hostname::Case::Lowercase);
+        assert!(config.update_etc_hosts);
+        assert_eq!(
+            config.user_provisioners,
+            [user::Provisioner::DirectEtcFiles]
+        );
+        assert_eq!(config.groups, Some(vec!["wheel".to_string()]));
+        assert_eq!(config.home_base, std::path::Path::new("/var/home"));
+        assert!(config.lock_root);
+        assert!(config.skip.hostname);
+        assert!(!config.skip.user);
+        assert!(config.sudoers.enabled);
+        assert!(!config.sudoers.nopasswd);
+        assert!(config.sshd.manage_password_authentication);
+        assert_eq!(config.media.retry.max_attempts, 4);
+        assert_eq!(config.log_level.as_deref(), Some("debug"));
+        assert_eq!(
+            config.provisioning_timeout(),
+            std::time::Duration::from_secs(60)
+        );
+
+        assert_eq!(config.users.len(), 2);
+        let user = config.users[0].user();
+        assert_eq!(user.name, "svc-backup");
+        assert_eq!(user.groups, Some(vec!["backup".to_string()]));
+        assert_eq!(user.uid, Some(2000));
+        assert_eq!(
+            config.users[0].public_keys()[0].key_data,
+            "ssh-ed25519 AAAA backup"
+        );
+        assert_eq!(config.users[1].user().groups, None);
+        assert!(config.users[1].public_keys().is_empty());
+    }
+
+    #[cfg(feature = "imds")]
+    #[test]
+    fn test_load_platform_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [http]
+            proxy = "http://proxy.example.com:3128"
+
+            [imds]
+            api_version = "2023-07-01"
+
+            [wireserver.retry]
+            max_attempts = 2
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.http.proxy.as_deref(),
+            Some("http://proxy.example.com:3128")
+        );
+        assert_eq!(config.http.timeout_secs, 30);
+        assert_eq!(config.imds.api_version, "2023-07-01");
+        assert_eq!(config.imds.endpoint, imds::DEFAULT_IMDS_ENDPOINT);
+        assert_eq!(config.imds.retry.max_attempts, 5);
+        assert_eq!(config.wireserver.retry.max_attempts, 2);
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        match toml::from_str::<Config>("hostnmae_provisioners = []") {
+            Err(err) => assert!(err.to_string().contains("unknown field")),
+            Ok(_) => panic!("Misspelled settings should be rejected"),
+        }
+
+        match toml::from_str::<Config>(r#"user_provisioners = ["usradd"]"#) {
+            Err(err) => {
+                assert!(err
+                    .to_string()
+                    .contains("Unknown provisioner 'usradd'"))
+            }
+            Ok(_) => panic!("Misspelled provisioners should be rejected"),
+        }
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"distribution = \"arch\"").unwrap();
+        match Config::load(file.path()) {
+            Err(Error::Toml(_)) => {}
+            _ => panic!("Unknown distributions should be rejected"),
+        }
+    }
+}
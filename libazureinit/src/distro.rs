@@ -1,96 +1,706 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-use std::process::Command;
+use std::fs;
+use std::sync::Arc;
 
+use serde::{Deserialize, Serialize};
+
+use crate::command::Runner;
 use crate::error::Error;
+use crate::hostname;
+use crate::machine_id;
+use crate::user::{self, User};
 
+/// The distribution-specific steps of provisioning.
+///
+/// [`Distributions`] implements this for the distributions azure-init
+/// supports. Integrators building on libazureinit for another
+/// distribution can implement it themselves and provision through a
+/// `Box<dyn Distribution>` or `Arc<dyn Distribution>`, both of which
+/// implement the trait too.
+///
+/// Implementations should run external commands through the given
+/// [`Runner`], so that step timeouts and dry runs apply to them.
 pub trait Distribution {
-    fn create_user(&self, username: &str, password: &str)
-        -> Result<i32, Error>;
-    fn set_hostname(&self, hostname: &str) -> Result<i32, Error>;
+    /// Create `user` and set its password.
+    ///
+    /// `password` is either empty, in which case the user should have no
+    /// password, or a crypt(3) hash; see [`is_password_hash`]. With `None`,
+    /// the password is left as user creation sets it. Creating a
+    /// user that already exists with the same home directory and UID
+    /// should succeed, at most adding it to groups in `user.groups` that
+    /// it isn't a member of; see [`user::reconcile_user`].
+    ///
+    /// Implementations that create users in the usual way can call
+    /// [`user::create_user`] with `provisioners`, trying each in order.
+    ///
+    /// Returns a report naming the backends used in its `user_backend` and
+    /// `password_backend` fields; `hostname_backend` and
+    /// `machine_id_backend` are left unset, as is `password_backend` if no
+    /// password was set.
+    fn create_user(
+        &self,
+        user: &User,
+        password: Option<&str>,
+        provisioners: &[user::Provisioner],
+        runner: &Runner,
+    ) -> Result<ProvisionReport, Error>;
+
+    /// Set the hostname with the first of `provisioners` that succeeds,
+    /// returning that provisioner.
+    ///
+    /// Implementations that don't need distribution-specific behavior can
+    /// call [`hostname::set_hostname`].
+    fn set_hostname(
+        &self,
+        hostname: &str,
+        provisioners: &[hostname::Provisioner],
+        runner: &Runner,
+    ) -> Result<hostname::Provisioner, Error>;
+}
+
+impl<D: Distribution + ?Sized> Distribution for Box<D> {
+    fn create_user(
+        &self,
+        user: &User,
+        password: Option<&str>,
+        provisioners: &[user::Provisioner],
+        runner: &Runner,
+    ) -> Result<ProvisionReport, Error> {
+        (**self).create_user(user, password, provisioners, runner)
+    }
+
+    fn set_hostname(
+        &self,
+        hostname: &str,
+        provisioners: &[hostname::Provisioner],
+        runner: &Runner,
+    ) -> Result<hostname::Provisioner, Error> {
+        (**self).set_hostname(hostname, provisioners, runner)
+    }
 }
 
+impl<D: Distribution + ?Sized> Distribution for Arc<D> {
+    fn create_user(
+        &self,
+        user: &User,
+        password: Option<&str>,
+        provisioners: &[user::Provisioner],
+        runner: &Runner,
+    ) -> Result<ProvisionReport, Error> {
+        (**self).create_user(user, password, provisioners, runner)
+    }
+
+    fn set_hostname(
+        &self,
+        hostname: &str,
+        provisioners: &[hostname::Provisioner],
+        runner: &Runner,
+    ) -> Result<hostname::Provisioner, Error> {
+        (**self).set_hostname(hostname, provisioners, runner)
+    }
+}
+
+/// The backends that succeeded in provisioning each resource.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProvisionReport {
+    /// The backend that created the user, or `None` if it already existed.
+    pub user_backend: Option<user::Provisioner>,
+    /// The command or file that set the user's password, or `None` if none ran.
+    pub password_backend: Option<String>,
+    pub hostname_backend: Option<hostname::Provisioner>,
+    /// The backend that reset the machine ID, or `None` if it wasn't reset.
+    pub machine_id_backend: Option<machine_id::Provisioner>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Distributions {
     Debian,
     Ubuntu,
+    /// Red Hat Enterprise Linux and derivatives such as CentOS and Fedora.
+    Rhel,
+}
+
+pub const PATH_OS_RELEASE: &str = "/etc/os-release";
+
+impl Distributions {
+    /// Detect the running distribution from `/etc/os-release`.
+    pub fn detect() -> Result<Distributions, Error> {
+        let os_release = fs::read_to_string(PATH_OS_RELEASE)?;
+
+        Ok(Distributions::from_os_release(&os_release))
+    }
+
+    /// Pick the distribution described by the contents of an os-release
+    /// file, going by `ID` and then each entry of `ID_LIKE`.
+    ///
+    /// Unrecognized distributions are treated as Ubuntu, which is what
+    /// azure-init assumed before detection was added.
+    pub fn from_os_release(os_release: &str) -> Distributions {
+        let mut id = None;
+        let mut id_like = None;
+
+        for line in os_release.lines() {
+            if let Some((key, value)) = line.trim().split_once('=') {
+                let value =
+                    value.trim().trim_matches(|c| c == '"' || c == '\'');
+                match key {
+                    "ID" => id = Some(value.to_lowercase()),
+                    "ID_LIKE" => id_like = Some(value.to_lowercase()),
+                    _ => {}
+                }
+            }
+        }
+
+        let candidates = id.iter().map(String::as_str).chain(
+            id_like
+                .iter()
+                .flat_map(|id_like| id_like.split_whitespace()),
+        );
+        for candidate in candidates {
+            if let Some(distro) = Distributions::from_id(candidate) {
+                return distro;
+            }
+        }
+
+        tracing::warn!(?id, ?id_like, "Unknown distribution, assuming Ubuntu");
+        Distributions::Ubuntu
+    }
+
+    fn from_id(id: &str) -> Option<Distributions> {
+        match id {
+            "debian" => Some(Distributions::Debian),
+            "ubuntu" => Some(Distributions::Ubuntu),
+            "rhel" | "centos" | "fedora" => Some(Distributions::Rhel),
+            _ => None,
+        }
+    }
+
+    /// Supplementary groups the provisioned user is added to.
+    ///
+    /// - Ubuntu: adm, audio, cdrom, dialout, dip, floppy, lxd, netdev,
+    ///   plugdev, sudo, video
+    /// - Debian: the Ubuntu groups except lxd
+    /// - RHEL: adm, systemd-journal, wheel
+    ///
+    /// Groups that don't exist on the running system are skipped when the
+    /// user is created.
+    pub fn default_groups(&self) -> &'static [&'static str] {
+        match self {
+            Distributions::Debian => &[
+                "adm", "audio", "cdrom", "dialout", "dip", "floppy", "netdev",
+                "plugdev", "sudo", "video",
+            ],
+            Distributions::Ubuntu => &[
+                "adm", "audio", "cdrom", "dialout", "dip", "floppy", "lxd",
+                "netdev", "plugdev", "sudo", "video",
+            ],
+            Distributions::Rhel => &["adm", "systemd-journal", "wheel"],
+        }
+    }
+
+    // The default groups which exist on the system being provisioned.
+    fn existing_default_groups(&self, runner: &Runner) -> Vec<&'static str> {
+        self.default_groups()
+            .iter()
+            .copied()
+            .filter(|group| match user::group_exists(group, runner) {
+                Ok(true) => true,
+                _ => {
+                    tracing::warn!(group, "Skipping missing group");
+                    false
+                }
+            })
+            .collect()
+    }
 }
 
 impl Distribution for Distributions {
     fn create_user(
         &self,
-        username: &str,
-        password: &str,
-    ) -> Result<i32, Error> {
-        match self {
-            Distributions::Debian | Distributions::Ubuntu => {
-                let mut home_path = "/home/".to_string();
-                home_path.push_str(username);
-
-                let status = Command::new("useradd")
-                    .arg(username)
-                    .arg("--comment")
-                    .arg(
-                      "Provisioning agent created this user based on username provided in IMDS",
-                    )
-                    .arg("--groups")
-                    .arg("adm,audio,cdrom,dialout,dip,floppy,lxd,netdev,plugdev,sudo,video")
-                    .arg("-d")
-                    .arg(home_path.clone())
-                    .arg("-m")
-                    .status()?;
-                if !status.success() {
-                    return Err(Error::SubprocessFailed {
-                        command: "useradd".to_string(),
-                        status,
+        user: &User,
+        password: Option<&str>,
+        provisioners: &[user::Provisioner],
+        runner: &Runner,
+    ) -> Result<ProvisionReport, Error> {
+        let username = user.name.as_str();
+
+        if let Some(shell) = &user.shell {
+            if !runner.path(shell).exists() {
+                return Err(Error::InvalidShell {
+                    shell: shell.clone(),
+                });
+            }
+        }
+
+        // Only pre-hashed passwords are accepted; creating a user with a
+        // plaintext password is not allowed.
+        if password.is_some_and(|p| !p.is_empty() && !is_password_hash(p)) {
+            return Err(Error::NonEmptyPassword);
+        }
+
+        if let Some(uid) = user.uid {
+            if let Some(owner) = user::get_user_by_uid(uid, runner)? {
+                if owner.name != username {
+                    return Err(Error::UidInUse {
+                        uid,
+                        user: owner.name,
                     });
                 }
+            }
+        }
 
-                if password.is_empty() {
-                    let status = Command::new("passwd")
-                        .arg("-d")
-                        .arg(username)
-                        .status()?;
-                    if !status.success() {
-                        return Err(Error::SubprocessFailed {
-                            command: "passwd".to_string(),
-                            status,
+        match self {
+            Distributions::Debian
+            | Distributions::Ubuntu
+            | Distributions::Rhel => {
+                let home_path = user.home_dir()?;
+
+                // Re-running provisioning must not fail because the user
+                // was already created by an earlier run.
+                if let Some(existing) =
+                    user::get_user_by_name(username, runner)?
+                {
+                    if existing.dir != home_path
+                        || user
+                            .uid
+                            .is_some_and(|uid| existing.uid.as_raw() != uid)
+                    {
+                        return Err(Error::UserExists {
+                            user: username.to_string(),
                         });
                     }
-                } else {
-                    // creating user with a non-empty password is not allowed.
-                    return Err(Error::NonEmptyPassword);
+
+                    tracing::info!(
+                        username,
+                        "User already exists, skipping creation"
+                    );
+                    // Groups given for the user are added, but defaults
+                    // aren't, so groups an operator took the user out of
+                    // stay that way.
+                    if let Some(groups) = &user.groups {
+                        user::reconcile_user(
+                            username,
+                            groups,
+                            provisioners,
+                            runner,
+                        )?;
+                    }
+                    return Ok(ProvisionReport::default());
                 }
 
-                Ok(0)
+                let groups = match &user.groups {
+                    Some(groups) => groups.clone(),
+                    None => self
+                        .existing_default_groups(runner)
+                        .into_iter()
+                        .map(String::from)
+                        .collect(),
+                };
+                let user_backend = user::create_user(
+                    user,
+                    &home_path,
+                    &groups,
+                    provisioners,
+                    runner,
+                )?;
+
+                // The password is set by the same backend that created the
+                // user, so both edit the account databases the same way.
+                let password_backend = match password {
+                    Some(password) => Some(
+                        user_backend
+                            .set_password(username, password, runner)?
+                            .to_string(),
+                    ),
+                    None => None,
+                };
+
+                Ok(ProvisionReport {
+                    user_backend: Some(user_backend),
+                    password_backend,
+                    hostname_backend: None,
+                    machine_id_backend: None,
+                })
             }
         }
     }
-    fn set_hostname(&self, hostname: &str) -> Result<i32, Error> {
+    fn set_hostname(
+        &self,
+        hostname: &str,
+        provisioners: &[hostname::Provisioner],
+        runner: &Runner,
+    ) -> Result<hostname::Provisioner, Error> {
         match self {
-            Distributions::Debian | Distributions::Ubuntu => {
-                let status = Command::new("hostnamectl")
-                    .arg("set-hostname")
-                    .arg(hostname)
-                    .status()?;
-                if status.success() {
-                    Ok(status.code().unwrap_or(1))
-                } else {
-                    Err(Error::SubprocessFailed {
-                        command: "chpasswd".to_string(),
-                        status,
-                    })
-                }
+            Distributions::Debian
+            | Distributions::Ubuntu
+            | Distributions::Rhel => {
+                hostname::set_hostname(hostname, provisioners, runner)
             }
         }
     }
 }
+
+/// Check whether `password` is a crypt(3) hash such as `$6$salt$hash`.
+pub fn is_password_hash(password: &str) -> bool {
+    let fields: Vec<&str> = password.split('$').collect();
+
+    // A leading '$' makes the first field empty; then come the algorithm ID,
+    // optional parameters, the salt and the hash itself.
+    fields.len() >= 4
+        && fields[0].is_empty()
+        && !fields[1].is_empty()
+        && fields[1].chars().all(|c| c.is_ascii_alphanumeric())
+        && fields[1..].iter().all(|field| {
+            field.chars().all(|c| {
+                c.is_ascii_alphanumeric()
+                    || matches!(c, '.' | '/' | '=' | ',' | '-')
+            })
+        })
+        && !fields[fields.len() - 1].is_empty()
+}
+
 impl From<&str> for Distributions {
     fn from(s: &str) -> Self {
-        match s {
-            "debian" => Distributions::Debian,
-            "ubuntu" => Distributions::Ubuntu,
-            _ => panic!("Unknown distribution"),
+        Distributions::from_id(s).expect("Unknown distribution")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_password_hash, Distribution, Distributions, ProvisionReport,
+    };
+    use crate::command::{RecordingCommandRunner, Runner};
+    use crate::error::Error;
+    use crate::hostname;
+    use crate::user::{self, User};
+
+    // A distribution defined outside the crate's Distributions enum.
+    struct CustomDistribution;
+
+    impl Distribution for CustomDistribution {
+        fn create_user(
+            &self,
+            user: &User,
+            _password: Option<&str>,
+            provisioners: &[user::Provisioner],
+            _runner: &Runner,
+        ) -> Result<ProvisionReport, Error> {
+            Ok(ProvisionReport {
+                user_backend: provisioners.first().copied(),
+                password_backend: Some(format!("custom {}", user.name)),
+                ..Default::default()
+            })
         }
+
+        fn set_hostname(
+            &self,
+            _hostname: &str,
+            provisioners: &[hostname::Provisioner],
+            _runner: &Runner,
+        ) -> Result<hostname::Provisioner, Error> {
+            provisioners.first().copied().ok_or(Error::NoProvisioners {
+                resource: "hostname".to_string(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_custom_distribution() {
+        let distro: Box<dyn Distribution> = Box::new(CustomDistribution);
+        let runner = Runner::new();
+
+        let report = distro
+            .create_user(
+                &User::new("azureuser"),
+                Some(""),
+                user::DEFAULT_PROVISIONERS,
+                &runner,
+            )
+            .unwrap();
+        assert_eq!(report.user_backend, Some(user::Provisioner::Useradd));
+        assert_eq!(
+            report.password_backend.as_deref(),
+            Some("custom azureuser")
+        );
+        assert_eq!(
+            distro
+                .set_hostname(
+                    "test-hostname",
+                    hostname::DEFAULT_PROVISIONERS,
+                    &runner
+                )
+                .unwrap(),
+            hostname::Provisioner::Hostnamectl
+        );
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Distributions::from("debian"), Distributions::Debian);
+        assert_eq!(Distributions::from("ubuntu"), Distributions::Ubuntu);
+        assert_eq!(Distributions::from("rhel"), Distributions::Rhel);
+        assert_eq!(Distributions::from("centos"), Distributions::Rhel);
+    }
+
+    #[test]
+    fn test_from_os_release_ubuntu() {
+        let os_release = r#"PRETTY_NAME="Ubuntu 22.04.4 LTS"
+NAME="Ubuntu"
+VERSION_ID="22.04"
+VERSION="22.04.4 LTS (Jammy Jellyfish)"
+ID=ubuntu
+ID_LIKE=debian
+"#;
+
+        assert_eq!(
+            Distributions::from_os_release(os_release),
+            Distributions::Ubuntu
+        );
+    }
+
+    #[test]
+    fn test_from_os_release_debian() {
+        let os_release = r#"PRETTY_NAME="Debian GNU/Linux 12 (bookworm)"
+NAME="Debian GNU/Linux"
+VERSION_ID="12"
+ID=debian
+"#;
+
+        assert_eq!(
+            Distributions::from_os_release(os_release),
+            Distributions::Debian
+        );
+    }
+
+    #[test]
+    fn test_from_os_release_id_like() {
+        let os_release = r#"NAME="Linux Mint"
+ID=linuxmint
+ID_LIKE="ubuntu debian"
+"#;
+
+        assert_eq!(
+            Distributions::from_os_release(os_release),
+            Distributions::Ubuntu
+        );
+    }
+
+    #[test]
+    fn test_from_os_release_rhel() {
+        let os_release = r#"NAME="Red Hat Enterprise Linux"
+VERSION_ID="9.3"
+ID="rhel"
+ID_LIKE="fedora"
+"#;
+
+        assert_eq!(
+            Distributions::from_os_release(os_release),
+            Distributions::Rhel
+        );
+    }
+
+    #[test]
+    fn test_from_os_release_rhel_like() {
+        let os_release = r#"NAME="AlmaLinux"
+ID="almalinux"
+ID_LIKE="rhel centos fedora"
+"#;
+
+        assert_eq!(
+            Distributions::from_os_release(os_release),
+            Distributions::Rhel
+        );
+    }
+
+    #[test]
+    fn test_from_os_release_unknown() {
+        let os_release = r#"NAME="Arch Linux"
+ID=arch
+"#;
+
+        assert_eq!(
+            Distributions::from_os_release(os_release),
+            Distributions::Ubuntu
+        );
+    }
+
+    #[test]
+    fn test_create_user_missing_shell() {
+        let user = User::new("i_sure_hope_this_user_doesnt_exist")
+            .with_shell("/this/shell/does/not/exist");
+
+        match Distributions::Ubuntu.create_user(
+            &user,
+            Some(""),
+            user::DEFAULT_PROVISIONERS,
+            &Runner::new(),
+        ) {
+            Err(Error::InvalidShell { shell }) => {
+                assert_eq!(shell, "/this/shell/does/not/exist")
+            }
+            _ => panic!("A missing shell should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_create_user_uid_in_use() {
+        let user = User::new("i_sure_hope_this_user_doesnt_exist").with_uid(0);
+
+        match Distributions::Ubuntu.create_user(
+            &user,
+            Some(""),
+            user::DEFAULT_PROVISIONERS,
+            &Runner::new(),
+        ) {
+            Err(Error::UidInUse { uid, user }) => {
+                assert_eq!(uid, 0);
+                assert_eq!(user, "root");
+            }
+            _ => panic!("UID 0 already belongs to root"),
+        }
+    }
+
+    #[test]
+    fn test_is_password_hash() {
+        assert!(is_password_hash(
+            "$6$rounds=5000$saltsalt$c2ltcGxlLmhhc2gudmFsdWUuZm9yLnRlc3Q"
+        ));
+        assert!(is_password_hash(
+            "$y$j9T$F5Jx5fExrKuPp53xLKQ..1$X3DX6M94c7o"
+        ));
+        assert!(is_password_hash("$1$saltsalt$qjXMvbEw8oaL.CzflDugX/"));
+        assert!(!is_password_hash("hunter2"));
+        assert!(!is_password_hash("$6$salt$"));
+        assert!(!is_password_hash("$6$salt$hash\nroot:$6$x$y"));
+        assert!(!is_password_hash("$6$salt:x$hash"));
+    }
+
+    #[test]
+    fn test_create_user_plaintext_password() {
+        let user = User::new("i_sure_hope_this_user_doesnt_exist");
+
+        match Distributions::Ubuntu.create_user(
+            &user,
+            Some("hunter2"),
+            user::DEFAULT_PROVISIONERS,
+            &Runner::new(),
+        ) {
+            Err(Error::NonEmptyPassword) => {}
+            _ => panic!("Plaintext passwords should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_create_user_conflicting_existing_user() {
+        // root exists, but its home directory isn't /home/root.
+        let user = User::new("root");
+
+        match Distributions::Ubuntu.create_user(
+            &user,
+            Some(""),
+            user::DEFAULT_PROVISIONERS,
+            &Runner::new(),
+        ) {
+            Err(Error::UserExists { user }) => assert_eq!(user, "root"),
+            _ => panic!("An existing user with other attributes conflicts"),
+        }
+    }
+
+    #[test]
+    fn test_rhel_default_groups() {
+        let groups = Distributions::Rhel.default_groups();
+
+        assert!(groups.contains(&"wheel"));
+        assert!(!groups.contains(&"lxd"));
+        assert!(!groups.contains(&"dip"));
+    }
+
+    #[test]
+    fn test_existing_default_groups_skips_missing() {
+        let groups =
+            Distributions::Ubuntu.existing_default_groups(&Runner::new());
+
+        for group in groups {
+            assert!(nix::unistd::Group::from_name(group).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_debian_default_groups() {
+        let groups = Distributions::Debian.default_groups();
+
+        assert!(groups.contains(&"sudo"));
+        assert!(!groups.contains(&"lxd"));
+    }
+
+    #[test]
+    fn test_debian_provisioning_commands() {
+        // lxd exists in the image, so only the Debian defaults leave it out.
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("etc")).unwrap();
+        std::fs::write(
+            root.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.path().join("etc/group"),
+            "root:x:0:\nadm:x:4:\nsudo:x:27:\nlxd:x:110:\n",
+        )
+        .unwrap();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir(root.path());
+
+        Distributions::Debian
+            .create_user(
+                &User::new("azureuser"),
+                None,
+                &[user::Provisioner::Useradd],
+                &runner,
+            )
+            .unwrap();
+
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].program, "useradd");
+        let groups = commands[0]
+            .args
+            .iter()
+            .skip_while(|arg| *arg != "--groups")
+            .nth(1)
+            .unwrap();
+        let groups: Vec<&str> = groups.split(',').collect();
+        assert!(groups.contains(&"sudo"));
+        assert!(!groups.contains(&"lxd"));
+
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        Distributions::Debian
+            .set_hostname(
+                "test-hostname",
+                &[hostname::Provisioner::Hostnamectl],
+                &runner,
+            )
+            .unwrap();
+
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].program, "hostnamectl");
+        assert_eq!(commands[0].args, ["set-hostname", "test-hostname"]);
+    }
+
+    #[test]
+    fn test_ubuntu_default_groups() {
+        let groups = Distributions::Ubuntu.default_groups();
+
+        assert!(groups.contains(&"sudo"));
+        assert!(groups.contains(&"lxd"));
     }
 }
@@ -1,25 +1,67 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+/// The most stderr output of a failed command kept in
+/// [`Error::SubprocessFailed`].
+pub const MAX_STDERR_LEN: usize = 1024;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Unable to deserialize or serialize JSON data")]
     Json(#[from] serde_json::Error),
+    #[error("Unable to deserialize TOML data")]
+    Toml(#[from] toml::de::Error),
     #[error("Unable to deserialize or serialize XML data")]
     Xml(#[from] serde_xml_rs::Error),
+    #[error("Unable to parse the OVF environment at element '{element}'")]
+    OvfParse {
+        element: String,
+        #[source]
+        source: serde_xml_rs::Error,
+    },
+    #[error("The OVF environment is missing the required '{field}' element")]
+    OvfMissingField { field: String },
+    #[cfg(feature = "imds")]
     #[error("HTTP client error ocurred")]
     Http(#[from] reqwest::Error),
     #[error("An I/O error occurred")]
     Io(#[from] std::io::Error),
+    #[cfg(feature = "imds")]
     #[error("HTTP request did not succeed (HTTP {status} from {endpoint})")]
     HttpStatus {
         endpoint: String,
         status: reqwest::StatusCode,
     },
-    #[error("executing {command} failed: {status}")]
+    #[error("Invalid User-Agent '{user_agent}'")]
+    InvalidUserAgent { user_agent: String },
+    #[error("Invalid IMDS endpoint '{endpoint}': {reason}")]
+    InvalidImdsEndpoint { endpoint: String, reason: String },
+    #[error("Invalid IMDS API version '{version}', expected YYYY-MM-DD")]
+    InvalidApiVersion { version: String },
+    #[error("IMDS did not respond within {wait:?}")]
+    ImdsWaitTimeout { wait: std::time::Duration },
+    #[error("The IMDS response is missing the '{field}' field")]
+    ImdsFieldMissing { field: String },
+    #[error("The goalstate is missing the '{field}' element")]
+    GoalstateFieldMissing { field: String },
+    #[error("The {payload} is larger than the limit of {max_size} bytes")]
+    PayloadTooLarge { payload: String, max_size: u64 },
+    #[error("Unable to decode base64 custom data")]
+    CustomDataDecode(#[source] base64::DecodeError),
+    #[error("Unable to decode base64 user data")]
+    UserDataDecode(#[source] base64::DecodeError),
+    #[error("executing {command} failed: {status}{}", stderr_suffix(stderr))]
     SubprocessFailed {
         command: String,
         status: std::process::ExitStatus,
+        /// The command's standard error, truncated to
+        /// [`MAX_STDERR_LEN`] bytes; empty if it wasn't captured.
+        stderr: String,
+    },
+    #[error("{command} did not finish within {timeout:?}")]
+    CommandTimeout {
+        command: String,
+        timeout: std::time::Duration,
     },
     #[error("failed to construct a C-style string")]
     NulError(#[from] std::ffi::NulError),
@@ -27,8 +69,215 @@ pub enum Error {
     Nix(#[from] nix::Error),
     #[error("The user {user} does not exist")]
     UserMissing { user: String },
-    #[error("Provisioning a user with a non-empty password is not supported")]
+    #[error("The user {user} already exists with conflicting attributes")]
+    UserExists { user: String },
+    #[error("UID {uid} is already in use by {user}")]
+    UidInUse { uid: u32, user: String },
+    #[error("The group {group} does not exist")]
+    MissingGroup { group: String },
+    #[error("The login shell {shell} does not exist")]
+    InvalidShell { shell: String },
+    #[error("Invalid home directory {}: {reason}", path.display())]
+    InvalidHomeDirectory {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    #[error("Invalid SSH directory {}: {reason}", path.display())]
+    InvalidSshDirectory {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    #[error("Provisioning a user with a plaintext password is not supported")]
     NonEmptyPassword,
+    #[error("Invalid SSH public key: {reason}")]
+    InvalidSshKey { reason: String },
+    #[error("Invalid hostname: {reason}")]
+    InvalidHostname { reason: String },
+    #[error("{path} holds no valid SSH public keys")]
+    NoAuthorizedKeys { path: std::path::PathBuf },
+    #[error("The hostname is missing or empty")]
+    HostnameMissing,
+    #[error("The hostname is '{actual}', expected '{expected}'")]
+    HostnameMismatch { expected: String, actual: String },
+    #[error("No {resource} provisioners were given")]
+    NoProvisioners { resource: String },
+    #[error("Unknown provisioner '{name}'")]
+    UnknownProvisioner { name: String },
+    #[error("No free user or group ID is left")]
+    NoFreeId,
+    #[error("{program} can only provision the running system, not {}", root_dir.display())]
+    RootDirUnsupported {
+        program: String,
+        root_dir: std::path::PathBuf,
+    },
+    /// Every resource that failed to provision, when provisioning carried
+    /// on past the first failure.
+    #[error("Failed to provision {}", resource_errors(.0))]
+    ProvisionAggregate(Vec<ResourceError>),
+    #[error("A provisioning task failed to complete")]
+    TaskFailed(#[from] tokio::task::JoinError),
+    #[error("Provisioning did not finish within {timeout:?}")]
+    ProvisionTimeout { timeout: std::time::Duration },
+    #[error("Provisioning was cancelled: {reason}")]
+    ProvisionCancelled { reason: String },
+    #[error("Unable to mount the provisioning media {device}")]
+    MountFailed {
+        device: String,
+        /// The OS error number, if the failure came from a system call
+        /// rather than from the mount command.
+        errno: Option<i32>,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error("No provisioning media devices were found")]
+    NoProvisioningMedia,
+    #[error("None of the provisioning media devices {devices:?} hold a valid OVF environment")]
+    NoValidOvfEnvironment {
+        devices: Vec<String>,
+        /// The error from the last device tried.
+        #[source]
+        source: Box<Error>,
+    },
     #[error("Unable to get list of block devices")]
     BlockUtils(#[from] block_utils::BlockUtilsError),
 }
+
+/// Why a resource, such as a user or the hostname, failed to provision.
+#[derive(thiserror::Error, Debug)]
+#[error("{resource}")]
+pub struct ResourceError {
+    /// The resource, e.g. `user:azureuser` or `hostname`.
+    pub resource: String,
+    /// The error from the backend tried last; earlier backends' errors are
+    /// logged as they fail.
+    #[source]
+    pub error: Error,
+}
+
+impl ResourceError {
+    pub fn new(resource: impl Into<String>, error: Error) -> Self {
+        Self {
+            resource: resource.into(),
+            error,
+        }
+    }
+}
+
+impl Error {
+    /// Build an [`Error::SubprocessFailed`] for `command` from its captured
+    /// output, and log the stderr it printed.
+    pub(crate) fn subprocess_failed(
+        command: impl Into<String>,
+        output: &std::process::Output,
+    ) -> Self {
+        let command = command.into();
+        let stderr = truncate_stderr(&output.stderr);
+        tracing::warn!(
+            command,
+            status = %output.status,
+            stderr,
+            "Command failed"
+        );
+
+        Error::SubprocessFailed {
+            command,
+            status: output.status,
+            stderr,
+        }
+    }
+}
+
+fn truncate_stderr(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim();
+    if stderr.len() <= MAX_STDERR_LEN {
+        return stderr.to_string();
+    }
+
+    let mut end = MAX_STDERR_LEN;
+    while !stderr.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &stderr[..end])
+}
+
+fn resource_errors(errors: &[ResourceError]) -> String {
+    errors
+        .iter()
+        .map(|failure| format!("{} ({})", failure.resource, failure.error))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn stderr_suffix(stderr: &str) -> String {
+    if stderr.is_empty() {
+        String::new()
+    } else {
+        format!(": {stderr}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{ExitStatus, Output};
+
+    use super::{Error, ResourceError, MAX_STDERR_LEN};
+
+    #[test]
+    fn test_subprocess_failed_includes_stderr() {
+        let output = Output {
+            status: ExitStatus::from_raw(9 << 8),
+            stdout: Vec::new(),
+            stderr: b"useradd: user 'azureuser' already exists\n".to_vec(),
+        };
+
+        let error = Error::subprocess_failed("useradd", &output);
+        assert_eq!(
+            error.to_string(),
+            "executing useradd failed: exit status: 9: \
+            useradd: user 'azureuser' already exists"
+        );
+    }
+
+    #[test]
+    fn test_subprocess_failed_truncates_stderr() {
+        let output = Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: Vec::new(),
+            stderr: "é".repeat(MAX_STDERR_LEN).into_bytes(),
+        };
+
+        match Error::subprocess_failed("mount", &output) {
+            Error::SubprocessFailed { stderr, .. } => {
+                assert!(stderr.len() <= MAX_STDERR_LEN + 3);
+                assert!(stderr.ends_with("..."));
+            }
+            _ => panic!("Expected a SubprocessFailed error"),
+        }
+    }
+
+    #[test]
+    fn test_provision_aggregate_lists_resources() {
+        let error = Error::ProvisionAggregate(vec![
+            ResourceError::new(
+                "user:azureuser",
+                Error::UserExists {
+                    user: "azureuser".to_string(),
+                },
+            ),
+            ResourceError::new("hostname", Error::NoFreeId),
+        ]);
+
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Failed to provision user:azureuser ({}), hostname ({})",
+                Error::UserExists {
+                    user: "azureuser".to_string()
+                },
+                Error::NoFreeId
+            )
+        );
+    }
+}
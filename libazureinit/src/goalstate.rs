@@ -1,19 +1,61 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use std::time::Duration;
+
 use reqwest;
 use reqwest::header::HeaderMap;
 use reqwest::header::HeaderValue;
 use reqwest::Client;
+use reqwest::StatusCode;
 
 use serde::Deserialize;
 use serde_xml_rs::from_str;
 
 use crate::error::Error;
+use crate::http::{self, RetryConfig};
+
+/// Base address of the Azure wireserver.
+pub const DEFAULT_WIRESERVER_ENDPOINT: &str = "http://168.63.129.16";
+/// How long a single wireserver request may take.
+pub const DEFAULT_WIRESERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Settings controlling how the wireserver is contacted.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WireserverConfig {
+    /// Base URL of the wireserver, without the `/machine` path.
+    pub endpoint: String,
+    /// How failed health reports are retried.
+    pub retry: RetryConfig,
+    /// How long each request may take. This overrides any timeout set on
+    /// the client.
+    #[serde(skip)]
+    pub timeout: Duration,
+}
+
+impl Default for WireserverConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: DEFAULT_WIRESERVER_ENDPOINT.to_owned(),
+            retry: RetryConfig::default(),
+            timeout: DEFAULT_WIRESERVER_TIMEOUT,
+        }
+    }
+}
+
+impl WireserverConfig {
+    fn machine_url(&self, component: &str) -> String {
+        format!(
+            "{}/machine/?comp={component}",
+            self.endpoint.trim_end_matches('/')
+        )
+    }
+}
 
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct Goalstate {
-    #[serde(rename = "Container")]
+    #[serde(rename = "Container", default)]
     container: Container,
     #[serde(rename = "Version")]
     version: String,
@@ -21,34 +63,62 @@ pub struct Goalstate {
     incarnation: String,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct Container {
-    #[serde(rename = "ContainerId")]
+    #[serde(rename = "ContainerId", default)]
     container_id: String,
-    #[serde(rename = "RoleInstanceList")]
+    #[serde(rename = "RoleInstanceList", default)]
     role_instance_list: RoleInstanceList,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct RoleInstanceList {
-    #[serde(rename = "RoleInstance")]
+    #[serde(rename = "RoleInstance", default)]
     role_instance: RoleInstance,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct RoleInstance {
-    #[serde(rename = "InstanceId")]
+    #[serde(rename = "InstanceId", default)]
     instance_id: String,
 }
 
-pub async fn get_goalstate(client: &Client) -> Result<Goalstate, Error> {
-    let url = "http://168.63.129.16/machine/?comp=goalstate";
+impl Goalstate {
+    /// The `Container/ContainerId` of the goalstate.
+    pub fn container_id(&self) -> Result<&str, Error> {
+        non_empty(&self.container.container_id, "Container.ContainerId")
+    }
+
+    /// The `InstanceId` of the goalstate's role instance.
+    pub fn instance_id(&self) -> Result<&str, Error> {
+        non_empty(
+            &self.container.role_instance_list.role_instance.instance_id,
+            "Container.RoleInstanceList.RoleInstance.InstanceId",
+        )
+    }
+}
+
+fn non_empty<'a>(value: &'a str, field: &str) -> Result<&'a str, Error> {
+    if value.is_empty() {
+        return Err(Error::GoalstateFieldMissing {
+            field: field.to_string(),
+        });
+    }
+
+    Ok(value)
+}
+
+pub async fn get_goalstate(
+    client: &Client,
+    config: &WireserverConfig,
+) -> Result<Goalstate, Error> {
+    let url = config.machine_url("goalstate");
 
     let mut headers = HeaderMap::new();
     headers.insert("x-ms-agent-name", HeaderValue::from_static("azure-init"));
     headers.insert("x-ms-version", HeaderValue::from_static("2012-11-30"));
 
-    let request = client.get(url).headers(headers);
+    let request = client.get(&url).headers(headers).timeout(config.timeout);
     let response = request.send().await?;
 
     if response.status().is_success() {
@@ -58,18 +128,71 @@ pub async fn get_goalstate(client: &Client) -> Result<Goalstate, Error> {
         Ok(goalstate)
     } else {
         Err(Error::HttpStatus {
-            endpoint: url.to_owned(),
+            endpoint: url,
             status: response.status(),
         })
     }
 }
 
+/// Report to the wireserver that the VM is ready.
+///
+/// Connection errors and server errors are retried according to
+/// `config.retry`; client errors are not.
 pub async fn report_health(
     client: &Client,
     goalstate: Goalstate,
+    config: &WireserverConfig,
+) -> Result<(), Error> {
+    post_health(client, build_report_health_file(goalstate)?, config).await
+}
+
+/// Report to the wireserver that provisioning failed, so the VM is marked
+/// as failed rather than left to time out.
+///
+/// `details` is sent as the description of the failure. Failed reports are
+/// retried like those sent by [`report_health`].
+pub async fn report_failure(
+    client: &Client,
+    goalstate: Goalstate,
+    details: &str,
+    config: &WireserverConfig,
 ) -> Result<(), Error> {
-    let url = "http://168.63.129.16/machine/?comp=health";
+    let post_request = build_report_failure_file(goalstate, details)?;
 
+    post_health(client, post_request, config).await
+}
+
+async fn post_health(
+    client: &Client,
+    post_request: String,
+    config: &WireserverConfig,
+) -> Result<(), Error> {
+    let url = config.machine_url("health");
+
+    http::retry(&config.retry, "wireserver health", is_retryable, || {
+        send_health(client, &url, post_request.clone(), config.timeout)
+    })
+    .await
+}
+
+fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::Http(error) => {
+            error.is_connect() || error.is_timeout() || error.is_request()
+        }
+        Error::HttpStatus { status, .. } => {
+            status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        _ => false,
+    }
+}
+
+async fn send_health(
+    client: &Client,
+    url: &str,
+    post_request: String,
+    timeout: Duration,
+) -> Result<(), Error> {
     let mut headers = HeaderMap::new();
     headers.insert("x-ms-agent-name", HeaderValue::from_static("azure-init"));
     headers.insert("x-ms-version", HeaderValue::from_static("2012-11-30"));
@@ -78,12 +201,11 @@ pub async fn report_health(
         HeaderValue::from_static("text/xml;charset=utf-8"),
     );
 
-    let post_request = build_report_health_file(goalstate);
-
     let response = client
         .post(url)
         .headers(headers)
         .body(post_request)
+        .timeout(timeout)
         .send()
         .await?;
 
@@ -97,7 +219,30 @@ pub async fn report_health(
     }
 }
 
-fn build_report_health_file(goalstate: Goalstate) -> String {
+fn build_report_health_file(goalstate: Goalstate) -> Result<String, Error> {
+    build_health_file(goalstate, "<State>Ready</State>\n")
+}
+
+fn build_report_failure_file(
+    goalstate: Goalstate,
+    details: &str,
+) -> Result<String, Error> {
+    let health = format!(
+        "<State>NotReady</State>\n\
+        <Details>\n\
+            <SubStatus>ProvisioningFailed</SubStatus>\n\
+            <Description>{}</Description>\n\
+        </Details>\n",
+        escape_xml(details)
+    );
+
+    build_health_file(goalstate, &health)
+}
+
+fn build_health_file(
+    goalstate: Goalstate,
+    health: &str,
+) -> Result<String, Error> {
     let post_request =
     "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
     <Health xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n\
@@ -108,7 +253,7 @@ fn build_report_health_file(goalstate: Goalstate) -> String {
                 <Role>\n\
                     <InstanceId>$INSTANCE_ID</InstanceId>\n\
                     <Health>\n\
-                        <State>Ready</State>\n\
+                        $HEALTH\
                     </Health>\n\
                 </Role>\n\
             </RoleInstanceList>\n\
@@ -117,21 +262,56 @@ fn build_report_health_file(goalstate: Goalstate) -> String {
 
     let post_request =
         post_request.replace("$GOAL_STATE_INCARNATION", &goalstate.incarnation);
-    let post_request = post_request
-        .replace("$CONTAINER_ID", &goalstate.container.container_id);
-    post_request.replace(
-        "$INSTANCE_ID",
-        &goalstate
-            .container
-            .role_instance_list
-            .role_instance
-            .instance_id,
-    )
+    let post_request =
+        post_request.replace("$CONTAINER_ID", goalstate.container_id()?);
+    let post_request =
+        post_request.replace("$INSTANCE_ID", goalstate.instance_id()?);
+
+    // The health may quote user data or command output, so it goes in last
+    // to keep any placeholders in it as they are.
+    Ok(post_request.replace("$HEALTH", health))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{build_report_health_file, Goalstate};
+    use super::{
+        build_report_failure_file, build_report_health_file, get_goalstate,
+        is_retryable, report_health, Goalstate, WireserverConfig,
+    };
+    use crate::error::Error;
+    use crate::http::test_server::{http_response, serve_responses};
+    use crate::http::RetryConfig;
+
+    use std::time::Duration;
+
+    use reqwest::Client;
+
+    use reqwest::StatusCode;
+
+    fn status_error(status: StatusCode) -> Error {
+        Error::HttpStatus {
+            endpoint: "http://168.63.129.16/machine/?comp=health".to_string(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_health_report_retries_server_errors_only() {
+        assert!(is_retryable(&status_error(
+            StatusCode::INTERNAL_SERVER_ERROR
+        )));
+        assert!(is_retryable(&status_error(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(!is_retryable(&status_error(StatusCode::BAD_REQUEST)));
+        assert!(!is_retryable(&status_error(StatusCode::NOT_FOUND)));
+    }
 
     #[test]
     fn test_parsing_goalstate() {
@@ -197,7 +377,172 @@ mod tests {
             </Container>\n\
         </Health>";
 
-        let actual_output = build_report_health_file(goalstate);
+        let actual_output = build_report_health_file(goalstate).unwrap();
+        assert_eq!(actual_output, expected_output);
+    }
+
+    #[test]
+    fn test_build_report_failure_file() {
+        let goalstate_str = "
+            <Goalstate>
+                <Container>
+                    <ContainerId>2</ContainerId>
+                    <RoleInstanceList>
+                        <RoleInstance>
+                            <InstanceId>test_user_instance_id</InstanceId>
+                        </RoleInstance>
+                    </RoleInstanceList>
+                </Container>
+                <Version>example_version</Version>
+                <Incarnation>test_goal_incarnation</Incarnation>
+            </Goalstate>";
+        let goalstate: Goalstate = serde_xml_rs::from_str(goalstate_str)
+            .expect("Failed to parse the goalstate XML.");
+
+        let expected_output =
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <Health xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n\
+            <GoalStateIncarnation>test_goal_incarnation</GoalStateIncarnation>\n\
+            <Container>\n\
+                <ContainerId>2</ContainerId>\n\
+                <RoleInstanceList>\n\
+                    <Role>\n\
+                        <InstanceId>test_user_instance_id</InstanceId>\n\
+                        <Health>\n\
+                            <State>NotReady</State>\n\
+                            <Details>\n\
+                                <SubStatus>ProvisioningFailed</SubStatus>\n\
+                                <Description>create_user: useradd &lt;azureuser&gt; failed</Description>\n\
+                            </Details>\n\
+                        </Health>\n\
+                    </Role>\n\
+                </RoleInstanceList>\n\
+            </Container>\n\
+        </Health>";
+
+        let actual_output = build_report_failure_file(
+            goalstate,
+            "create_user: useradd <azureuser> failed",
+        )
+        .unwrap();
         assert_eq!(actual_output, expected_output);
     }
+
+    #[test]
+    fn test_build_report_failure_file_keeps_placeholders() {
+        let goalstate: Goalstate = serde_xml_rs::from_str(
+            "<Goalstate>
+                <Container>
+                    <ContainerId>2</ContainerId>
+                    <RoleInstanceList>
+                        <RoleInstance>
+                            <InstanceId>test_user_instance_id</InstanceId>
+                        </RoleInstance>
+                    </RoleInstanceList>
+                </Container>
+                <Version>example_version</Version>
+                <Incarnation>test_goal_incarnation</Incarnation>
+            </Goalstate>",
+        )
+        .unwrap();
+
+        let actual_output = build_report_failure_file(
+            goalstate,
+            "useradd: invalid user name '$INSTANCE_ID$CONTAINER_ID'",
+        )
+        .unwrap();
+        assert!(actual_output.contains(
+            "<Description>useradd: invalid user name \
+            &apos;$INSTANCE_ID$CONTAINER_ID&apos;</Description>"
+        ));
+        assert_eq!(actual_output.matches("test_user_instance_id").count(), 1);
+    }
+
+    #[test]
+    fn test_goalstate_ids() {
+        let goalstate_str = "<Goalstate>
+            <Container>
+                <ContainerId>2</ContainerId>
+                <RoleInstanceList>
+                    <RoleInstance>
+                        <InstanceId>test_user_instance_id</InstanceId>
+                    </RoleInstance>
+                </RoleInstanceList>
+            </Container>
+            <Version>example_version</Version>
+            <Incarnation>test_goal_incarnation</Incarnation>
+        </Goalstate>";
+        let goalstate: Goalstate = serde_xml_rs::from_str(goalstate_str)
+            .expect("Failed to parse the goalstate XML.");
+
+        assert_eq!(goalstate.container_id().unwrap(), "2");
+        assert_eq!(goalstate.instance_id().unwrap(), "test_user_instance_id");
+    }
+
+    #[test]
+    fn test_goalstate_missing_ids() {
+        let goalstate_str = "<Goalstate>
+            <Container>
+                <ContainerId>2</ContainerId>
+            </Container>
+            <Version>example_version</Version>
+            <Incarnation>test_goal_incarnation</Incarnation>
+        </Goalstate>";
+        let goalstate: Goalstate = serde_xml_rs::from_str(goalstate_str)
+            .expect("Failed to parse the goalstate XML.");
+
+        assert_eq!(goalstate.container_id().unwrap(), "2");
+        match goalstate.instance_id() {
+            Err(Error::GoalstateFieldMissing { field }) => {
+                assert!(field.ends_with("InstanceId"))
+            }
+            _ => panic!("A missing instance ID should be an error"),
+        }
+        match build_report_health_file(goalstate) {
+            Err(Error::GoalstateFieldMissing { .. }) => {}
+            _ => panic!("Health reports need an instance ID"),
+        }
+    }
+
+    const GOALSTATE_XML: &str = "<Goalstate>
+        <Container>
+            <ContainerId>2</ContainerId>
+            <RoleInstanceList>
+                <RoleInstance>
+                    <InstanceId>test_user_instance_id</InstanceId>
+                </RoleInstance>
+            </RoleInstanceList>
+        </Container>
+        <Version>example_version</Version>
+        <Incarnation>test_goal_incarnation</Incarnation>
+    </Goalstate>";
+
+    #[tokio::test]
+    async fn test_custom_wireserver_endpoint() {
+        let endpoint = serve_responses(vec![
+            http_response("200 OK", GOALSTATE_XML),
+            http_response("503 Service Unavailable", ""),
+            http_response("200 OK", ""),
+        ])
+        .await;
+        let config = WireserverConfig {
+            endpoint,
+            retry: RetryConfig {
+                max_attempts: 2,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(5),
+            },
+            ..Default::default()
+        };
+        let client = Client::new();
+
+        let goalstate = get_goalstate(&client, &config)
+            .await
+            .expect("Failed to get the goalstate from the mock wireserver.");
+        assert_eq!(goalstate.container_id().unwrap(), "2");
+
+        report_health(&client, goalstate, &config)
+            .await
+            .expect("Failed to report health after retrying.");
+    }
 }
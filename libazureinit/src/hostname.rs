@@ -0,0 +1,590 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::Runner;
+use crate::error::Error;
+
+pub const PATH_ETC_HOSTNAME: &str = "/etc/hostname";
+pub const PATH_ETC_HOSTS: &str = "/etc/hosts";
+
+// Debian-family systems map the hostname to this address so it resolves
+// without network access.
+const HOSTNAME_LOOPBACK: &str = "127.0.1.1";
+
+/// Backends able to set the hostname.
+///
+/// Provisioners are named in the configuration as in their
+/// [`FromStr`] implementation, e.g. `etc_hostname` or `etc-hostname`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", try_from = "String")]
+pub enum Provisioner {
+    /// Set the hostname with `hostnamectl set-hostname`.
+    Hostnamectl,
+    /// Set the hostname with `nmcli general hostname`, which also lets
+    /// NetworkManager know about it.
+    Nmcli,
+    /// Write the hostname to `/etc/hostname` and set the running hostname
+    /// with sethostname(2). Useful where systemd isn't available.
+    EtcHostname,
+}
+
+/// Backends tried, in order, when the caller doesn't choose any.
+pub const DEFAULT_PROVISIONERS: &[Provisioner] = &[
+    Provisioner::Hostnamectl,
+    Provisioner::Nmcli,
+    Provisioner::EtcHostname,
+];
+
+/// How the case of the hostname from the platform is handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Case {
+    /// Set the hostname exactly as given.
+    #[default]
+    Preserve,
+    /// Lowercase the hostname, since Azure may provide uppercase
+    /// characters that some services don't expect.
+    Lowercase,
+}
+
+impl Case {
+    /// Apply the case handling to `hostname`.
+    ///
+    /// The result still needs to be checked with [`validate`], as
+    /// [`set_hostname`] does.
+    pub fn normalize(&self, hostname: &str) -> String {
+        match self {
+            Case::Preserve => hostname.to_string(),
+            Case::Lowercase => hostname.to_ascii_lowercase(),
+        }
+    }
+}
+
+impl FromStr for Provisioner {
+    type Err = Error;
+
+    /// Parse a provisioner's snake_case name, in which hyphens may stand in
+    /// for underscores.
+    fn from_str(name: &str) -> Result<Self, Error> {
+        match name.replace('-', "_").as_str() {
+            "hostnamectl" => Ok(Provisioner::Hostnamectl),
+            "nmcli" => Ok(Provisioner::Nmcli),
+            "etc_hostname" => Ok(Provisioner::EtcHostname),
+            _ => Err(Error::UnknownProvisioner {
+                name: name.to_string(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for Provisioner {
+    type Error = Error;
+
+    fn try_from(name: String) -> Result<Self, Error> {
+        name.parse()
+    }
+}
+
+impl Provisioner {
+    /// Set the hostname to `hostname`.
+    ///
+    /// Under a root directory other than `/`, only
+    /// [`Provisioner::EtcHostname`] works, and only writes the file.
+    pub fn set(&self, hostname: &str, runner: &Runner) -> Result<(), Error> {
+        let path = runner.path(PATH_ETC_HOSTNAME);
+        match self {
+            Provisioner::Hostnamectl | Provisioner::Nmcli
+                if !runner.is_system_root() =>
+            {
+                Err(Error::RootDirUnsupported {
+                    program: format!("{self:?}").to_lowercase(),
+                    root_dir: runner.root().to_path_buf(),
+                })
+            }
+            Provisioner::Hostnamectl => hostnamectl(hostname, runner),
+            Provisioner::Nmcli => nmcli(hostname, runner),
+            Provisioner::EtcHostname if runner.is_dry_run() => {
+                tracing::info!(
+                    path = %path.display(),
+                    hostname,
+                    "Dry run, not writing hostname file or calling sethostname"
+                );
+                Ok(())
+            }
+            Provisioner::EtcHostname => {
+                write_hostname_file(&path, hostname, runner)?;
+                if runner.is_system_root() {
+                    nix::unistd::sethostname(hostname)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Check that `hostname` is valid according to RFC 1123.
+///
+/// The name must be at most 253 characters long and consist of
+/// dot-separated labels of 1 to 63 ASCII letters, digits, or hyphens, where
+/// no label starts or ends with a hyphen.
+pub fn validate(hostname: &str) -> Result<(), Error> {
+    let invalid = |reason: String| Err(Error::InvalidHostname { reason });
+
+    if hostname.is_empty() {
+        return invalid("hostname is empty".to_string());
+    }
+    if hostname.len() > 253 {
+        return invalid(format!(
+            "hostname is {} characters long, the maximum is 253",
+            hostname.len()
+        ));
+    }
+
+    for label in hostname.split('.') {
+        if label.is_empty() {
+            return invalid("hostname contains an empty label".to_string());
+        }
+        if label.len() > 63 {
+            return invalid(format!(
+                "label '{label}' is {} characters long, the maximum is 63",
+                label.len()
+            ));
+        }
+        if let Some(c) = label
+            .chars()
+            .find(|c| !c.is_ascii_alphanumeric() && *c != '-')
+        {
+            return invalid(format!(
+                "label '{label}' contains the invalid character '{c}'"
+            ));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return invalid(format!(
+                "label '{label}' starts or ends with a hyphen"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Set the hostname using the first of `provisioners` that succeeds, and
+/// return the provisioner that did.
+///
+/// The hostname is checked with [`validate`] before any provisioner runs.
+/// A provisioner whose command exceeds the runner's step timeout counts as
+/// failed. If every provisioner fails, the error from the last one is
+/// returned.
+pub fn set_hostname(
+    hostname: &str,
+    provisioners: &[Provisioner],
+    runner: &Runner,
+) -> Result<Provisioner, Error> {
+    validate(hostname)?;
+
+    let mut last_error = Error::NoProvisioners {
+        resource: "hostname".to_string(),
+    };
+
+    for provisioner in provisioners {
+        match provisioner.set(hostname, runner) {
+            Ok(()) => {
+                tracing::info!(?provisioner, hostname, "Set hostname");
+                return Ok(*provisioner);
+            }
+            Err(error) => {
+                tracing::warn!(?provisioner, ?error, "Failed to set hostname");
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Make `/etc/hosts` map 127.0.1.1 to `hostname`, so the hostname resolves
+/// locally, e.g. for `sudo`.
+///
+/// An existing 127.0.1.1 entry is replaced rather than another one added.
+pub fn update_etc_hosts(hostname: &str, runner: &Runner) -> Result<(), Error> {
+    let path = runner.path(PATH_ETC_HOSTS);
+    if runner.is_dry_run() {
+        tracing::info!(
+            path = %path.display(),
+            hostname,
+            "Dry run, not updating the hosts file"
+        );
+        return Ok(());
+    }
+
+    write_hosts_entry(&path, hostname, runner)
+}
+
+fn write_hosts_entry(
+    path: &Path,
+    hostname: &str,
+    runner: &Runner,
+) -> Result<(), Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entry = format!("{HOSTNAME_LOOPBACK}\t{hostname}");
+    if let Some((short, _)) = hostname.split_once('.') {
+        entry.push(' ');
+        entry.push_str(short);
+    }
+
+    let mut replaced = false;
+    let mut lines: Vec<&str> = Vec::new();
+    for line in contents.lines() {
+        if line.split_whitespace().next() == Some(HOSTNAME_LOOPBACK) {
+            if !replaced {
+                lines.push(&entry);
+                replaced = true;
+            }
+        } else {
+            lines.push(line);
+        }
+    }
+    if !replaced {
+        lines.push(&entry);
+    }
+
+    runner.write_file(path, (lines.join("\n") + "\n").as_bytes(), 0o644, None)
+}
+
+fn hostnamectl(hostname: &str, runner: &Runner) -> Result<(), Error> {
+    let output = runner.output(
+        Command::new("hostnamectl")
+            .arg("set-hostname")
+            .arg(hostname),
+    )?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::subprocess_failed("hostnamectl", &output))
+    }
+}
+
+fn nmcli(hostname: &str, runner: &Runner) -> Result<(), Error> {
+    let output = runner.output(
+        Command::new("nmcli")
+            .arg("general")
+            .arg("hostname")
+            .arg(hostname),
+    )?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::subprocess_failed("nmcli", &output))
+    }
+}
+
+fn write_hostname_file(
+    path: &Path,
+    hostname: &str,
+    runner: &Runner,
+) -> Result<(), Error> {
+    runner.write_file(path, format!("{hostname}\n").as_bytes(), 0o644, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::{
+        set_hostname, validate, write_hostname_file, write_hosts_entry, Case,
+        Provisioner, DEFAULT_PROVISIONERS,
+    };
+    use crate::command::{RecordingCommandRunner, Runner};
+    use crate::error::Error;
+
+    #[test]
+    fn test_write_hostname_file() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let path = test_dir.path().join("hostname");
+        std::fs::write(&path, "old-hostname\n").unwrap();
+
+        let runner = Runner::new();
+        write_hostname_file(&path, "new-hostname", &runner).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new-hostname\n");
+        let written = runner.written_files();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].path, path);
+        assert_eq!(written[0].size, 13);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+    }
+
+    #[test]
+    fn test_write_hosts_entry_appends() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let path = test_dir.path().join("hosts");
+        std::fs::write(&path, "127.0.0.1\tlocalhost\n::1\tlocalhost\n")
+            .unwrap();
+
+        write_hosts_entry(&path, "new-hostname", &Runner::new()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "127.0.0.1\tlocalhost\n::1\tlocalhost\n127.0.1.1\tnew-hostname\n"
+        );
+    }
+
+    #[test]
+    fn test_write_hosts_entry_replaces_existing() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let path = test_dir.path().join("hosts");
+        std::fs::write(
+            &path,
+            "127.0.0.1 localhost\n127.0.1.1 old-hostname\n\
+            # comment\n127.0.1.1 duplicate\n",
+        )
+        .unwrap();
+
+        write_hosts_entry(&path, "vm1.internal.cloudapp.net", &Runner::new())
+            .unwrap();
+        write_hosts_entry(&path, "vm1.internal.cloudapp.net", &Runner::new())
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "127.0.0.1 localhost\n\
+            127.0.1.1\tvm1.internal.cloudapp.net vm1\n# comment\n"
+        );
+    }
+
+    #[test]
+    fn test_write_hosts_entry_missing_file() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let path = test_dir.path().join("hosts");
+
+        write_hosts_entry(&path, "new-hostname", &Runner::new()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "127.0.1.1\tnew-hostname\n"
+        );
+    }
+
+    #[test]
+    fn test_validate_accepts_valid_hostnames() {
+        validate("AzTux-MinProvAgent-Test-0001").unwrap();
+        validate("vm1.internal.cloudapp.net").unwrap();
+        validate("a").unwrap();
+        validate(&"a".repeat(63)).unwrap();
+        validate(
+            &[
+                "a".repeat(63),
+                "b".repeat(63),
+                "c".repeat(63),
+                "d".repeat(61),
+            ]
+            .join("."),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_validate_rejects_invalid_hostnames() {
+        let long_name = [
+            "a".repeat(63),
+            "b".repeat(63),
+            "c".repeat(63),
+            "d".repeat(62),
+        ]
+        .join(".");
+        let invalid = [
+            "",
+            "-leading-hyphen",
+            "trailing-hyphen-",
+            "under_score",
+            "white space",
+            "double..dot",
+            "trailing.dot.",
+            &"a".repeat(64),
+            &long_name,
+        ];
+
+        for hostname in invalid {
+            match validate(hostname) {
+                Err(Error::InvalidHostname { .. }) => {}
+                _ => panic!("'{hostname}' should be rejected"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_case_normalize() {
+        let hostname = "Test-VM.Example.COM";
+
+        assert_eq!(Case::Preserve.normalize(hostname), hostname);
+        assert_eq!(Case::Lowercase.normalize(hostname), "test-vm.example.com");
+        validate(&Case::Lowercase.normalize(hostname)).unwrap();
+
+        // Lowercasing doesn't make an invalid hostname valid.
+        assert!(validate(&Case::Lowercase.normalize("Bad_Host")).is_err());
+    }
+
+    #[test]
+    fn test_provisioner_from_str() {
+        assert_eq!(
+            "hostnamectl".parse::<Provisioner>().unwrap(),
+            Provisioner::Hostnamectl
+        );
+        assert_eq!(
+            "etc_hostname".parse::<Provisioner>().unwrap(),
+            Provisioner::EtcHostname
+        );
+        assert_eq!(
+            "etc-hostname".parse::<Provisioner>().unwrap(),
+            Provisioner::EtcHostname
+        );
+        match "hostnamectll".parse::<Provisioner>() {
+            Err(Error::UnknownProvisioner { name }) => {
+                assert_eq!(name, "hostnamectll")
+            }
+            _ => panic!("Misspelled provisioners should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_set_hostname_validates_first() {
+        match set_hostname(
+            "-invalid",
+            &[Provisioner::Hostnamectl],
+            &Runner::new(),
+        ) {
+            Err(Error::InvalidHostname { reason }) => {
+                assert!(reason.contains("hyphen"))
+            }
+            _ => panic!("Invalid hostnames should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_set_hostname_dry_run() {
+        let runner = Runner::new().dry_run(true);
+        let before = nix::unistd::gethostname().unwrap();
+
+        for provisioner in DEFAULT_PROVISIONERS {
+            assert_eq!(
+                set_hostname("dry-run-hostname", &[*provisioner], &runner)
+                    .unwrap(),
+                *provisioner
+            );
+        }
+
+        assert_eq!(nix::unistd::gethostname().unwrap(), before);
+    }
+
+    #[test]
+    fn test_set_hostname_without_provisioners() {
+        match set_hostname("test-hostname", &[], &Runner::new()) {
+            Err(Error::NoProvisioners { resource }) => {
+                assert_eq!(resource, "hostname")
+            }
+            _ => panic!("Setting a hostname needs at least one provisioner"),
+        }
+    }
+
+    #[test]
+    fn test_hostnamectl_arguments() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        assert_eq!(
+            set_hostname("test-hostname", &[Provisioner::Hostnamectl], &runner)
+                .unwrap(),
+            Provisioner::Hostnamectl
+        );
+
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].program, "hostnamectl");
+        assert_eq!(commands[0].args, ["set-hostname", "test-hostname"]);
+    }
+
+    #[test]
+    fn test_nmcli_arguments() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        assert_eq!(
+            set_hostname("test-hostname", &[Provisioner::Nmcli], &runner)
+                .unwrap(),
+            Provisioner::Nmcli
+        );
+
+        let commands = recorder.commands();
+        assert_eq!(commands[0].program, "nmcli");
+        assert_eq!(commands[0].args, ["general", "hostname", "test-hostname"]);
+    }
+
+    #[test]
+    fn test_set_hostname_falls_back_after_failure() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        recorder.fail(
+            "hostnamectl",
+            1,
+            "System has not been booted with systemd",
+        );
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        assert_eq!(
+            set_hostname(
+                "test-hostname",
+                &[Provisioner::Hostnamectl, Provisioner::Nmcli],
+                &runner
+            )
+            .unwrap(),
+            Provisioner::Nmcli
+        );
+
+        let programs: Vec<String> =
+            recorder.commands().into_iter().map(|c| c.program).collect();
+        assert_eq!(programs, ["hostnamectl", "nmcli"]);
+    }
+
+    #[test]
+    fn test_set_hostname_under_root_dir() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("etc")).unwrap();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir(root.path());
+
+        let provisioner =
+            set_hostname("test-hostname", DEFAULT_PROVISIONERS, &runner)
+                .unwrap();
+
+        // Only the image's hostname file is written; the running system's
+        // hostname is left alone.
+        assert_eq!(provisioner, Provisioner::EtcHostname);
+        assert!(recorder.commands().is_empty());
+        assert_eq!(
+            std::fs::read_to_string(root.path().join("etc/hostname")).unwrap(),
+            "test-hostname\n"
+        );
+        match Provisioner::Hostnamectl.set("test-hostname", &runner) {
+            Err(Error::RootDirUnsupported { program, .. }) => {
+                assert_eq!(program, "hostnamectl")
+            }
+            _ => panic!("hostnamectl can't set an image's hostname"),
+        }
+    }
+}
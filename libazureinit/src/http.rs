@@ -0,0 +1,650 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+#[cfg(feature = "imds")]
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(feature = "imds")]
+use reqwest::{header, Certificate, Client, ClientBuilder, NoProxy, Proxy};
+use serde::Deserialize;
+
+use crate::error::Error;
+
+/// The `User-Agent` sent with requests to the Azure platform, unless
+/// [`HttpConfig::user_agent`] says otherwise.
+#[cfg(feature = "imds")]
+pub const DEFAULT_USER_AGENT: &str =
+    concat!("azure-init v", env!("CARGO_PKG_VERSION"));
+/// How long a request may take by default, unless it sets its own timeout.
+#[cfg(feature = "imds")]
+pub const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 30;
+/// Addresses of the platform endpoints, IMDS on the IPv4 and IPv6
+/// link-local networks and the wireserver, which are reached directly
+/// unless [`HttpConfig::proxy_platform_endpoints`] is set.
+#[cfg(feature = "imds")]
+pub const PLATFORM_NO_PROXY: &[&str] =
+    &["169.254.0.0/16", "fe80::/10", "168.63.129.16"];
+
+/// Settings for the HTTP client that talks to the Azure platform.
+#[cfg(feature = "imds")]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Send every request through this proxy, e.g. `http://proxy:3128`.
+    /// When unset, the proxies named by the `HTTP_PROXY`, `HTTPS_PROXY`
+    /// and `ALL_PROXY` environment variables are used.
+    pub proxy: Option<String>,
+    /// Hosts, domains, IP addresses or CIDR ranges reached without the
+    /// proxy, in addition to those listed in `NO_PROXY`.
+    pub no_proxy: Vec<String>,
+    /// Let requests to the platform endpoints go through the proxy too.
+    /// They are link-local to the VM, so most proxies can't reach them.
+    pub proxy_platform_endpoints: bool,
+    /// PEM files holding CA certificates to trust in addition to the
+    /// built-in ones, e.g. for a TLS-intercepting proxy.
+    pub ca_certs: Vec<PathBuf>,
+    /// How many seconds a request may take, unless the request sets its
+    /// own timeout.
+    pub timeout_secs: u64,
+    /// How many seconds connecting may take; unlimited when unset.
+    pub connect_timeout_secs: Option<u64>,
+    /// The `User-Agent` sent with every request. Products built on
+    /// libazureinit can identify themselves in platform logs with it, e.g.
+    /// `acme-provisioner/1.2 (azure-init 0.1)`.
+    pub user_agent: String,
+    /// Headers sent with every request, in addition to `User-Agent`.
+    #[serde(skip)]
+    pub headers: header::HeaderMap,
+}
+
+#[cfg(feature = "imds")]
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            no_proxy: Vec::new(),
+            proxy_platform_endpoints: false,
+            ca_certs: Vec::new(),
+            timeout_secs: DEFAULT_HTTP_TIMEOUT_SECS,
+            connect_timeout_secs: None,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: header::HeaderMap::new(),
+        }
+    }
+}
+
+/// Build the client used for requests to IMDS and the wireserver.
+///
+/// Compressed responses are decoded transparently, which saves bytes at
+/// boot on large metadata documents.
+#[cfg(feature = "imds")]
+pub fn build_client(config: &HttpConfig) -> Result<Client, Error> {
+    // reqwest reads the environment itself, but only when no proxy is
+    // configured and without the platform exclusions, so do it here.
+    let mut builder = client_builder(config)?.no_proxy();
+    for proxy in proxies(config, |name| std::env::var(name).ok())? {
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Like [`build_client`], but connections go out through `interface`, for
+/// link-local IPv6 endpoints, which are only reachable on a given link.
+///
+/// The client never uses a proxy, since link-local addresses can't be
+/// reached through one.
+#[cfg(feature = "imds")]
+pub fn build_interface_client(
+    config: &HttpConfig,
+    interface: &str,
+) -> Result<Client, Error> {
+    Ok(client_builder(config)?
+        .interface(interface)
+        .no_proxy()
+        .build()?)
+}
+
+// A client builder with the headers, timeouts and CA certificates of
+// `config`, but no proxies.
+#[cfg(feature = "imds")]
+fn client_builder(config: &HttpConfig) -> Result<ClientBuilder, Error> {
+    let mut headers = config.headers.clone();
+    let user_agent = header::HeaderValue::from_str(&config.user_agent)
+        .map_err(|_| Error::InvalidUserAgent {
+            user_agent: config.user_agent.clone(),
+        })?;
+    headers.insert(header::USER_AGENT, user_agent);
+
+    let mut builder = Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .gzip(true)
+        .brotli(true)
+        .deflate(true)
+        .default_headers(headers);
+    if let Some(secs) = config.connect_timeout_secs {
+        builder = builder.connect_timeout(Duration::from_secs(secs));
+    }
+    for path in &config.ca_certs {
+        let pem = std::fs::read(path)?;
+        for certificate in Certificate::from_pem_bundle(&pem)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    Ok(builder)
+}
+
+/// The host of `url`, without the brackets of an IPv6 address, as
+/// `no_proxy` lists it.
+#[cfg(feature = "imds")]
+pub(crate) fn url_host(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+
+    Some(
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string(),
+    )
+}
+
+// The proxies for `config`, reading environment variables through `env`.
+#[cfg(feature = "imds")]
+fn proxies(
+    config: &HttpConfig,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<Proxy>, Error> {
+    // Like curl, accept the lowercase spelling of each variable too.
+    let var = |name: &str| {
+        env(name)
+            .or_else(|| env(&name.to_lowercase()))
+            .filter(|value| !value.is_empty())
+    };
+
+    let no_proxy = NoProxy::from_string(
+        &no_proxy_hosts(config, var("NO_PROXY")).join(","),
+    );
+
+    let mut proxies = Vec::new();
+    if let Some(proxy) = &config.proxy {
+        proxies.push(Proxy::all(proxy)?);
+    } else {
+        if let Some(proxy) = var("HTTPS_PROXY") {
+            proxies.push(Proxy::https(proxy)?);
+        }
+        if let Some(proxy) = var("HTTP_PROXY") {
+            proxies.push(Proxy::http(proxy)?);
+        }
+        if let Some(proxy) = var("ALL_PROXY") {
+            proxies.push(Proxy::all(proxy)?);
+        }
+    }
+
+    Ok(proxies
+        .into_iter()
+        .map(|proxy| proxy.no_proxy(no_proxy.clone()))
+        .collect())
+}
+
+// The hosts reached without a proxy: those in `env_no_proxy`, the value of
+// `NO_PROXY`, and `config.no_proxy` and, unless they are to be proxied too,
+// the platform endpoints.
+#[cfg(feature = "imds")]
+fn no_proxy_hosts(
+    config: &HttpConfig,
+    env_no_proxy: Option<String>,
+) -> Vec<String> {
+    let mut no_proxy: Vec<String> = env_no_proxy.into_iter().collect();
+    no_proxy.extend(config.no_proxy.iter().cloned());
+    if !config.proxy_platform_endpoints {
+        no_proxy.extend(PLATFORM_NO_PROXY.iter().map(|host| host.to_string()));
+    }
+
+    no_proxy
+}
+
+/// Exponential backoff parameters for retrying a request.
+///
+/// The delay before attempt `n` (starting at 1 for the first retry) is
+/// `base_delay * 2^(n - 1)`, capped at `max_delay`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    #[serde(skip)]
+    pub base_delay: Duration,
+    #[serde(skip)]
+    pub max_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(8),
+        }
+    }
+}
+
+impl RetryConfig {
+    fn delay(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// The delay before attempt `retry`, plus a random jitter of up to half
+    /// of it, so that retries racing the same resource spread out.
+    pub(crate) fn jittered_delay(&self, retry: u32) -> Duration {
+        let delay = self.delay(retry);
+        // A freshly seeded hasher is random enough to spread out retries
+        // without pulling in an RNG.
+        let random = RandomState::new().build_hasher().finish();
+        let max_jitter = delay.as_micros() as u64 / 2;
+
+        delay + Duration::from_micros(random % (max_jitter + 1))
+    }
+}
+
+/// Call `request` until it succeeds, it fails with an error for which
+/// `is_retryable` returns false, or `config.max_attempts` is reached.
+///
+/// Each attempt is logged along with `name`, which identifies the request.
+/// If every attempt fails, the last error is returned.
+#[cfg_attr(not(feature = "imds"), allow(dead_code))]
+pub(crate) async fn retry<T, F, Fut>(
+    config: &RetryConfig,
+    name: &str,
+    is_retryable: fn(&Error) -> bool,
+    mut request: F,
+) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let max_attempts = config.max_attempts.max(1);
+    let mut attempt = 1;
+
+    loop {
+        tracing::info!(
+            request = name,
+            attempt,
+            max_attempts,
+            "Sending request"
+        );
+
+        let error = match request().await {
+            Ok(response) => return Ok(response),
+            Err(error) => error,
+        };
+
+        if attempt >= max_attempts || !is_retryable(&error) {
+            tracing::error!(request = name, attempt, ?error, "Request failed");
+            return Err(error);
+        }
+
+        let delay = config.delay(attempt);
+        tracing::warn!(
+            request = name,
+            attempt,
+            ?error,
+            ?delay,
+            "Request failed, retrying"
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// A minimal HTTP server for testing requests to the Azure platform
+/// endpoints.
+#[cfg(all(test, feature = "imds"))]
+pub(crate) mod test_server {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve each of the given raw HTTP responses, in order, to successive
+    /// connections on a local port and return the base URL to reach it.
+    pub(crate) async fn serve_responses<T>(responses: Vec<T>) -> String
+    where
+        T: AsRef<[u8]> + Send + 'static,
+    {
+        serve_responses_at("127.0.0.1:0", responses).await
+    }
+
+    /// Like [`serve_responses`], but listen on `addr`, e.g. `[::1]:0`.
+    pub(crate) async fn serve_responses_at<T>(
+        addr: &str,
+        responses: Vec<T>,
+    ) -> String
+    where
+        T: AsRef<[u8]> + Send + 'static,
+    {
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await.unwrap();
+                stream.write_all(response.as_ref()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+
+        format!("http://{addr}")
+    }
+
+    pub(crate) fn http_response(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+#[cfg(all(test, feature = "imds"))]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::test_server::{
+        http_response, serve_responses, serve_responses_at,
+    };
+    use super::{
+        build_client, no_proxy_hosts, proxies, retry, HttpConfig, RetryConfig,
+    };
+    use crate::error::Error;
+
+    fn status_error(status: StatusCode) -> Error {
+        Error::HttpStatus {
+            endpoint: "http://127.0.0.1/".to_string(),
+            status,
+        }
+    }
+
+    fn fast_retry(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let mut attempts = 0;
+
+        let result = retry(
+            &fast_retry(3),
+            "test",
+            |_| true,
+            || {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    match attempt {
+                        3 => Ok(attempt),
+                        _ => Err(status_error(StatusCode::SERVICE_UNAVAILABLE)),
+                    }
+                }
+            },
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_stops_on_permanent_errors() {
+        let mut attempts = 0;
+
+        let result: Result<(), Error> = retry(
+            &fast_retry(5),
+            "test",
+            |_| false,
+            || {
+                attempts += 1;
+                async { Err(status_error(StatusCode::BAD_REQUEST)) }
+            },
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_retry_delay_is_capped() {
+        let retry = RetryConfig {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        };
+
+        assert_eq!(retry.delay(1), Duration::from_millis(100));
+        assert_eq!(retry.delay(2), Duration::from_millis(200));
+        assert_eq!(retry.delay(3), Duration::from_millis(300));
+        assert_eq!(retry.delay(30), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_retry_jittered_delay_is_bounded() {
+        let retry = RetryConfig {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        };
+
+        for _ in 0..100 {
+            let delay = retry.jittered_delay(2);
+            assert!(delay >= Duration::from_millis(200));
+            assert!(delay <= Duration::from_millis(300));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_client_uses_proxy() {
+        let proxy =
+            serve_responses(vec![http_response("200 OK", "proxied")]).await;
+        let config = HttpConfig {
+            proxy: Some(proxy),
+            ..Default::default()
+        };
+
+        let client = build_client(&config).unwrap();
+        // The host doesn't resolve, so only the proxy can answer.
+        let response =
+            client.get("http://metadata.invalid/").send().await.unwrap();
+
+        assert_eq!(response.text().await.unwrap(), "proxied");
+    }
+
+    // A client using the proxies found in `env`.
+    fn client_with_env(
+        config: &HttpConfig,
+        env: &[(&str, &str)],
+    ) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder().no_proxy();
+        let lookup = |name: &str| {
+            env.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        };
+        for proxy in proxies(config, lookup).unwrap() {
+            builder = builder.proxy(proxy);
+        }
+        builder.build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_proxy_from_env() {
+        let proxy =
+            serve_responses(vec![http_response("200 OK", "proxied")]).await;
+        let client =
+            client_with_env(&HttpConfig::default(), &[("http_proxy", &proxy)]);
+
+        let response =
+            client.get("http://metadata.invalid/").send().await.unwrap();
+
+        assert_eq!(response.text().await.unwrap(), "proxied");
+    }
+
+    #[tokio::test]
+    async fn test_no_proxy_skips_proxy() {
+        let proxy =
+            serve_responses(vec![http_response("200 OK", "proxied")]).await;
+        let config = HttpConfig {
+            no_proxy: vec!["other.invalid".to_string()],
+            ..Default::default()
+        };
+        let client = client_with_env(
+            &config,
+            &[("HTTP_PROXY", &proxy), ("NO_PROXY", "metadata.invalid")],
+        );
+
+        // Direct requests fail, as neither host resolves.
+        assert!(client.get("http://metadata.invalid/").send().await.is_err());
+        assert!(client.get("http://other.invalid/").send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_proxy_ipv6_endpoint() {
+        let proxy =
+            serve_responses(vec![http_response("200 OK", "proxied")]).await;
+        let endpoint = serve_responses_at(
+            "[::1]:0",
+            vec![http_response("200 OK", "direct")],
+        )
+        .await;
+        let config = HttpConfig {
+            proxy: Some(proxy),
+            no_proxy: vec!["::1".to_string()],
+            ..Default::default()
+        };
+
+        let response = build_client(&config)
+            .unwrap()
+            .get(&endpoint)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.text().await.unwrap(), "direct");
+    }
+
+    #[test]
+    fn test_platform_endpoints_skip_proxy() {
+        let mut config = HttpConfig {
+            no_proxy: vec!["other.example".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            no_proxy_hosts(&config, Some("env.example".to_string())),
+            [
+                "env.example",
+                "other.example",
+                "169.254.0.0/16",
+                "fe80::/10",
+                "168.63.129.16"
+            ]
+        );
+
+        config.proxy_platform_endpoints = true;
+        assert_eq!(no_proxy_hosts(&config, None), ["other.example"]);
+    }
+
+    // Platform endpoints are listed as CIDR ranges, such as 169.254.0.0/16;
+    // a local range stands in for them, as requests to the real ones
+    // would reach IMDS on Azure.
+    #[tokio::test]
+    async fn test_no_proxy_cidr_range() {
+        let proxy =
+            serve_responses(vec![http_response("200 OK", "proxied")]).await;
+        let endpoint =
+            serve_responses(vec![http_response("200 OK", "direct")]).await;
+        let config = HttpConfig {
+            no_proxy: vec!["127.0.0.0/8".to_string()],
+            ..Default::default()
+        };
+        let client = client_with_env(&config, &[("HTTP_PROXY", &proxy)]);
+
+        let response = client.get(&endpoint).send().await.unwrap();
+
+        assert_eq!(response.text().await.unwrap(), "direct");
+    }
+
+    #[tokio::test]
+    async fn test_build_client_user_agent() {
+        // Answer with the request itself, to see the headers sent.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..len]).to_string();
+            let response = http_response("200 OK", &request);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        let config = HttpConfig {
+            user_agent: "acme-provisioner/1.2 (azure-init 0.1)".to_string(),
+            ..Default::default()
+        };
+
+        let client = build_client(&config).unwrap();
+        let request = client
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+
+        assert!(request
+            .contains("user-agent: acme-provisioner/1.2 (azure-init 0.1)\r\n"));
+    }
+
+    #[test]
+    fn test_build_client_invalid_user_agent() {
+        let config = HttpConfig {
+            user_agent: "acme\nprovisioner".to_string(),
+            ..Default::default()
+        };
+
+        match build_client(&config) {
+            Err(Error::InvalidUserAgent { user_agent }) => {
+                assert_eq!(user_agent, "acme\nprovisioner")
+            }
+            _ => panic!("A user agent with a newline should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_build_client_missing_ca_cert() {
+        let config = HttpConfig {
+            ca_certs: vec!["/nonexistent/ca.pem".into()],
+            ..Default::default()
+        };
+
+        match build_client(&config) {
+            Err(Error::Io(_)) => {}
+            _ => panic!("A missing CA certificate file should be an error"),
+        }
+    }
+}
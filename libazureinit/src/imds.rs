@@ -1,32 +1,328 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::Ipv6Addr;
+use std::path::Path;
+use std::time::Duration;
+
 use reqwest;
 use reqwest::header::HeaderMap;
 use reqwest::header::HeaderValue;
 use reqwest::Client;
+use reqwest::StatusCode;
+use reqwest::Url;
 
+use base64::Engine;
 use serde::Deserialize;
 use serde_json;
 use serde_json::Value;
 
+use crate::command::Runner;
 use crate::error::Error;
+pub use crate::http::RetryConfig;
+use crate::http::{self, HttpConfig};
+use crate::provision::ProvisioningData;
+pub use crate::user::PublicKeys;
 
-#[derive(Debug, Deserialize, PartialEq, Clone)]
-pub struct PublicKeys {
-    #[serde(rename = "keyData")]
-    pub key_data: String,
-    #[serde(rename = "path")]
-    pub path: String,
+/// Base address of the Azure Instance Metadata Service.
+pub const DEFAULT_IMDS_ENDPOINT: &str = "http://169.254.169.254";
+/// IMDS API version requested unless configured otherwise.
+pub const DEFAULT_IMDS_API_VERSION: &str = "2021-02-01";
+/// How long a single IMDS request may take. IMDS answers locally, so this
+/// is short to fail fast and leave time for retries.
+pub const DEFAULT_IMDS_TIMEOUT: Duration = Duration::from_secs(5);
+/// The largest decoded custom data accepted by [`get_custom_data`]. Azure
+/// itself caps custom data well below this.
+pub const DEFAULT_MAX_CUSTOM_DATA_SIZE: u64 = 4 * 1024 * 1024;
+/// Where a copy of the IMDS response is kept when caching is enabled.
+pub const DEFAULT_IMDS_CACHE_PATH: &str = "/var/lib/azure-init/imds.json";
+
+/// Settings controlling how the Instance Metadata Service is queried.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImdsConfig {
+    /// Base URL of the metadata service, without the `/metadata` path. An
+    /// address alone is taken to mean `http://`, and an IPv6 address may be
+    /// given with or without brackets, e.g. `fd00::254` or `[fd00::254]:80`,
+    /// and with the scope of a link-local address, e.g. `fe80::254%eth0`;
+    /// see [`parse_endpoint`].
+    pub endpoint: String,
+    /// Endpoints tried in order when `endpoint` can't be reached, e.g. the
+    /// IPv6 address of the metadata service on VMs where the IPv4
+    /// link-local network may be unreachable. Putting the IPv6 address in
+    /// `endpoint` instead prefers it over IPv4.
+    pub fallback_endpoints: Vec<String>,
+    /// Value of the `api-version` query parameter, in `YYYY-MM-DD` form.
+    pub api_version: String,
+    /// How failed requests to the metadata service are retried.
+    pub retry: RetryConfig,
+    /// How long each request may take before it fails and is retried. This
+    /// overrides any timeout set on the client.
+    #[serde(skip)]
+    pub timeout: Duration,
+    /// If set, keep querying IMDS, each time with the retries of `retry`,
+    /// until it responds or this much time has passed. This is for VMs
+    /// whose metadata service can take longer to start than the retries
+    /// allow.
+    #[serde(skip)]
+    pub wait: Option<Duration>,
+}
+
+impl Default for ImdsConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: DEFAULT_IMDS_ENDPOINT.to_owned(),
+            fallback_endpoints: Vec::new(),
+            api_version: DEFAULT_IMDS_API_VERSION.to_owned(),
+            retry: RetryConfig::default(),
+            timeout: DEFAULT_IMDS_TIMEOUT,
+            wait: None,
+        }
+    }
+}
+
+impl ImdsConfig {
+    // The instance metadata URL of each endpoint and the client to request
+    // it with, in the order they are tried. Endpoints with an IPv6 scope
+    // get a client bound to that interface.
+    fn instance_urls(
+        &self,
+        client: &Client,
+        http_config: &HttpConfig,
+    ) -> Result<Vec<(String, Client)>, Error> {
+        if !is_valid_api_version(&self.api_version) {
+            return Err(Error::InvalidApiVersion {
+                version: self.api_version.clone(),
+            });
+        }
+
+        std::iter::once(&self.endpoint)
+            .chain(&self.fallback_endpoints)
+            .map(|endpoint| {
+                let endpoint = parse_endpoint(endpoint)?;
+                let url = format!(
+                    "{}/metadata/instance?api-version={}",
+                    endpoint.url, self.api_version
+                );
+                let client = match &endpoint.interface {
+                    Some(interface) => {
+                        http::build_interface_client(http_config, interface)?
+                    }
+                    None => client.clone(),
+                };
+
+                Ok((url, client))
+            })
+            .collect()
+    }
+}
+
+/// An IMDS endpoint, as parsed by [`parse_endpoint`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImdsEndpoint {
+    /// The base URL, without a trailing slash or an IPv6 scope.
+    pub url: String,
+    /// The interface named by the scope of a link-local IPv6 address,
+    /// which requests must go out through.
+    pub interface: Option<String>,
+}
+
+/// Parse an IMDS endpoint into its base URL and, for a scoped IPv6
+/// address, the interface to reach it through.
+///
+/// Endpoints without a scheme use `http://`, and a bare IPv6 address is
+/// put in brackets, so `fd00::254` becomes `http://[fd00::254]`. The scope
+/// of a link-local address names an interface, as in `fe80::254%eth0`,
+/// `[fe80::254%eth0]:80` or, as RFC 6874 writes it in URLs,
+/// `http://[fe80::254%25eth0]`. The HTTP client can't take it in the URL,
+/// so it is taken out and requests are bound to the interface instead;
+/// such requests carry the default `User-Agent` and are never proxied.
+pub fn parse_endpoint(endpoint: &str) -> Result<ImdsEndpoint, Error> {
+    let invalid = |reason: &str| Error::InvalidImdsEndpoint {
+        endpoint: endpoint.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let trimmed = endpoint.trim_end_matches('/');
+    let (scheme, mut rest) = match trimmed.split_once("://") {
+        Some((scheme, rest)) => (scheme, rest.to_string()),
+        None => {
+            let address = trimmed.split('%').next().unwrap_or_default();
+            match address.parse::<Ipv6Addr>() {
+                Ok(_) => ("http", format!("[{trimmed}]")),
+                Err(_) => ("http", trimmed.to_string()),
+            }
+        }
+    };
+
+    let mut interface = None;
+    if let (Some(open), Some(close)) = (rest.find('['), rest.find(']')) {
+        if let Some(percent) = rest[open..close].find('%') {
+            let percent = open + percent;
+            let zone = &rest[percent + 1..close];
+            // Only URLs percent-encode the separator.
+            let zone = match trimmed.contains("://") {
+                true => zone.strip_prefix("25").unwrap_or(zone),
+                false => zone,
+            };
+            if zone.is_empty() || zone.contains(['/', '%']) {
+                return Err(invalid("invalid IPv6 scope"));
+            }
+            interface = Some(zone.to_string());
+            rest.replace_range(percent..close, "");
+        }
+    }
+
+    let url = format!("{scheme}://{rest}");
+    let parsed =
+        Url::parse(&url).map_err(|error| invalid(&error.to_string()))?;
+    if parsed.host().is_none() {
+        return Err(invalid("no host given"));
+    }
+
+    Ok(ImdsEndpoint { url, interface })
+}
+
+// API versions are dates of the form YYYY-MM-DD.
+fn is_valid_api_version(version: &str) -> bool {
+    let parts: Vec<&str> = version.split('-').collect();
+
+    match parts.as_slice() {
+        [year, month, day] => {
+            year.len() == 4
+                && month.len() == 2
+                && day.len() == 2
+                && parts
+                    .iter()
+                    .all(|part| part.chars().all(|c| c.is_ascii_digit()))
+        }
+        _ => false,
+    }
 }
 
+/// Query the instance metadata from the default IMDS endpoint.
 pub async fn query_imds(client: &Client) -> Result<String, Error> {
-    let url = "http://169.254.169.254/metadata/instance?api-version=2021-02-01";
+    query_imds_with_config(
+        client,
+        &ImdsConfig::default(),
+        &HttpConfig::default(),
+    )
+    .await
+}
+
+/// Query the instance metadata using the given [`ImdsConfig`].
+///
+/// Requests that fail with a connection error or a status IMDS is known to
+/// return while it is still starting up are retried according to
+/// `config.retry`. If every attempt fails, the last error is returned.
+///
+/// When `config.wait` is set, the retries are repeated until IMDS responds
+/// or the wait is over, in which case [`Error::ImdsWaitTimeout`] is
+/// returned.
+///
+/// `client` should be built from `http_config` with
+/// [`http::build_client`]; endpoints with an IPv6 scope are requested
+/// with a client built from `http_config` for their interface instead.
+pub async fn query_imds_with_config(
+    client: &Client,
+    config: &ImdsConfig,
+    http_config: &HttpConfig,
+) -> Result<String, Error> {
+    let urls = config.instance_urls(client, http_config)?;
+
+    match config.wait {
+        Some(wait) => wait_for_imds(config, &urls, wait).await,
+        None => query_with_retries(config, &urls).await,
+    }
+}
+
+async fn query_with_retries(
+    config: &ImdsConfig,
+    urls: &[(String, Client)],
+) -> Result<String, Error> {
+    http::retry(&config.retry, "imds", is_retryable, || {
+        request_any(urls, config.timeout)
+    })
+    .await
+}
+
+// Request each of `urls` in turn, with its client, until one answers. An
+// endpoint that fails in a way retrying could fix, such as being
+// unreachable, moves on to the next one; any other error is returned
+// right away.
+async fn request_any(
+    urls: &[(String, Client)],
+    timeout: Duration,
+) -> Result<String, Error> {
+    let mut last_error = None;
+    for (url, client) in urls {
+        match request_imds(client, url, timeout).await {
+            Ok(imds_body) => return Ok(imds_body),
+            Err(error) if is_retryable(&error) => {
+                tracing::debug!(url, ?error, "IMDS endpoint failed");
+                last_error = Some(error);
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    Err(last_error.expect("there is always at least one IMDS endpoint"))
+}
+
+async fn wait_for_imds(
+    config: &ImdsConfig,
+    urls: &[(String, Client)],
+    wait: Duration,
+) -> Result<String, Error> {
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut polls = 1;
+
+    loop {
+        let query = query_with_retries(config, urls);
+        let error = match tokio::time::timeout_at(deadline, query).await {
+            Ok(Ok(imds_body)) => return Ok(imds_body),
+            Ok(Err(error)) if is_retryable(&error) => error,
+            Ok(Err(error)) => return Err(error),
+            Err(_) => return Err(Error::ImdsWaitTimeout { wait }),
+        };
+
+        tracing::warn!(?error, polls, ?wait, "IMDS is not available, waiting");
+        let next_poll = tokio::time::Instant::now() + config.retry.max_delay;
+        tokio::time::sleep_until(next_poll.min(deadline)).await;
+        if tokio::time::Instant::now() >= deadline {
+            return Err(Error::ImdsWaitTimeout { wait });
+        }
+        polls += 1;
+    }
+}
+
+fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::Http(error) => error.is_connect() || error.is_timeout(),
+        Error::HttpStatus { status, .. } => {
+            matches!(
+                *status,
+                StatusCode::NOT_FOUND
+                    | StatusCode::GONE
+                    | StatusCode::TOO_MANY_REQUESTS
+            ) || status.is_server_error()
+        }
+        _ => false,
+    }
+}
+
+async fn request_imds(
+    client: &Client,
+    url: &str,
+    timeout: Duration,
+) -> Result<String, Error> {
     let mut headers = HeaderMap::new();
 
     headers.insert("Metadata", HeaderValue::from_static("true"));
 
-    let request = client.get(url).headers(headers);
+    let request = client.get(url).headers(headers).timeout(timeout);
     let response = request.send().await?;
 
     if response.status().is_success() {
@@ -41,6 +337,34 @@ pub async fn query_imds(client: &Client) -> Result<String, Error> {
     }
 }
 
+/// Save the raw IMDS response `imds_body` to `path`, so a provisioning run
+/// can later be reproduced with [`read_imds_cache`].
+///
+/// The file is only readable by its owner, since the response describes the
+/// VM's users and keys, and is renamed into place once fully written.
+pub fn write_imds_cache(
+    path: &Path,
+    imds_body: &str,
+    runner: &Runner,
+) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    runner.write_file(path, imds_body.as_bytes(), 0o600, None)
+}
+
+/// Read an IMDS response saved by [`write_imds_cache`], for use in place of
+/// querying IMDS.
+///
+/// The cached body must still be valid JSON.
+pub fn read_imds_cache(path: &Path) -> Result<String, Error> {
+    let imds_body = fs::read_to_string(path)?;
+    serde_json::from_str::<Value>(&imds_body)?;
+
+    Ok(imds_body)
+}
+
 pub fn get_ssh_keys(imds_body: String) -> Result<Vec<PublicKeys>, Error> {
     let data: Value = serde_json::from_str(&imds_body)?;
     let public_keys =
@@ -57,36 +381,791 @@ pub fn get_username(imds_body: String) -> Result<String, Error> {
     Ok(username)
 }
 
-pub fn get_hostname(imds_body: String) -> Result<String, Error> {
+/// Get the hostname (`compute.osProfile.computerName`), as cleaned up by
+/// [`parse_hostname`].
+pub fn get_hostname(imds_body: String, short: bool) -> Result<String, Error> {
     let data: Value = serde_json::from_str(&imds_body)?;
     let hostname =
         String::deserialize(&data["compute"]["osProfile"]["computerName"])?;
 
-    Ok(hostname)
+    parse_hostname(&hostname, short)
 }
 
-pub fn is_password_authentication_disabled(
+/// Clean up a hostname given by the platform: trailing dots, as in the
+/// absolute name `vm.example.com.`, are removed, and with `short` only the
+/// part before the first dot is kept.
+///
+/// Returns [`Error::HostnameMissing`] if nothing is left.
+pub fn parse_hostname(name: &str, short: bool) -> Result<String, Error> {
+    let mut hostname = name.trim_end_matches('.');
+    if short {
+        hostname = hostname.split('.').next().unwrap_or_default();
+    }
+    if hostname.is_empty() {
+        return Err(Error::HostnameMissing);
+    }
+
+    Ok(hostname.to_string())
+}
+
+/// Read everything provisioning needs from the IMDS response, parsing it
+/// only once.
+///
+/// Tags and the password policy are read as with [`get_tags`] and
+/// [`is_password_authentication_disabled`], except that tags which can't
+/// be read are only logged, since they don't affect provisioning.
+pub fn get_provisioning_data(
     imds_body: &str,
-) -> Result<bool, Error> {
+) -> Result<ProvisioningData, Error> {
     let data: Value = serde_json::from_str(imds_body)?;
+    let compute = &data["compute"];
+    let os_profile = &compute["osProfile"];
 
-    let provision_with_password = String::deserialize(
-        &data["compute"]["osProfile"]["disablePasswordAuthentication"],
-    )?;
+    let tags = match tags(compute) {
+        Ok(tags) => tags.into_iter().collect(),
+        Err(error) => {
+            tracing::warn!(?error, "Unable to read the VM's tags");
+            BTreeMap::new()
+        }
+    };
+
+    Ok(ProvisioningData {
+        username: String::deserialize(&os_profile["adminUsername"])?,
+        hostname: parse_hostname(
+            &String::deserialize(&os_profile["computerName"])?,
+            false,
+        )?,
+        ssh_keys: Vec::<PublicKeys>::deserialize(&compute["publicKeys"])?,
+        password_authentication_disabled: password_authentication_disabled(
+            &os_profile["disablePasswordAuthentication"],
+        ),
+        ssh_disabled: false,
+        tags,
+    })
+}
+
+/// Get the unique ID Azure assigned to the VM (`compute.vmId`).
+pub fn get_vm_id(imds_body: &str) -> Result<String, Error> {
+    get_compute_field(imds_body, "vmId")
+}
+
+/// Get the Azure Resource Manager ID of the VM (`compute.resourceId`).
+pub fn get_resource_id(imds_body: &str) -> Result<String, Error> {
+    get_compute_field(imds_body, "resourceId")
+}
+
+// Get a string field of the compute metadata, treating an empty value the
+// same as a missing one.
+fn get_compute_field(imds_body: &str, field: &str) -> Result<String, Error> {
+    let data: Value = serde_json::from_str(imds_body)?;
+
+    match &data["compute"][field] {
+        Value::String(value) if !value.is_empty() => Ok(value.clone()),
+        _ => Err(Error::ImdsFieldMissing {
+            field: format!("compute.{field}"),
+        }),
+    }
+}
+
+/// Get the user-supplied custom data of the VM.
+///
+/// IMDS provides `compute.customData` base64-encoded; the decoded bytes are
+/// returned as-is since the payload is not required to be text. An absent or
+/// empty field yields an empty vector. Custom data larger than
+/// [`DEFAULT_MAX_CUSTOM_DATA_SIZE`] is rejected with
+/// [`Error::PayloadTooLarge`]; see [`get_custom_data_with_limit`].
+pub fn get_custom_data(imds_body: String) -> Result<Vec<u8>, Error> {
+    get_custom_data_with_limit(imds_body, DEFAULT_MAX_CUSTOM_DATA_SIZE)
+}
+
+/// Get the user-supplied custom data of the VM, failing with
+/// [`Error::PayloadTooLarge`] if it would decode to more than `max_size`
+/// bytes.
+///
+/// The size is checked before decoding, so oversized data is never
+/// decoded.
+pub fn get_custom_data_with_limit(
+    imds_body: String,
+    max_size: u64,
+) -> Result<Vec<u8>, Error> {
+    get_base64_field(
+        &imds_body,
+        "customData",
+        "custom data",
+        max_size,
+        Error::CustomDataDecode,
+    )
+}
+
+/// Get the SSH public keys listed in `custom_data`, as returned by
+/// [`get_custom_data`].
+///
+/// Keys are read from custom data that is a JSON object with an
+/// `ssh_authorized_keys` array of lines in `authorized_keys` format:
+///
+/// ```json
+/// {"ssh_authorized_keys": ["ssh-ed25519 AAAA... admin@example"]}
+/// ```
+///
+/// Other custom data, such as a script or cloud-init configuration, and
+/// objects without the array yield no keys. An `ssh_authorized_keys` field
+/// that isn't an array of strings is an error. The keys are not validated.
+pub fn get_custom_data_ssh_keys(
+    custom_data: &[u8],
+) -> Result<Vec<PublicKeys>, Error> {
+    let keys = match serde_json::from_slice::<Value>(custom_data) {
+        Ok(Value::Object(mut object)) => object.remove("ssh_authorized_keys"),
+        _ => None,
+    };
+    let Some(keys) = keys else {
+        return Ok(Vec::new());
+    };
+
+    Ok(Vec::<String>::deserialize(keys)?
+        .iter()
+        .map(|line| PublicKeys::from_authorized_keys_line(line))
+        .collect())
+}
+
+/// Get the user data of the VM.
+///
+/// User data (`compute.userData`) is separate from custom data: custom data
+/// is fixed when the VM is created and is meant for bootstrapping it, while
+/// user data can be updated while the VM exists, e.g. for configuration
+/// read at runtime. IMDS only reports it with API version 2021-01-01 or
+/// later.
+///
+/// It is decoded like custom data; see [`get_custom_data`]. An absent or
+/// empty field yields an empty vector, and user data larger than
+/// [`DEFAULT_MAX_CUSTOM_DATA_SIZE`] is rejected.
+pub fn get_user_data(imds_body: String) -> Result<Vec<u8>, Error> {
+    get_base64_field(
+        &imds_body,
+        "userData",
+        "user data",
+        DEFAULT_MAX_CUSTOM_DATA_SIZE,
+        Error::UserDataDecode,
+    )
+}
+
+// Decode the base64 `compute.<field>`, checking the size of the decoded
+// `payload` against `max_size` before decoding. Base64 errors are reported
+// with `decode_error`.
+fn get_base64_field(
+    imds_body: &str,
+    field: &str,
+    payload: &str,
+    max_size: u64,
+    decode_error: fn(base64::DecodeError) -> Error,
+) -> Result<Vec<u8>, Error> {
+    let data: Value = serde_json::from_str(imds_body)?;
+
+    let encoded = match &data["compute"][field] {
+        Value::Null => return Ok(Vec::new()),
+        encoded => String::deserialize(encoded)?,
+    };
 
-    if provision_with_password == "true" {
-        return Ok(true);
+    let encoded = encoded.trim();
+    let padding = encoded.bytes().rev().take_while(|&b| b == b'=').count();
+    let decoded_size = (encoded.len() / 4 * 3).saturating_sub(padding);
+    if decoded_size as u64 > max_size {
+        return Err(Error::PayloadTooLarge {
+            payload: payload.to_string(),
+            max_size,
+        });
     }
 
-    Ok(false)
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(decode_error)
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+struct Tag {
+    name: String,
+    value: String,
+}
+
+/// Get the tags assigned to the VM as a list of `(name, value)` pairs.
+///
+/// Tags are read from the structured `compute.tagsList` array when present,
+/// then from the legacy `compute.tags` string of `name:value` pairs separated
+/// by semicolons. A tag name is only reported once; entries from
+/// `tagsList` take precedence since the legacy format cannot represent
+/// values containing a semicolon.
+pub fn get_tags(imds_body: String) -> Result<Vec<(String, String)>, Error> {
+    let data: Value = serde_json::from_str(&imds_body)?;
+
+    tags(&data["compute"])
+}
+
+// The tags in the `compute` object of an IMDS response.
+fn tags(compute: &Value) -> Result<Vec<(String, String)>, Error> {
+    let mut tags: Vec<(String, String)> = Vec::new();
+
+    let tags_list = match &compute["tagsList"] {
+        Value::Null => Vec::new(),
+        list => Vec::<Tag>::deserialize(list)?,
+    };
+    let legacy_tags = match &compute["tags"] {
+        Value::Null => String::new(),
+        legacy => String::deserialize(legacy)?,
+    };
+
+    let legacy_tags = legacy_tags
+        .split(';')
+        .filter(|tag| !tag.trim().is_empty())
+        .map(|tag| match tag.split_once(':') {
+            Some((name, value)) => Tag {
+                name: name.trim().to_owned(),
+                value: value.trim().to_owned(),
+            },
+            None => Tag {
+                name: tag.trim().to_owned(),
+                value: String::new(),
+            },
+        });
+
+    for tag in tags_list.into_iter().chain(legacy_tags) {
+        if !tags.iter().any(|(name, _)| *name == tag.name) {
+            tags.push((tag.name, tag.value));
+        }
+    }
+
+    Ok(tags)
+}
+
+/// A network interface of the VM, from `network.interface`.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NetworkInterface {
+    /// The MAC address as IMDS reports it: 12 hex digits, no separators.
+    pub mac_address: String,
+    pub ipv4: IpConfiguration,
+    pub ipv6: IpConfiguration,
+}
+
+/// The addresses and subnets of one IP version on a [`NetworkInterface`].
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IpConfiguration {
+    pub ip_address: Vec<IpAddress>,
+    pub subnet: Vec<Subnet>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct IpAddress {
+    pub private_ip_address: String,
+    /// The public IP address mapped to the private one. Empty if there is
+    /// none.
+    pub public_ip_address: String,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Clone)]
+#[serde(default)]
+pub struct Subnet {
+    pub address: String,
+    /// The prefix length of the subnet, e.g. `24`.
+    pub prefix: String,
+}
+
+/// Get the VM's network interfaces, the primary one first.
+///
+/// A missing `network` section yields an empty list.
+pub fn get_network_interfaces(
+    imds_body: &str,
+) -> Result<Vec<NetworkInterface>, Error> {
+    let data: Value = serde_json::from_str(imds_body)?;
+
+    match &data["network"]["interface"] {
+        Value::Null => Ok(Vec::new()),
+        interfaces => Ok(Vec::<NetworkInterface>::deserialize(interfaces)?),
+    }
+}
+
+/// Whether IMDS reports that password authentication is disabled.
+///
+/// `disablePasswordAuthentication` is accepted as either a string or a
+/// boolean. If the body can't be parsed or the field is missing or has any
+/// other value, a warning is logged and password authentication is treated
+/// as disabled, since that is the secure choice.
+pub fn is_password_authentication_disabled(imds_body: &str) -> bool {
+    let data: Value = match serde_json::from_str(imds_body) {
+        Ok(data) => data,
+        Err(error) => {
+            tracing::warn!(
+                ?error,
+                "Unable to parse the IMDS response, assuming password authentication is disabled"
+            );
+            return true;
+        }
+    };
+
+    password_authentication_disabled(
+        &data["compute"]["osProfile"]["disablePasswordAuthentication"],
+    )
+}
+
+// Interpret `compute.osProfile.disablePasswordAuthentication`.
+fn password_authentication_disabled(value: &Value) -> bool {
+    match value {
+        Value::Bool(disabled) => *disabled,
+        Value::String(disabled) if disabled == "true" => true,
+        Value::String(disabled) if disabled == "false" => false,
+        value => {
+            tracing::warn!(
+                %value,
+                "Unexpected disablePasswordAuthentication, assuming password authentication is disabled"
+            );
+            true
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
-        get_hostname, get_ssh_keys, get_username,
-        is_password_authentication_disabled,
+        get_custom_data, get_custom_data_ssh_keys, get_custom_data_with_limit,
+        get_hostname, get_network_interfaces, get_provisioning_data,
+        get_resource_id, get_ssh_keys, get_tags, get_user_data, get_username,
+        get_vm_id, is_password_authentication_disabled, is_valid_api_version,
+        parse_endpoint, parse_hostname, query_imds_with_config,
+        read_imds_cache, write_imds_cache, ImdsConfig, ImdsEndpoint,
+        RetryConfig,
     };
+    use crate::command::Runner;
+    use crate::error::Error;
+    use crate::http::test_server::{http_response, serve_responses};
+    use crate::http::HttpConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use reqwest::{Client, StatusCode};
+
+    #[test]
+    fn test_get_provisioning_data() {
+        let body = r#"{
+            "compute": {
+                "osProfile": {
+                    "adminUsername": "azureuser",
+                    "computerName": "test-vm",
+                    "disablePasswordAuthentication": "false"
+                },
+                "publicKeys": [{
+                    "keyData": "ssh-ed25519 AAAA test",
+                    "path": "/home/azureuser/.ssh/authorized_keys"
+                }],
+                "tags": "env:test"
+            }
+        }"#;
+
+        let data = get_provisioning_data(body).unwrap();
+
+        assert_eq!(data.username, "azureuser");
+        assert_eq!(data.hostname, "test-vm");
+        assert_eq!(data.ssh_keys.len(), 1);
+        assert_eq!(data.ssh_keys[0].key_data, "ssh-ed25519 AAAA test");
+        assert!(!data.password_authentication_disabled);
+        assert_eq!(data.tags["env"], "test");
+
+        // Tags are optional, but the admin user isn't.
+        let body = r#"{"compute": {"osProfile": {"computerName": "vm"},
+            "publicKeys": [], "tags": 42}}"#;
+        assert!(matches!(get_provisioning_data(body), Err(Error::Json(_))));
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_custom_endpoint() {
+        let body = r#"{"compute": {"name": "test"}}"#;
+        let endpoint =
+            serve_responses(vec![http_response("200 OK", body)]).await;
+        let config = ImdsConfig {
+            endpoint,
+            ..Default::default()
+        };
+
+        let imds_body = query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        .expect("Failed to query the mock IMDS endpoint.");
+
+        assert_eq!(imds_body, body);
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        let cases = [
+            ("http://169.254.169.254/", "http://169.254.169.254", None),
+            ("169.254.169.254", "http://169.254.169.254", None),
+            ("fd00::254", "http://[fd00::254]", None),
+            ("[fd00::254]", "http://[fd00::254]", None),
+            ("[fd00::254]:8080/", "http://[fd00::254]:8080", None),
+            ("http://[fd00::254]", "http://[fd00::254]", None),
+            ("fe80::254%eth0", "http://[fe80::254]", Some("eth0")),
+            ("[fe80::254%eth0]:80", "http://[fe80::254]:80", Some("eth0")),
+            (
+                "http://[fe80::254%25eth0]/",
+                "http://[fe80::254]",
+                Some("eth0"),
+            ),
+        ];
+        for (endpoint, url, interface) in cases {
+            assert_eq!(
+                parse_endpoint(endpoint).unwrap(),
+                ImdsEndpoint {
+                    url: url.to_string(),
+                    interface: interface.map(String::from),
+                },
+                "{endpoint}"
+            );
+        }
+
+        for endpoint in [
+            "fe80::254%",
+            "http://[fe80::254%25]",
+            "http://[fd00::254",
+            "",
+        ] {
+            match parse_endpoint(endpoint) {
+                Err(Error::InvalidImdsEndpoint { .. }) => {}
+                other => {
+                    panic!("{endpoint:?} should be invalid, got {other:?}")
+                }
+            }
+        }
+    }
+
+    // Serve `body` once on the IPv6 loopback address, returning the port.
+    // Answer one request on the IPv6 loopback address with `body`,
+    // returning the port and the task that yields the request received.
+    async fn serve_ipv6(
+        body: &'static str,
+    ) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("[::1]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+            let response = http_response("200 OK", body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        });
+
+        (port, request)
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_scoped_endpoint() {
+        let body = r#"{"compute": {"name": "test"}}"#;
+        let (port, _) = serve_ipv6(body).await;
+        // The loopback address stands in for a link-local one, whose
+        // scope names the interface to reach it through.
+        let config = ImdsConfig {
+            endpoint: format!("[::1%lo]:{port}"),
+            ..Default::default()
+        };
+
+        let imds_body = query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(imds_body, body);
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_scoped_endpoint_headers() {
+        let body = r#"{"compute": {"name": "test"}}"#;
+        let (port, request) = serve_ipv6(body).await;
+        let config = ImdsConfig {
+            endpoint: format!("[::1%lo]:{port}"),
+            ..Default::default()
+        };
+        let mut http_config = HttpConfig {
+            user_agent: "acme-provisioner/1.2".to_string(),
+            ..Default::default()
+        };
+        http_config.headers.insert(
+            "x-ms-client-request-id",
+            reqwest::header::HeaderValue::from_static("test-correlation-id"),
+        );
+
+        query_imds_with_config(&Client::new(), &config, &http_config)
+            .await
+            .unwrap();
+
+        let request = request.await.unwrap().to_lowercase();
+        assert!(request.contains("user-agent: acme-provisioner/1.2\r\n"));
+        assert!(
+            request.contains("x-ms-client-request-id: test-correlation-id\r\n")
+        );
+        assert!(request.contains("metadata: true\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_falls_back_to_ipv6() {
+        let body = r#"{"compute": {"name": "test"}}"#;
+        let (port, _) = serve_ipv6(body).await;
+        let config = ImdsConfig {
+            // Nothing listens on port 1, so connecting is refused.
+            endpoint: "http://127.0.0.1:1".to_string(),
+            fallback_endpoints: vec![format!("[::1]:{port}")],
+            retry: RetryConfig {
+                max_attempts: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let imds_body = query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(imds_body, body);
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_gzip_response() {
+        let body =
+            r#"{"compute": {"osProfile": {"adminUsername": "azureuser"}}}"#;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            compressed.len()
+        )
+        .into_bytes();
+        response.extend(compressed);
+        let config = ImdsConfig {
+            endpoint: serve_responses(vec![response]).await,
+            ..Default::default()
+        };
+
+        let imds_body = query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(imds_body, body);
+        assert_eq!(get_username(imds_body).unwrap(), "azureuser");
+    }
+
+    fn fast_retry(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_retries_until_success() {
+        let body = r#"{"compute": {"name": "test"}}"#;
+        let endpoint = serve_responses(vec![
+            http_response("404 Not Found", ""),
+            http_response("410 Gone", ""),
+            http_response("200 OK", body),
+        ])
+        .await;
+        let config = ImdsConfig {
+            endpoint,
+            retry: fast_retry(3),
+            ..Default::default()
+        };
+
+        let imds_body = query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        .expect("Failed to query IMDS after retrying.");
+
+        assert_eq!(imds_body, body);
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_returns_last_error() {
+        let endpoint = serve_responses(vec![
+            http_response("410 Gone", ""),
+            http_response("404 Not Found", ""),
+        ])
+        .await;
+        let config = ImdsConfig {
+            endpoint,
+            retry: fast_retry(2),
+            ..Default::default()
+        };
+
+        match query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        {
+            Err(Error::HttpStatus { status, .. }) => {
+                assert_eq!(status, StatusCode::NOT_FOUND)
+            }
+            _ => panic!("Expected the last HTTP status error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_imds_outlasts_retries() {
+        let body = r#"{"compute": {"name": "test"}}"#;
+        let endpoint = serve_responses(vec![
+            http_response("503 Service Unavailable", ""),
+            http_response("503 Service Unavailable", ""),
+            http_response("200 OK", body),
+        ])
+        .await;
+        let config = ImdsConfig {
+            endpoint,
+            retry: fast_retry(1),
+            wait: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        let imds_body = query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        .expect("Failed to query IMDS while waiting for it.");
+
+        assert_eq!(imds_body, body);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_imds_deadline() {
+        // Nothing listens once the server has no responses left, so every
+        // request is refused.
+        let config = ImdsConfig {
+            endpoint: serve_responses(Vec::<String>::new()).await,
+            retry: fast_retry(2),
+            wait: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        match query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        {
+            Err(Error::ImdsWaitTimeout { wait }) => {
+                assert_eq!(wait, Duration::from_millis(50))
+            }
+            _ => panic!("Waiting for IMDS should stop at the deadline"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_imds_stops_sleeping_at_deadline() {
+        let config = ImdsConfig {
+            endpoint: serve_responses(Vec::<String>::new()).await,
+            retry: RetryConfig {
+                max_attempts: 1,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_secs(30),
+            },
+            wait: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+
+        let start = std::time::Instant::now();
+        let result = query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::ImdsWaitTimeout { .. })));
+        // The pause between polls is cut short rather than overrunning the
+        // wait by up to max_delay.
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_api_version_validation() {
+        assert!(is_valid_api_version("2021-02-01"));
+        assert!(is_valid_api_version("2023-07-01"));
+        assert!(!is_valid_api_version("2021-2-01"));
+        assert!(!is_valid_api_version("2021-02-01-preview"));
+        assert!(!is_valid_api_version("latest"));
+        assert!(!is_valid_api_version("2021-02-0a"));
+        assert!(!is_valid_api_version(""));
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_invalid_api_version() {
+        let config = ImdsConfig {
+            api_version: "2021-02".to_owned(),
+            ..Default::default()
+        };
+
+        match query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        {
+            Err(Error::InvalidApiVersion { version }) => {
+                assert_eq!(version, "2021-02")
+            }
+            _ => panic!("Expected an invalid API version error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_error_status() {
+        let endpoint =
+            serve_responses(vec![http_response("403 Forbidden", "")]).await;
+        let config = ImdsConfig {
+            endpoint,
+            ..Default::default()
+        };
+
+        match query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        {
+            Err(Error::HttpStatus { status, .. }) => {
+                assert_eq!(status, StatusCode::FORBIDDEN)
+            }
+            _ => panic!("Expected an HTTP status error"),
+        }
+    }
 
     #[test]
     fn test_get_ssh_keys() {
@@ -114,6 +1193,31 @@ mod tests {
 
         assert_eq!(public_keys[0].key_data, "ssh-rsa test_key1".to_string());
         assert_eq!(public_keys[1].key_data, "ssh-rsa test_key2".to_string());
+        assert_eq!(
+            public_keys[0].path,
+            "/path/to/.ssh/authorized_keys".to_string()
+        );
+    }
+
+    #[test]
+    fn test_get_ssh_keys_without_path() {
+        let file_body = r#"
+        {
+            "compute": {
+              "publicKeys": [
+                {
+                  "keyData": "ssh-rsa test_key1"
+                }
+              ]
+            }
+        }"#
+        .to_string();
+
+        let public_keys = get_ssh_keys(file_body)
+            .expect("Failed to obtain ssh keys from the JSON file.");
+
+        assert_eq!(public_keys[0].key_data, "ssh-rsa test_key1".to_string());
+        assert!(public_keys[0].path.is_empty());
     }
 
     #[test]
@@ -167,11 +1271,348 @@ mod tests {
         .to_string();
 
         let hostname =
-            get_hostname(file_body).expect("Failed to get hostname.");
+            get_hostname(file_body, false).expect("Failed to get hostname.");
 
         assert_eq!(hostname, "AzTux-MinProvAgent-Test-0001".to_string());
     }
 
+    #[test]
+    fn test_get_hostname_fqdn() {
+        let body = |name: &str| {
+            serde_json::json!({"compute": {"osProfile": {"computerName": name}}})
+                .to_string()
+        };
+
+        assert_eq!(
+            get_hostname(body("vm.example.com."), false).unwrap(),
+            "vm.example.com"
+        );
+        assert_eq!(get_hostname(body("vm.example.com."), true).unwrap(), "vm");
+        assert_eq!(get_hostname(body("vm"), true).unwrap(), "vm");
+    }
+
+    #[test]
+    fn test_parse_hostname_missing() {
+        let cases = [("", false), ("..", false), (".example.com", true)];
+        for (name, short) in cases {
+            match parse_hostname(name, short) {
+                Err(Error::HostnameMissing) => {}
+                other => panic!("{name:?} should be missing, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_vm_and_resource_id() {
+        let file_body = r#"
+        {
+            "compute": {
+              "name": "AzTux-MinProvAgent-Test-0001",
+              "resourceId": "/subscriptions/xxx/resourceGroups/rg/providers/Microsoft.Compute/virtualMachines/AzTux-MinProvAgent-Test-0001",
+              "vmId": "02aab8a4-74ef-476e-8182-f6d2ba4166a6"
+            }
+        }"#;
+
+        assert_eq!(
+            get_vm_id(file_body).expect("Failed to get the VM ID."),
+            "02aab8a4-74ef-476e-8182-f6d2ba4166a6"
+        );
+        assert_eq!(
+            get_resource_id(file_body).expect("Failed to get the resource ID."),
+            "/subscriptions/xxx/resourceGroups/rg/providers/Microsoft.Compute/virtualMachines/AzTux-MinProvAgent-Test-0001"
+        );
+    }
+
+    #[test]
+    fn test_get_vm_and_resource_id_missing() {
+        let file_body = r#"{"compute": {"vmId": ""}}"#;
+
+        match get_vm_id(file_body) {
+            Err(Error::ImdsFieldMissing { field }) => {
+                assert_eq!(field, "compute.vmId")
+            }
+            _ => panic!("An empty vmId should be reported as missing"),
+        }
+        match get_resource_id(file_body) {
+            Err(Error::ImdsFieldMissing { field }) => {
+                assert_eq!(field, "compute.resourceId")
+            }
+            _ => panic!("A missing resourceId should be reported"),
+        }
+    }
+
+    #[test]
+    fn test_get_custom_data() {
+        let file_body = r#"
+        {
+            "compute": {
+              "customData": "IyEvYmluL3NoCmVjaG8gaGVsbG8K/w=="
+            }
+        }"#
+        .to_string();
+
+        let custom_data =
+            get_custom_data(file_body).expect("Failed to get custom data.");
+
+        assert_eq!(custom_data, b"#!/bin/sh\necho hello\n\xff".to_vec());
+    }
+
+    #[test]
+    fn test_get_custom_data_absent_or_empty() {
+        let absent = r#"{"compute": {"name": "test"}}"#.to_string();
+        let empty = r#"{"compute": {"customData": ""}}"#.to_string();
+
+        assert!(get_custom_data(absent).unwrap().is_empty());
+        assert!(get_custom_data(empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_custom_data_size_limit() {
+        // "hello" encodes to 8 characters with one padding character.
+        let file_body =
+            r#"{"compute": {"customData": "aGVsbG8="}}"#.to_string();
+
+        assert_eq!(
+            get_custom_data_with_limit(file_body.clone(), 5).unwrap(),
+            b"hello"
+        );
+        match get_custom_data_with_limit(file_body, 4) {
+            Err(Error::PayloadTooLarge { max_size, .. }) => {
+                assert_eq!(max_size, 4)
+            }
+            _ => panic!("Oversized custom data should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_get_custom_data_malformed() {
+        let file_body =
+            r#"{"compute": {"customData": "not base64!"}}"#.to_string();
+
+        match get_custom_data(file_body) {
+            Err(Error::CustomDataDecode(_)) => {}
+            _ => panic!("Malformed base64 should fail to decode"),
+        }
+    }
+
+    #[test]
+    fn test_get_custom_data_ssh_keys() {
+        let custom_data = br#"{
+            "ssh_authorized_keys": [
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG/fjRn7 admin@example",
+                "no-pty ssh-rsa AAAAB3NzaC1yc2EAAAADAQAB ops@example"
+            ]
+        }"#;
+
+        let keys = get_custom_data_ssh_keys(custom_data).unwrap();
+
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            keys[0].key_data,
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG/fjRn7 admin@example"
+        );
+        assert_eq!(keys[1].options.as_deref(), Some("no-pty"));
+        assert!(keys.iter().all(|key| key.path.is_empty()));
+    }
+
+    #[test]
+    fn test_get_custom_data_ssh_keys_ignores_other_data() {
+        for custom_data in [
+            &b""[..],
+            b"#!/bin/sh\necho hello\n",
+            b"#cloud-config\nssh_authorized_keys: []\n",
+            br#"{"runcmd": ["true"]}"#,
+            br#"["ssh-ed25519 AAAA"]"#,
+        ] {
+            assert!(get_custom_data_ssh_keys(custom_data).unwrap().is_empty());
+        }
+
+        assert!(matches!(
+            get_custom_data_ssh_keys(br#"{"ssh_authorized_keys": "ssh-rsa"}"#),
+            Err(Error::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_user_data() {
+        let file_body = r#"
+        {
+            "compute": {
+              "customData": "Ym9vdHN0cmFw",
+              "userData": "cnVudGltZQ=="
+            }
+        }"#;
+
+        assert_eq!(get_user_data(file_body.to_string()).unwrap(), b"runtime");
+        assert_eq!(
+            get_custom_data(file_body.to_string()).unwrap(),
+            b"bootstrap"
+        );
+
+        let absent = r#"{"compute": {"customData": "Ym9vdHN0cmFw"}}"#;
+        assert!(get_user_data(absent.to_string()).unwrap().is_empty());
+        let empty = r#"{"compute": {"userData": ""}}"#;
+        assert!(get_user_data(empty.to_string()).unwrap().is_empty());
+
+        let malformed = r#"{"compute": {"userData": "not base64!"}}"#;
+        match get_user_data(malformed.to_string()) {
+            Err(Error::UserDataDecode(_)) => {}
+            _ => panic!("Malformed base64 should fail to decode"),
+        }
+    }
+
+    #[test]
+    fn test_get_tags() {
+        let file_body = r#"
+        {
+            "compute": {
+              "name": "AzTux-MinProvAgent-Test-0001",
+              "tags": "environment:prod;team:linux;url:http://example.com",
+              "tagsList": [
+                {
+                  "name": "environment",
+                  "value": "prod"
+                },
+                {
+                  "name": "team",
+                  "value": "linux"
+                },
+                {
+                  "name": "url",
+                  "value": "http://example.com"
+                }
+              ]
+            }
+        }"#
+        .to_string();
+
+        let tags = get_tags(file_body).expect("Failed to get tags.");
+
+        assert_eq!(
+            tags,
+            vec![
+                ("environment".to_string(), "prod".to_string()),
+                ("team".to_string(), "linux".to_string()),
+                ("url".to_string(), "http://example.com".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_tags_legacy_only() {
+        let file_body = r#"
+        {
+            "compute": {
+              "name": "AzTux-MinProvAgent-Test-0001",
+              "tags": "environment:prod;;flag;environment:dev"
+            }
+        }"#
+        .to_string();
+
+        let tags = get_tags(file_body).expect("Failed to get tags.");
+
+        assert_eq!(
+            tags,
+            vec![
+                ("environment".to_string(), "prod".to_string()),
+                ("flag".to_string(), "".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_network_interfaces() {
+        let file_body = r#"
+        {
+            "compute": {"name": "test"},
+            "network": {
+                "interface": [
+                    {
+                        "ipv4": {
+                            "ipAddress": [
+                                {
+                                    "privateIpAddress": "10.0.0.4",
+                                    "publicIpAddress": "20.1.2.3"
+                                }
+                            ],
+                            "subnet": [
+                                {"address": "10.0.0.0", "prefix": "24"}
+                            ]
+                        },
+                        "ipv6": {"ipAddress": []},
+                        "macAddress": "000D3A123456"
+                    },
+                    {
+                        "ipv4": {
+                            "ipAddress": [
+                                {
+                                    "privateIpAddress": "10.0.1.4",
+                                    "publicIpAddress": ""
+                                }
+                            ],
+                            "subnet": [
+                                {"address": "10.0.1.0", "prefix": "24"}
+                            ]
+                        },
+                        "ipv6": {
+                            "ipAddress": [
+                                {"privateIpAddress": "fd00::4"}
+                            ]
+                        },
+                        "macAddress": "000D3A654321"
+                    }
+                ]
+            }
+        }"#;
+
+        let interfaces = get_network_interfaces(file_body)
+            .expect("Failed to get network interfaces.");
+
+        assert_eq!(interfaces.len(), 2);
+        assert_eq!(interfaces[0].mac_address, "000D3A123456");
+        assert_eq!(
+            interfaces[0].ipv4.ip_address[0].private_ip_address,
+            "10.0.0.4"
+        );
+        assert_eq!(
+            interfaces[0].ipv4.ip_address[0].public_ip_address,
+            "20.1.2.3"
+        );
+        assert_eq!(interfaces[0].ipv4.subnet[0].address, "10.0.0.0");
+        assert_eq!(interfaces[0].ipv4.subnet[0].prefix, "24");
+        assert!(interfaces[0].ipv6.ip_address.is_empty());
+        assert!(interfaces[1].ipv4.ip_address[0]
+            .public_ip_address
+            .is_empty());
+        assert_eq!(
+            interfaces[1].ipv6.ip_address[0].private_ip_address,
+            "fd00::4"
+        );
+    }
+
+    #[test]
+    fn test_get_network_interfaces_missing() {
+        let file_body = r#"{"compute": {"name": "test"}}"#;
+
+        let interfaces = get_network_interfaces(file_body)
+            .expect("Failed to get network interfaces.");
+
+        assert!(interfaces.is_empty());
+        assert!(get_network_interfaces(
+            r#"{"network": {"interface": "eth0"}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_get_tags_missing() {
+        let file_body = r#"{"compute": {"name": "test"}}"#.to_string();
+
+        let tags = get_tags(file_body).expect("Failed to get tags.");
+
+        assert!(tags.is_empty());
+    }
+
     #[test]
     fn test_provision_with_password_true() {
         let file_body = r#"
@@ -195,9 +1636,97 @@ mod tests {
         .to_string();
 
         let provision_with_password =
-            is_password_authentication_disabled(&file_body)
-                .expect("Failed to interpret disablePasswordAuthentication.");
+            is_password_authentication_disabled(&file_body);
+
+        assert!(provision_with_password);
+    }
 
-        assert_eq!(provision_with_password, true);
+    #[test]
+    fn test_password_authentication_enabled() {
+        let body = r#"{
+            "compute": {
+                "osProfile": {"disablePasswordAuthentication": "false"}
+            }
+        }"#;
+        assert!(!is_password_authentication_disabled(body));
+
+        let body = r#"{
+            "compute": {
+                "osProfile": {"disablePasswordAuthentication": false}
+            }
+        }"#;
+        assert!(!is_password_authentication_disabled(body));
+    }
+
+    #[test]
+    fn test_password_authentication_defaults_to_disabled() {
+        // Missing field.
+        assert!(is_password_authentication_disabled(
+            r#"{"compute": {"osProfile": {}}}"#
+        ));
+        // Unexpected value.
+        assert!(is_password_authentication_disabled(
+            r#"{"compute": {"osProfile": {"disablePasswordAuthentication": "no"}}}"#
+        ));
+        // Malformed body.
+        assert!(is_password_authentication_disabled("{\"compute\": "));
+    }
+
+    #[test]
+    fn test_imds_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("imds.json");
+        let body = r#"{"compute": {"osProfile": {"adminUsername": "a"}}}"#;
+
+        write_imds_cache(&path, body, &Runner::new()).unwrap();
+
+        assert_eq!(read_imds_cache(&path).unwrap(), body);
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn test_read_imds_cache_rejects_invalid_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("imds.json");
+        std::fs::write(&path, "{ truncated").unwrap();
+
+        match read_imds_cache(&path) {
+            Err(Error::Json(_)) => {}
+            _ => panic!("A corrupt cache should be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_imds_timeout() {
+        // Accept connections but never answer them.
+        let listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let config = ImdsConfig {
+            endpoint,
+            retry: RetryConfig {
+                max_attempts: 1,
+                ..Default::default()
+            },
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+
+        let start = std::time::Instant::now();
+        match query_imds_with_config(
+            &Client::new(),
+            &config,
+            &HttpConfig::default(),
+        )
+        .await
+        {
+            Err(Error::Http(error)) => assert!(error.is_timeout()),
+            _ => panic!("The request should have timed out"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        drop(listener);
     }
 }
@@ -1,12 +1,31 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+// Diagnostics go through tracing, so library consumers control where they
+// end up and nothing pollutes their stdout.
+#![warn(clippy::print_stdout, clippy::print_stderr)]
+
+pub mod command;
+pub mod config;
 pub mod distro;
 pub mod error;
+#[cfg(feature = "imds")]
 pub mod goalstate;
+pub mod hostname;
+pub mod http;
+#[cfg(feature = "imds")]
 pub mod imds;
+pub mod machine_id;
 pub mod media;
+pub mod platform;
+pub mod provision;
+pub mod report;
+pub mod ssh;
 pub mod user;
+pub mod util;
+pub mod verify;
 
+pub use provision::run_provisioning;
 // Re-export as the Client is used in our API.
+#[cfg(feature = "imds")]
 pub use reqwest;
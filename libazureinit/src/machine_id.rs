@@ -0,0 +1,278 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Reset the machine ID, which VMs cloned from an image otherwise share
+//! with the image and each other. Services such as DHCP clients derive
+//! identifiers from it, so duplicates cause lease and DNS collisions.
+
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::Runner;
+use crate::error::Error;
+
+pub const PATH_ETC_MACHINE_ID: &str = "/etc/machine-id";
+
+/// Backends able to reset the machine ID.
+///
+/// Provisioners are named in the configuration as in their
+/// [`FromStr`] implementation, e.g. `systemd_machine_id_setup` or
+/// `systemd-machine-id-setup`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", try_from = "String")]
+pub enum Provisioner {
+    /// Empty `/etc/machine-id` and generate a new ID right away with
+    /// `systemd-machine-id-setup`, which uses the VM's UUID if it has one.
+    SystemdMachineIdSetup,
+    /// Empty `/etc/machine-id`, so systemd generates a new ID on the next
+    /// boot. Useful where `systemd-machine-id-setup` isn't available, or
+    /// when preparing an image.
+    Truncate,
+}
+
+/// Backends tried, in order, when the caller doesn't choose any.
+pub const DEFAULT_PROVISIONERS: &[Provisioner] =
+    &[Provisioner::SystemdMachineIdSetup, Provisioner::Truncate];
+
+/// Controls whether provisioning resets the machine ID.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineIdOptions {
+    /// Replace the image's machine ID with a new one.
+    pub reset: bool,
+    /// Backends tried, in order, to reset it.
+    pub provisioners: Vec<Provisioner>,
+}
+
+impl Default for MachineIdOptions {
+    fn default() -> Self {
+        Self {
+            reset: false,
+            provisioners: DEFAULT_PROVISIONERS.to_vec(),
+        }
+    }
+}
+
+impl FromStr for Provisioner {
+    type Err = Error;
+
+    /// Parse a provisioner's snake_case name, in which hyphens may stand in
+    /// for underscores.
+    fn from_str(name: &str) -> Result<Self, Error> {
+        match name.replace('-', "_").as_str() {
+            "systemd_machine_id_setup" => {
+                Ok(Provisioner::SystemdMachineIdSetup)
+            }
+            "truncate" => Ok(Provisioner::Truncate),
+            _ => Err(Error::UnknownProvisioner {
+                name: name.to_string(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for Provisioner {
+    type Error = Error;
+
+    fn try_from(name: String) -> Result<Self, Error> {
+        name.parse()
+    }
+}
+
+impl Provisioner {
+    /// Discard the current machine ID, generating a new one if the
+    /// provisioner does.
+    pub fn reset(&self, runner: &Runner) -> Result<(), Error> {
+        let path = runner.path(PATH_ETC_MACHINE_ID);
+        if runner.is_dry_run() {
+            tracing::info!(
+                path = %path.display(),
+                "Dry run, not emptying the machine ID file"
+            );
+        } else {
+            truncate_machine_id(&path, runner)?;
+        }
+
+        match self {
+            Provisioner::SystemdMachineIdSetup => {
+                systemd_machine_id_setup(runner)
+            }
+            Provisioner::Truncate => Ok(()),
+        }
+    }
+}
+
+/// Reset the machine ID using the first of `provisioners` that succeeds,
+/// and return the provisioner that did.
+///
+/// If every provisioner fails, the error from the last one is returned.
+pub fn reset_machine_id(
+    provisioners: &[Provisioner],
+    runner: &Runner,
+) -> Result<Provisioner, Error> {
+    let mut last_error = Error::NoProvisioners {
+        resource: "machine ID".to_string(),
+    };
+
+    for provisioner in provisioners {
+        match provisioner.reset(runner) {
+            Ok(()) => {
+                tracing::info!(?provisioner, "Reset machine ID");
+                return Ok(*provisioner);
+            }
+            Err(error) => {
+                tracing::warn!(
+                    ?provisioner,
+                    ?error,
+                    "Failed to reset machine ID"
+                );
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+// An empty file, rather than a missing one, tells systemd to generate an
+// ID without treating the boot as the first one, and keeps /etc/machine-id
+// in place for read-only /etc setups that bind-mount over it.
+fn truncate_machine_id(path: &Path, runner: &Runner) -> Result<(), Error> {
+    runner.write_file(path, b"", 0o444, None)
+}
+
+fn systemd_machine_id_setup(runner: &Runner) -> Result<(), Error> {
+    let mut command = Command::new("systemd-machine-id-setup");
+    if !runner.is_system_root() {
+        command.arg("--root").arg(runner.root());
+    }
+
+    let output = runner.output(&mut command)?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::subprocess_failed(
+            "systemd-machine-id-setup",
+            &output,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use super::{
+        reset_machine_id, MachineIdOptions, Provisioner, DEFAULT_PROVISIONERS,
+    };
+    use crate::command::{RecordingCommandRunner, Runner};
+    use crate::error::Error;
+
+    // A root directory with an image's machine ID in place.
+    fn image_root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("etc")).unwrap();
+        fs::write(
+            root.path().join("etc/machine-id"),
+            "0123456789abcdef0123456789abcdef\n",
+        )
+        .unwrap();
+        root
+    }
+
+    #[test]
+    fn test_parse_provisioner() {
+        assert_eq!(
+            "systemd-machine-id-setup".parse::<Provisioner>().unwrap(),
+            Provisioner::SystemdMachineIdSetup
+        );
+        assert_eq!(
+            "truncate".parse::<Provisioner>().unwrap(),
+            Provisioner::Truncate
+        );
+        assert!(matches!(
+            "rm".parse::<Provisioner>(),
+            Err(Error::UnknownProvisioner { name }) if name == "rm"
+        ));
+    }
+
+    #[test]
+    fn test_options_default_to_not_resetting() {
+        let options: MachineIdOptions = toml::from_str("").unwrap();
+
+        assert!(!options.reset);
+        assert_eq!(options.provisioners, DEFAULT_PROVISIONERS);
+    }
+
+    #[test]
+    fn test_systemd_machine_id_setup_under_root_dir() {
+        let root = image_root();
+        let recorder = Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir(root.path());
+
+        assert_eq!(
+            reset_machine_id(DEFAULT_PROVISIONERS, &runner).unwrap(),
+            Provisioner::SystemdMachineIdSetup
+        );
+
+        assert_eq!(
+            fs::read_to_string(root.path().join("etc/machine-id")).unwrap(),
+            ""
+        );
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].program, "systemd-machine-id-setup");
+        assert_eq!(
+            commands[0].args,
+            ["--root".to_string(), root.path().display().to_string()]
+        );
+    }
+
+    #[test]
+    fn test_reset_falls_back_to_truncate() {
+        let root = image_root();
+        let recorder = Arc::new(RecordingCommandRunner::new());
+        recorder.fail("systemd-machine-id-setup", 1, "Failed to open /etc");
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir(root.path());
+
+        assert_eq!(
+            reset_machine_id(DEFAULT_PROVISIONERS, &runner).unwrap(),
+            Provisioner::Truncate
+        );
+        assert_eq!(
+            fs::read_to_string(root.path().join("etc/machine-id")).unwrap(),
+            ""
+        );
+    }
+
+    #[test]
+    fn test_reset_dry_run_keeps_machine_id() {
+        let root = image_root();
+        let runner = Runner::new().dry_run(true).root_dir(root.path());
+
+        reset_machine_id(&[Provisioner::Truncate], &runner).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(root.path().join("etc/machine-id")).unwrap(),
+            "0123456789abcdef0123456789abcdef\n"
+        );
+    }
+
+    #[test]
+    fn test_reset_without_provisioners() {
+        match reset_machine_id(&[], &Runner::new().dry_run(true)) {
+            Err(Error::NoProvisioners { resource }) => {
+                assert_eq!(resource, "machine ID")
+            }
+            _ => panic!("Resetting the machine ID needs a provisioner"),
+        }
+    }
+}
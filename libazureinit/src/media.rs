@@ -6,13 +6,18 @@ use std::fs::create_dir_all;
 use std::fs::File;
 use std::io::Read;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use base64::Engine;
 use serde::Deserialize;
-use serde_xml_rs::from_str;
 
+use crate::command::Runner;
 use crate::error::Error;
+use crate::http::RetryConfig;
 
 #[derive(Debug, Default, Deserialize, PartialEq, Clone)]
 pub struct Environment {
@@ -32,12 +37,56 @@ pub struct ProvisioningSection {
 
 #[derive(Debug, Default, Deserialize, PartialEq, Clone)]
 pub struct LinuxProvisioningConfigurationSet {
-    #[serde(rename = "UserName")]
+    // Required, but defaulted so parse_ovf_env can report it by name.
+    #[serde(default, rename = "UserName")]
     pub username: String,
     #[serde(default = "default_password", rename = "UserPassword")]
     pub password: String,
-    #[serde(rename = "HostName")]
+    #[serde(default, rename = "HostName")]
     pub hostname: String,
+    #[serde(
+        default = "default_disable_ssh_password_authentication",
+        rename = "DisableSshPasswordAuthentication"
+    )]
+    pub disable_ssh_password_authentication: bool,
+    #[serde(default, rename = "DisableSsh")]
+    pub disable_ssh: bool,
+    #[serde(
+        default,
+        rename = "CustomData",
+        deserialize_with = "deserialize_custom_data"
+    )]
+    pub custom_data: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct RawCustomData {
+    #[serde(default, rename = "CustomDataEncoding")]
+    encoding: Option<String>,
+    #[serde(default, rename = "$value")]
+    data: String,
+}
+
+// CustomData is base64-encoded when its CustomDataEncoding attribute says
+// so; otherwise the element text is used as-is.
+fn deserialize_custom_data<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = RawCustomData::deserialize(deserializer)?;
+
+    match raw.encoding {
+        Some(encoding) if encoding.eq_ignore_ascii_case("base64") => {
+            base64::engine::general_purpose::STANDARD
+                .decode(raw.data.trim())
+                .map_err(|error| {
+                    serde::de::Error::custom(format!(
+                        "invalid base64 in CustomData: {error}"
+                    ))
+                })
+        }
+        _ => Ok(raw.data.into_bytes()),
+    }
 }
 
 #[derive(Debug, Default, Deserialize, PartialEq, Clone)]
@@ -60,6 +109,11 @@ fn default_password() -> String {
     "".to_owned()
 }
 
+// Missing the element should never be read as enabling password login.
+fn default_disable_ssh_password_authentication() -> bool {
+    true
+}
+
 fn default_preprov() -> bool {
     false
 }
@@ -70,43 +124,166 @@ fn default_preprov_type() -> String {
 
 pub const PATH_MOUNT_DEVICE: &str = "/dev/sr0";
 pub const PATH_MOUNT_POINT: &str = "/run/azure-init/media/";
+const PATH_PROC_MOUNTS: &str = "/proc/self/mounts";
+/// The largest `ovf-env.xml` read by default. Real environments are a few
+/// kilobytes; the limit guards against corrupt or malicious media.
+pub const DEFAULT_MAX_OVF_ENV_SIZE: u64 = 4 * 1024 * 1024;
+/// How many seconds listing the devices that may hold provisioning media
+/// may take by default.
+pub const DEFAULT_SCAN_TIMEOUT_SECS: u64 = 10;
+/// Where a config drive mounted by the OS is looked for by default.
+pub const PATH_CONFIG_DRIVE: &str = "/media/configdrive";
+
+/// Filesystems provisioning media may be formatted with, in the order they
+/// are attempted when mounting.
+pub const MEDIA_VALID_FS: &[&str] = &["iso9660", "udf", "ntfs"];
 
-const CDROM_VALID_FS: &[&str] = &["iso9660", "udf"];
+/// Settings for finding the OVF environment on provisioning media.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+    /// How mounting each device is retried, since a device may not be
+    /// ready the instant it is listed early in boot. Retries wait a
+    /// jittered delay.
+    pub retry: RetryConfig,
+    /// Directories searched, in order, for `ovf-env.xml` when reading a
+    /// config drive, which the OS mounts rather than azure-init.
+    pub config_drive_paths: Vec<PathBuf>,
+    /// The largest `ovf-env.xml`, in bytes, that is read. A larger file
+    /// fails with [`Error::PayloadTooLarge`].
+    pub max_ovf_env_size: u64,
+    /// How many seconds listing the devices that may hold provisioning
+    /// media may take, after which only the devices found so far are
+    /// tried.
+    pub scan_timeout_secs: u64,
+}
 
-// Get a mounted device with any filesystem for CDROM
-pub fn get_mount_device() -> Result<Vec<String>, Error> {
-    let mut list_devices: Vec<String> = Vec::new();
+impl Default for MediaConfig {
+    fn default() -> Self {
+        Self {
+            retry: RetryConfig {
+                max_attempts: 3,
+                base_delay: Duration::from_millis(250),
+                max_delay: Duration::from_secs(1),
+            },
+            config_drive_paths: vec![PathBuf::from(PATH_CONFIG_DRIVE)],
+            max_ovf_env_size: DEFAULT_MAX_OVF_ENV_SIZE,
+            scan_timeout_secs: DEFAULT_SCAN_TIMEOUT_SECS,
+        }
+    }
+}
 
-    while let Some(device) = block_utils::get_mounted_devices()?
-        .into_iter()
-        .find(|dev| CDROM_VALID_FS.contains(&dev.fs_type.to_str()))
-    {
-        list_devices.push(device.name);
+impl MediaConfig {
+    /// The deadline for listing devices with [`get_mount_device`].
+    pub fn scan_timeout(&self) -> Duration {
+        Duration::from_secs(self.scan_timeout_secs)
     }
+}
 
-    Ok(list_devices)
+/// List the mounted devices with any filesystem valid for provisioning
+/// media.
+///
+/// Each device is looked up through udev, which may stall on systems with
+/// many disks or a slow storage subsystem. If the scan takes longer than
+/// `timeout`, a warning is logged and the devices found so far are
+/// returned.
+pub fn get_mount_device(timeout: Duration) -> Result<Vec<String>, Error> {
+    let devices =
+        block_utils::get_mounted_devices_iter()?.filter_map(|dev| match dev {
+            Ok(dev) if MEDIA_VALID_FS.contains(&dev.fs_type.to_str()) => {
+                Some(Ok(dev.name))
+            }
+            Ok(_) => None,
+            Err(error) => Some(Err(error)),
+        });
+
+    collect_within(devices, timeout)
 }
 
-// Some zero-sized structs that just provide states for our state machine
-pub struct Mounted;
-pub struct Unmounted;
+// Collect `items` until they run out or `timeout` passes, whichever comes
+// first. The iterator is consumed on another thread, which can't be
+// interrupted; on timeout it is left to finish and its results dropped.
+fn collect_within<T, E>(
+    items: impl Iterator<Item = Result<T, E>> + Send + 'static,
+    timeout: Duration,
+) -> Result<Vec<T>, Error>
+where
+    T: Send + 'static,
+    E: Into<Error> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for item in items {
+            if sender.send(item).is_err() {
+                break;
+            }
+        }
+    });
 
-pub struct Media<State = Unmounted> {
+    let deadline = Instant::now() + timeout;
+    let mut found = Vec::new();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(remaining) {
+            Ok(item) => found.push(item.map_err(Into::into)?),
+            Err(RecvTimeoutError::Disconnected) => return Ok(found),
+            Err(RecvTimeoutError::Timeout) => {
+                tracing::warn!(
+                    ?timeout,
+                    found = found.len(),
+                    "Timed out listing devices, using those found so far"
+                );
+                return Ok(found);
+            }
+        }
+    }
+}
+
+pub struct Media {
     device_path: PathBuf,
     mount_path: PathBuf,
-    state: std::marker::PhantomData<State>,
+    runner: Runner,
 }
 
-impl Media<Unmounted> {
-    pub fn new(device_path: PathBuf, mount_path: PathBuf) -> Media<Unmounted> {
+impl Media {
+    /// Media on `device_path`, to be mounted at `mount_path` with the
+    /// commands of `runner`.
+    pub fn new(
+        device_path: PathBuf,
+        mount_path: PathBuf,
+        runner: &Runner,
+    ) -> Media {
         Media {
             device_path,
             mount_path,
-            state: std::marker::PhantomData,
+            runner: runner.clone(),
         }
     }
 
-    pub fn mount(self) -> Result<Media<Mounted>, Error> {
+    /// Mount the media read-only, failing with [`Error::MountFailed`] if
+    /// it can't be.
+    ///
+    /// The media is mounted in dry-run mode too, so the OVF environment
+    /// can be read, but it is never ejected then. A `mount` that hangs, e.g.
+    /// on a stuck CD device, is killed after the runner's step timeout.
+    pub fn mount(self) -> Result<MountedMedia, Error> {
+        let device = self.device_path.display().to_string();
+
+        self.try_mount().map_err(|error| {
+            let errno = match &error {
+                Error::Io(error) => error.raw_os_error(),
+                Error::Nix(errno) => Some(*errno as i32),
+                _ => None,
+            };
+            Error::MountFailed {
+                device,
+                errno,
+                source: Box::new(error),
+            }
+        })
+    }
+
+    fn try_mount(self) -> Result<MountedMedia, Error> {
         create_dir_all(&self.mount_path)?;
 
         let metadata = fs::metadata(&self.mount_path)?;
@@ -115,81 +292,385 @@ impl Media<Unmounted> {
         new_permissions.set_mode(0o700);
         fs::set_permissions(&self.mount_path, new_permissions)?;
 
-        let mount_status = Command::new("mount")
-            .arg("-o")
-            .arg("ro")
-            .arg(&self.device_path)
-            .arg(&self.mount_path)
-            .status()?;
-
-        if !mount_status.success() {
-            Err(Error::SubprocessFailed {
-                command: "mount".to_string(),
-                status: mount_status,
-            })
-        } else {
-            Ok(Media {
-                device_path: self.device_path,
-                mount_path: self.mount_path,
-                state: std::marker::PhantomData,
-            })
+        // Try each filesystem in turn, since mount rejects the media if
+        // the given type doesn't match what's on the device.
+        let mut last_error = None;
+        for fs_type in MEDIA_VALID_FS {
+            let mount_output = self.runner.output_even_in_dry_run(
+                Command::new("mount")
+                    .arg("-t")
+                    .arg(fs_type)
+                    .arg("-o")
+                    .arg("ro")
+                    .arg(&self.device_path)
+                    .arg(&self.mount_path),
+            )?;
+
+            if mount_output.status.success() {
+                tracing::info!(
+                    device = ?self.device_path,
+                    fs_type,
+                    "Mounted provisioning media"
+                );
+                return Ok(MountedMedia {
+                    device_path: self.device_path,
+                    mount_path: self.mount_path,
+                    runner: self.runner,
+                    mounted: true,
+                });
+            }
+
+            tracing::debug!(
+                device = ?self.device_path,
+                fs_type,
+                "Unable to mount provisioning media"
+            );
+            last_error = Some(Error::subprocess_failed(
+                format!("mount -t {fs_type}"),
+                &mount_output,
+            ));
         }
+
+        Err(last_error.expect("MEDIA_VALID_FS must not be empty"))
     }
 }
 
-impl Media<Mounted> {
-    pub fn unmount(self) -> Result<(), Error> {
-        let umount_status =
-            Command::new("umount").arg(self.mount_path).status()?;
-        if !umount_status.success() {
-            return Err(Error::SubprocessFailed {
-                command: "umount".to_string(),
-                status: umount_status,
-            });
+/// Provisioning media mounted by [`Media::mount`].
+///
+/// If this is dropped without calling [`MountedMedia::unmount`], e.g.
+/// because reading the media failed, the media is still unmounted and the
+/// mount point removed so it does not leak across retries.
+pub struct MountedMedia {
+    device_path: PathBuf,
+    mount_path: PathBuf,
+    runner: Runner,
+    mounted: bool,
+}
+
+impl MountedMedia {
+    pub fn mount_path(&self) -> &Path {
+        &self.mount_path
+    }
+
+    /// Unmount and eject the media, reporting any failure. In dry-run
+    /// mode the media is only unmounted, so a later run can still read it.
+    pub fn unmount(mut self) -> Result<(), Error> {
+        self.mounted = false;
+
+        let umount_output = self.runner.output_even_in_dry_run(
+            Command::new("umount").arg(&self.mount_path),
+        )?;
+        if !umount_output.status.success() {
+            return Err(Error::subprocess_failed("umount", &umount_output));
         }
+        let _ = fs::remove_dir(&self.mount_path);
 
-        let eject_status =
-            Command::new("eject").arg(self.device_path).status()?;
-        if !eject_status.success() {
-            Err(Error::SubprocessFailed {
-                command: "eject".to_string(),
-                status: eject_status,
-            })
+        let eject_output = self
+            .runner
+            .output(Command::new("eject").arg(&self.device_path))?;
+        if !eject_output.status.success() {
+            Err(Error::subprocess_failed("eject", &eject_output))
         } else {
             Ok(())
         }
     }
 
     pub fn read_ovf_env_to_string(&self) -> Result<String, Error> {
-        let mut file_path = self.mount_path.clone();
-        file_path.push("ovf-env.xml");
-        let mut file =
-            File::open(file_path.to_str().unwrap_or(PATH_MOUNT_POINT))?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
+        read_ovf_env_to_string(&self.mount_path, DEFAULT_MAX_OVF_ENV_SIZE)
+    }
+}
+
+impl Drop for MountedMedia {
+    fn drop(&mut self) {
+        if !self.mounted {
+            return;
+        }
+
+        let umount = self.runner.output_even_in_dry_run(
+            Command::new("umount").arg(&self.mount_path),
+        );
+        match umount.map(|output| output.status) {
+            Ok(status) if status.success() => {}
+            result => tracing::warn!(
+                mount_path = ?self.mount_path,
+                ?result,
+                "Failed to unmount provisioning media"
+            ),
+        }
 
-        Ok(contents)
+        if let Err(error) = fs::remove_dir(&self.mount_path) {
+            tracing::warn!(
+                mount_path = ?self.mount_path,
+                ?error,
+                "Failed to remove the mount point"
+            );
+        }
     }
 }
 
-pub fn parse_ovf_env(ovf_body: &str) -> Result<Environment, Error> {
-    let environment: Environment = from_str(ovf_body)?;
-
-    if !environment
-        .provisioning_section
-        .linux_prov_conf_set
-        .password
-        .is_empty()
-    {
-        Err(Error::NonEmptyPassword)
-    } else {
+/// Unmount whatever is mounted at `mount_path` and remove the mount point,
+/// for when provisioning stops before [`MountedMedia`] could clean up.
+///
+/// Nothing is unmounted if nothing is mounted there, and a missing mount
+/// point is not an error. Media is mounted in dry-run mode too, so it is
+/// unmounted then as well.
+pub fn clean_up_mount_point(
+    mount_path: &Path,
+    runner: &Runner,
+) -> Result<(), Error> {
+    let mounts = fs::read_to_string(PATH_PROC_MOUNTS)?;
+    if is_mount_point(&mounts, mount_path) {
+        let output = runner
+            .output_even_in_dry_run(Command::new("umount").arg(mount_path))?;
+        if !output.status.success() {
+            return Err(Error::subprocess_failed("umount", &output));
+        }
+    }
+
+    match fs::remove_dir(mount_path) {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+// Whether `mounts`, in the format of /proc/self/mounts, lists a filesystem
+// mounted at `path`.
+fn is_mount_point(mounts: &str, path: &Path) -> bool {
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .any(|mount_point| Path::new(mount_point) == path)
+}
+
+// Read `ovf-env.xml` from `dir`, failing rather than reading more than
+// `max_size` bytes.
+fn read_ovf_env_to_string(dir: &Path, max_size: u64) -> Result<String, Error> {
+    let file = File::open(dir.join("ovf-env.xml"))?;
+    let mut contents = String::new();
+    file.take(max_size.saturating_add(1))
+        .read_to_string(&mut contents)?;
+    if contents.len() as u64 > max_size {
+        return Err(Error::PayloadTooLarge {
+            payload: "OVF environment".to_string(),
+            max_size,
+        });
+    }
+
+    Ok(contents)
+}
+
+/// Mount each of `devices` in turn at `mount_path` and return the first
+/// one holding a valid OVF environment, along with that environment.
+///
+/// This uses the default [`MediaConfig`]; see
+/// [`find_ovf_env_with_config`].
+pub fn find_ovf_env(
+    devices: &[String],
+    mount_path: &Path,
+    runner: &Runner,
+) -> Result<(String, Environment), Error> {
+    find_ovf_env_with_config(
+        devices,
+        mount_path,
+        &MediaConfig::default(),
+        runner,
+    )
+}
+
+/// Mount each of `devices` in turn at `mount_path` and return the first
+/// one holding a valid OVF environment, along with that environment.
+///
+/// Mounting a device is retried according to `config.retry`; a device that
+/// still can't be mounted is skipped. Devices after the first valid one
+/// are not looked at, so the environment can't be replaced by one on
+/// another device. Without any devices this fails with
+/// [`Error::NoProvisioningMedia`]; if none of them holds a valid
+/// environment, with [`Error::NoValidOvfEnvironment`].
+pub fn find_ovf_env_with_config(
+    devices: &[String],
+    mount_path: &Path,
+    config: &MediaConfig,
+    runner: &Runner,
+) -> Result<(String, Environment), Error> {
+    first_ovf_env(devices, |device| {
+        let mounted = retry_mount(&config.retry, device, || {
+            Media::new(PathBuf::from(device), mount_path.to_path_buf(), runner)
+                .mount()
+        })?;
+        // If parsing fails, dropping `mounted` unmounts the media.
+        let environment =
+            read_ovf_env(mounted.mount_path(), config.max_ovf_env_size)?;
+        mounted.unmount()?;
+
         Ok(environment)
+    })
+}
+
+/// Read the OVF environment from the first of `config.config_drive_paths`
+/// holding a valid one, and return that directory along with the
+/// environment.
+///
+/// This is for platforms presenting provisioning data as a config drive,
+/// e.g. a vfat filesystem, that the OS has already mounted; unlike
+/// [`find_ovf_env`], nothing is mounted. Without any directories this
+/// fails with [`Error::NoProvisioningMedia`]; if none of them holds a
+/// valid environment, with [`Error::NoValidOvfEnvironment`].
+pub fn find_config_drive_ovf_env(
+    config: &MediaConfig,
+) -> Result<(PathBuf, Environment), Error> {
+    let dirs: Vec<String> = config
+        .config_drive_paths
+        .iter()
+        .map(|dir| dir.display().to_string())
+        .collect();
+    let (dir, environment) = first_ovf_env(&dirs, |dir| {
+        read_ovf_env(Path::new(dir), config.max_ovf_env_size)
+    })?;
+
+    Ok((PathBuf::from(dir), environment))
+}
+
+// Call `mount` until it succeeds or `retry.max_attempts` is reached,
+// returning the last error if every attempt fails.
+fn retry_mount<T>(
+    retry: &RetryConfig,
+    device: &str,
+    mut mount: impl FnMut() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let max_attempts = retry.max_attempts.max(1);
+    let mut attempt = 1;
+
+    loop {
+        tracing::info!(
+            device,
+            attempt,
+            max_attempts,
+            "Mounting provisioning media"
+        );
+
+        let error = match mount() {
+            Ok(mounted) => return Ok(mounted),
+            Err(error) => error,
+        };
+        if attempt >= max_attempts {
+            return Err(error);
+        }
+
+        let delay = retry.jittered_delay(attempt);
+        tracing::warn!(
+            device,
+            attempt,
+            ?error,
+            ?delay,
+            "Mounting provisioning media failed, retrying"
+        );
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+fn first_ovf_env(
+    devices: &[String],
+    mut read_ovf_env: impl FnMut(&str) -> Result<Environment, Error>,
+) -> Result<(String, Environment), Error> {
+    let mut last_error = None;
+
+    for device in devices {
+        match read_ovf_env(device) {
+            Ok(environment) => {
+                tracing::info!(device, "Read the OVF environment");
+                return Ok((device.clone(), environment));
+            }
+            Err(error) => {
+                tracing::warn!(
+                    device,
+                    ?error,
+                    "Unable to read an OVF environment from the device"
+                );
+                last_error = Some(error);
+            }
+        }
     }
+
+    match last_error {
+        Some(error) => Err(Error::NoValidOvfEnvironment {
+            devices: devices.to_vec(),
+            source: Box::new(error),
+        }),
+        None => Err(Error::NoProvisioningMedia),
+    }
+}
+
+/// Read and parse `ovf-env.xml` from a directory where the provisioning
+/// media is already mounted, e.g. by the initramfs.
+///
+/// Unlike [`Media::mount`], this neither mounts nor ejects anything.
+///
+/// Files larger than [`DEFAULT_MAX_OVF_ENV_SIZE`] are rejected with
+/// [`Error::PayloadTooLarge`].
+pub fn parse_ovf_env_from_path(dir: &Path) -> Result<Environment, Error> {
+    read_ovf_env(dir, DEFAULT_MAX_OVF_ENV_SIZE)
+}
+
+fn read_ovf_env(dir: &Path, max_size: u64) -> Result<Environment, Error> {
+    let ovf_body = read_ovf_env_to_string(dir, max_size)?;
+
+    parse_ovf_env(ovf_body.as_str())
+}
+
+/// Parse the OVF environment document.
+///
+/// Elements are matched on their local name, so documents are accepted
+/// regardless of which namespace prefixes they use. If the document can't be
+/// deserialized, the error names the element that failed; if it lacks the
+/// username or hostname, [`Error::OvfMissingField`] names the missing one.
+pub fn parse_ovf_env(ovf_body: &str) -> Result<Environment, Error> {
+    let mut deserializer =
+        serde_xml_rs::Deserializer::new_from_reader(ovf_body.as_bytes());
+    let environment: Environment = serde_path_to_error::deserialize(
+        &mut deserializer,
+    )
+    .map_err(|error| Error::OvfParse {
+        element: error.path().to_string(),
+        source: error.into_inner(),
+    })?;
+
+    validate_ovf_env(&environment)?;
+
+    Ok(environment)
+}
+
+// Check the settings provisioning can't do without, so a document the
+// platform got wrong is told apart from one we failed to parse.
+fn validate_ovf_env(environment: &Environment) -> Result<(), Error> {
+    let settings = &environment.provisioning_section.linux_prov_conf_set;
+
+    for (element, value) in [
+        ("UserName", &settings.username),
+        ("HostName", &settings.hostname),
+    ] {
+        if value.trim().is_empty() {
+            return Err(Error::OvfMissingField {
+                field: format!(
+                    "ProvisioningSection.LinuxProvisioningConfigurationSet.{element}"
+                ),
+            });
+        }
+    }
+
+    if !settings.password.is_empty() {
+        return Err(Error::NonEmptyPassword);
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use super::*;
+    use crate::command::{CommandRunner, RecordingCommandRunner};
 
     #[test]
     fn test_get_ovf_env_none_missing() {
@@ -248,12 +729,17 @@ mod tests {
                 .hostname,
             "myhostname"
         );
-        assert_eq!(
-            environment
+        assert!(
+            !environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .disable_ssh_password_authentication
+        );
+        assert!(
+            !environment
                 .platform_settings_section
                 .platform_settings
-                .preprovisioned_vm,
-            false
+                .preprovisioned_vm
         );
         assert_eq!(
             environment
@@ -321,19 +807,583 @@ mod tests {
                 .hostname,
             "myhostname"
         );
+        assert!(
+            !environment
+                .platform_settings_section
+                .platform_settings
+                .preprovisioned_vm
+        );
         assert_eq!(
             environment
                 .platform_settings_section
                 .platform_settings
-                .preprovisioned_vm,
-            false
+                .preprovisioned_vm_type,
+            "None"
+        );
+    }
+
+    #[test]
+    fn test_get_ovf_env_password_authentication_default() {
+        let ovf_body = r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:oe="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:wa="http://schemas.microsoft.com/windowsazure"
+            xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+            <wa:ProvisioningSection>
+                <wa:Version>1.0</wa:Version>
+                <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure"
+                    xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+                    <ConfigurationSetType>LinuxProvisioningConfiguration</ConfigurationSetType>
+                    <UserName>myusername</UserName>
+                    <HostName>myhostname</HostName>
+                </LinuxProvisioningConfigurationSet>
+            </wa:ProvisioningSection>
+            <wa:PlatformSettingsSection>
+                <wa:Version>1.0</wa:Version>
+                <PlatformSettings xmlns="http://schemas.microsoft.com/windowsazure"
+                    xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+                    <PreprovisionedVm>false</PreprovisionedVm>
+                </PlatformSettings>
+            </wa:PlatformSettingsSection>
+        </Environment>"#;
+
+        let environment: Environment = parse_ovf_env(ovf_body).unwrap();
+
+        assert!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .disable_ssh_password_authentication
+        );
+    }
+
+    fn ovf_with_custom_data(custom_data: &str) -> String {
+        ovf_with_settings(&format!(
+            "<UserName>myusername</UserName>
+            <HostName>myhostname</HostName>
+            {custom_data}"
+        ))
+    }
+
+    fn ovf_with_settings(settings: &str) -> String {
+        format!(
+            r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:oe="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:wa="http://schemas.microsoft.com/windowsazure"
+            xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+            <wa:ProvisioningSection>
+                <wa:Version>1.0</wa:Version>
+                <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure"
+                    xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+                    <ConfigurationSetType>LinuxProvisioningConfiguration</ConfigurationSetType>
+                    {settings}
+                </LinuxProvisioningConfigurationSet>
+            </wa:ProvisioningSection>
+            <wa:PlatformSettingsSection>
+                <wa:Version>1.0</wa:Version>
+                <PlatformSettings xmlns="http://schemas.microsoft.com/windowsazure"
+                    xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+                    <PreprovisionedVm>false</PreprovisionedVm>
+                </PlatformSettings>
+            </wa:PlatformSettingsSection>
+        </Environment>"#
+        )
+    }
+
+    #[test]
+    fn test_get_ovf_env_custom_data_base64() {
+        let ovf_body = ovf_with_custom_data(
+            r#"<CustomData CustomDataEncoding="Base64">IyEvYmluL3NoCmVjaG8gaGVsbG8K</CustomData>"#,
+        );
+
+        let environment = parse_ovf_env(&ovf_body).unwrap();
+
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .custom_data,
+            b"#!/bin/sh\necho hello\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_get_ovf_env_custom_data_plain() {
+        let ovf_body =
+            ovf_with_custom_data("<CustomData>plain text</CustomData>");
+
+        let environment = parse_ovf_env(&ovf_body).unwrap();
+
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .custom_data,
+            b"plain text".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_get_ovf_env_custom_data_absent() {
+        let ovf_body = ovf_with_custom_data("");
+
+        let environment = parse_ovf_env(&ovf_body).unwrap();
+
+        assert!(environment
+            .provisioning_section
+            .linux_prov_conf_set
+            .custom_data
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_ovf_env_disable_ssh() {
+        let settings = |element| {
+            parse_ovf_env(&ovf_with_custom_data(element))
+                .unwrap()
+                .provisioning_section
+                .linux_prov_conf_set
+        };
+
+        assert!(settings("<DisableSsh>true</DisableSsh>").disable_ssh);
+        assert!(!settings("<DisableSsh>false</DisableSsh>").disable_ssh);
+        assert!(!settings("").disable_ssh);
+    }
+
+    #[test]
+    fn test_get_ovf_env_custom_data_malformed() {
+        let ovf_body = ovf_with_custom_data(
+            r#"<CustomData CustomDataEncoding="Base64">not base64!</CustomData>"#,
+        );
+
+        match parse_ovf_env(&ovf_body) {
+            Err(Error::OvfParse { element, .. }) => assert_eq!(
+                element,
+                "ProvisioningSection.LinuxProvisioningConfigurationSet.CustomData"
+            ),
+            _ => panic!("Malformed base64 custom data should be rejected"),
+        };
+    }
+
+    #[test]
+    fn test_get_ovf_env_alternate_prefixes() {
+        let ovf_body = r#"
+        <ns0:Environment xmlns:ns0="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:ns1="http://schemas.microsoft.com/windowsazure"
+            xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+            <ns1:ProvisioningSection>
+                <ns1:Version>1.0</ns1:Version>
+                <ns1:LinuxProvisioningConfigurationSet>
+                    <ns1:ConfigurationSetType>LinuxProvisioningConfiguration</ns1:ConfigurationSetType>
+                    <ns1:UserName>myusername</ns1:UserName>
+                    <ns1:UserPassword></ns1:UserPassword>
+                    <ns1:DisableSshPasswordAuthentication>false</ns1:DisableSshPasswordAuthentication>
+                    <ns1:HostName>myhostname</ns1:HostName>
+                </ns1:LinuxProvisioningConfigurationSet>
+            </ns1:ProvisioningSection>
+            <ns1:PlatformSettingsSection>
+                <ns1:Version>1.0</ns1:Version>
+                <ns1:PlatformSettings>
+                    <ns1:PreprovisionedVm>true</ns1:PreprovisionedVm>
+                    <ns1:PreprovisionedVmType>Savable</ns1:PreprovisionedVmType>
+                </ns1:PlatformSettings>
+            </ns1:PlatformSettingsSection>
+        </ns0:Environment>"#;
+
+        let environment = parse_ovf_env(ovf_body).unwrap();
+
+        let linux_prov_conf_set =
+            &environment.provisioning_section.linux_prov_conf_set;
+        assert_eq!(linux_prov_conf_set.username, "myusername");
+        assert_eq!(linux_prov_conf_set.hostname, "myhostname");
+        assert!(!linux_prov_conf_set.disable_ssh_password_authentication);
+        assert!(
+            environment
+                .platform_settings_section
+                .platform_settings
+                .preprovisioned_vm
         );
         assert_eq!(
             environment
                 .platform_settings_section
                 .platform_settings
                 .preprovisioned_vm_type,
-            "None"
+            "Savable"
+        );
+    }
+
+    #[test]
+    fn test_get_ovf_env_default_namespace_only() {
+        let ovf_body = r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1">
+            <ProvisioningSection xmlns="http://schemas.microsoft.com/windowsazure">
+                <Version>1.0</Version>
+                <LinuxProvisioningConfigurationSet>
+                    <UserName>myusername</UserName>
+                    <HostName>myhostname</HostName>
+                </LinuxProvisioningConfigurationSet>
+            </ProvisioningSection>
+            <PlatformSettingsSection xmlns="http://schemas.microsoft.com/windowsazure">
+                <Version>1.0</Version>
+                <PlatformSettings>
+                    <PreprovisionedVm>false</PreprovisionedVm>
+                </PlatformSettings>
+            </PlatformSettingsSection>
+        </Environment>"#;
+
+        let environment = parse_ovf_env(ovf_body).unwrap();
+
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .username,
+            "myusername"
+        );
+    }
+
+    #[test]
+    fn test_get_ovf_env_error_names_element() {
+        let ovf_body = r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:wa="http://schemas.microsoft.com/windowsazure">
+            <wa:ProvisioningSection>
+                <wa:Version>1.0</wa:Version>
+                <wa:LinuxProvisioningConfigurationSet>
+                    <wa:UserName>myusername</wa:UserName>
+                    <wa:HostName>myhostname</wa:HostName>
+                </wa:LinuxProvisioningConfigurationSet>
+            </wa:ProvisioningSection>
+            <wa:PlatformSettingsSection>
+                <wa:Version>1.0</wa:Version>
+                <wa:PlatformSettings>
+                    <wa:PreprovisionedVm>maybe</wa:PreprovisionedVm>
+                </wa:PlatformSettings>
+            </wa:PlatformSettingsSection>
+        </Environment>"#;
+
+        match parse_ovf_env(ovf_body) {
+            Err(Error::OvfParse { element, .. }) => assert_eq!(
+                element,
+                "PlatformSettingsSection.PlatformSettings.PreprovisionedVm"
+            ),
+            _ => panic!("An invalid boolean should fail to parse"),
+        };
+    }
+
+    #[test]
+    fn test_parse_ovf_env_from_path() {
+        let ovf_body = r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:oe="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:wa="http://schemas.microsoft.com/windowsazure"
+            xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+            <wa:ProvisioningSection>
+                <wa:Version>1.0</wa:Version>
+                <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure"
+                    xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+                    <ConfigurationSetType>LinuxProvisioningConfiguration</ConfigurationSetType>
+                    <UserName>myusername</UserName>
+                    <UserPassword></UserPassword>
+                    <DisableSshPasswordAuthentication>false</DisableSshPasswordAuthentication>
+                    <HostName>myhostname</HostName>
+                </LinuxProvisioningConfigurationSet>
+            </wa:ProvisioningSection>
+            <wa:PlatformSettingsSection>
+                <wa:Version>1.0</wa:Version>
+                <PlatformSettings xmlns="http://schemas.microsoft.com/windowsazure"
+                    xmlns:i="http://www.w3.org/2001/XMLSchema-instance">
+                    <PreprovisionedVm>false</PreprovisionedVm>
+                </PlatformSettings>
+            </wa:PlatformSettingsSection>
+        </Environment>"#;
+        let mount_dir = tempfile::tempdir().unwrap();
+        fs::write(mount_dir.path().join("ovf-env.xml"), ovf_body).unwrap();
+
+        let environment = parse_ovf_env_from_path(mount_dir.path()).unwrap();
+
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .username,
+            "myusername"
+        );
+    }
+
+    #[test]
+    fn test_first_ovf_env_stops_at_first_valid_device() {
+        let ovf_body = |username: &str| {
+            format!(
+                r#"
+        <Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+            xmlns:wa="http://schemas.microsoft.com/windowsazure">
+            <wa:ProvisioningSection>
+                <wa:Version>1.0</wa:Version>
+                <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure">
+                    <ConfigurationSetType>LinuxProvisioningConfiguration</ConfigurationSetType>
+                    <UserName>{username}</UserName>
+                    <HostName>myhostname</HostName>
+                </LinuxProvisioningConfigurationSet>
+            </wa:ProvisioningSection>
+            <wa:PlatformSettingsSection>
+                <wa:Version>1.0</wa:Version>
+                <PlatformSettings xmlns="http://schemas.microsoft.com/windowsazure">
+                    <PreprovisionedVm>false</PreprovisionedVm>
+                </PlatformSettings>
+            </wa:PlatformSettingsSection>
+        </Environment>"#
+            )
+        };
+        let devices = tempfile::tempdir().unwrap();
+        let paths: Vec<String> = ["sr0", "sr1", "sr2"]
+            .iter()
+            .map(|name| {
+                let path = devices.path().join(name);
+                create_dir_all(&path).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        fs::write(Path::new(&paths[0]).join("ovf-env.xml"), "not xml").unwrap();
+        fs::write(
+            Path::new(&paths[1]).join("ovf-env.xml"),
+            ovf_body("firstuser"),
+        )
+        .unwrap();
+        fs::write(
+            Path::new(&paths[2]).join("ovf-env.xml"),
+            ovf_body("seconduser"),
+        )
+        .unwrap();
+
+        let mut tried = Vec::new();
+        let (device, environment) = first_ovf_env(&paths, |device| {
+            tried.push(device.to_string());
+            parse_ovf_env_from_path(Path::new(device))
+        })
+        .unwrap();
+
+        assert_eq!(device, paths[1]);
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .username,
+            "firstuser"
+        );
+        assert_eq!(tried, paths[..2]);
+    }
+
+    #[test]
+    fn test_find_config_drive_ovf_env() {
+        let config_drive = tempfile::tempdir().unwrap();
+        fs::write(
+            config_drive.path().join("ovf-env.xml"),
+            ovf_with_settings(
+                "<UserName>myusername</UserName>
+                <HostName>myhostname</HostName>",
+            ),
+        )
+        .unwrap();
+        let mut config = MediaConfig {
+            config_drive_paths: vec![
+                config_drive.path().join("missing"),
+                config_drive.path().to_path_buf(),
+            ],
+            ..Default::default()
+        };
+
+        let (dir, environment) = find_config_drive_ovf_env(&config).unwrap();
+
+        assert_eq!(dir, config_drive.path());
+        assert_eq!(
+            environment
+                .provisioning_section
+                .linux_prov_conf_set
+                .username,
+            "myusername"
+        );
+
+        config.config_drive_paths.pop();
+        match find_config_drive_ovf_env(&config) {
+            Err(Error::NoValidOvfEnvironment { source, .. }) => {
+                assert!(matches!(*source, Error::Io(_)))
+            }
+            _ => panic!("A directory without ovf-env.xml should be skipped"),
+        }
+    }
+
+    #[test]
+    fn test_first_ovf_env_without_valid_devices() {
+        match first_ovf_env(&[], |_| unreachable!()) {
+            Err(Error::NoProvisioningMedia) => {}
+            _ => panic!("No devices should mean no provisioning media"),
+        }
+
+        let devices = vec!["/dev/sr0".to_string()];
+        match first_ovf_env(&devices, |_| Err(Error::NonEmptyPassword)) {
+            Err(Error::NoValidOvfEnvironment { devices, source }) => {
+                assert_eq!(devices, ["/dev/sr0"]);
+                assert!(matches!(*source, Error::NonEmptyPassword));
+            }
+            _ => panic!("Devices without valid environments are an error"),
+        }
+    }
+
+    #[test]
+    fn test_read_ovf_env_size_limit() {
+        let mount_dir = tempfile::tempdir().unwrap();
+        let ovf_body = ovf_with_settings(
+            "<UserName>myusername</UserName>
+            <HostName>myhostname</HostName>",
+        );
+        fs::write(mount_dir.path().join("ovf-env.xml"), &ovf_body).unwrap();
+        let size = ovf_body.len() as u64;
+
+        read_ovf_env(mount_dir.path(), size).unwrap();
+        match read_ovf_env(mount_dir.path(), size - 1) {
+            Err(Error::PayloadTooLarge { max_size, .. }) => {
+                assert_eq!(max_size, size - 1)
+            }
+            _ => panic!("An oversized OVF environment should be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_parse_ovf_env_from_path_missing_file() {
+        let mount_dir = tempfile::tempdir().unwrap();
+
+        match parse_ovf_env_from_path(mount_dir.path()) {
+            Err(Error::Io(_)) => {}
+            _ => panic!("A missing ovf-env.xml should be an I/O error"),
+        };
+    }
+
+    #[test]
+    fn test_mount_failure_names_device() {
+        // The mount point can't be created under a regular file.
+        let test_file = tempfile::NamedTempFile::new().unwrap();
+        let media = Media::new(
+            PathBuf::from("/dev/sr0"),
+            test_file.path().join("media"),
+            &Runner::new(),
+        );
+
+        match media.mount() {
+            Err(Error::MountFailed { device, errno, .. }) => {
+                assert_eq!(device, "/dev/sr0");
+                assert_eq!(errno, Some(nix::libc::ENOTDIR));
+            }
+            _ => panic!("A failed mount should name the device"),
+        }
+    }
+
+    #[test]
+    fn test_mount_point_removed_on_parse_error() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let mount_path = test_dir.path().join("media");
+        create_dir_all(&mount_path).unwrap();
+
+        let recorder = Arc::new(RecordingCommandRunner::new());
+
+        let mounted = MountedMedia {
+            device_path: PathBuf::from("/dev/null"),
+            mount_path: mount_path.clone(),
+            runner: Runner::new().command_runner(recorder.clone()),
+            mounted: true,
+        };
+
+        assert!(parse_ovf_env_from_path(mounted.mount_path()).is_err());
+        drop(mounted);
+
+        assert!(!mount_path.exists());
+        let programs: Vec<String> = recorder
+            .commands()
+            .into_iter()
+            .map(|command| command.program)
+            .collect();
+        assert_eq!(programs, ["umount"]);
+    }
+
+    // Fails every command as if it hung until the step timeout, recording
+    // the timeout it was given.
+    #[derive(Debug, Default)]
+    struct HangingCommandRunner {
+        timeouts: std::sync::Mutex<Vec<Duration>>,
+    }
+
+    impl CommandRunner for HangingCommandRunner {
+        fn run(
+            &self,
+            command: &mut Command,
+            _input: Option<&[u8]>,
+            _capture: bool,
+            timeout: Duration,
+        ) -> Result<std::process::Output, Error> {
+            self.timeouts.lock().unwrap().push(timeout);
+            Err(Error::CommandTimeout {
+                command: command.get_program().to_string_lossy().into_owned(),
+                timeout,
+            })
+        }
+    }
+
+    #[test]
+    fn test_mount_uses_step_timeout() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let hanging = Arc::new(HangingCommandRunner::default());
+        let runner = Runner::new()
+            .step_timeout(Duration::from_secs(5))
+            .command_runner(hanging.clone());
+
+        let media = Media::new(
+            PathBuf::from("/dev/sr0"),
+            test_dir.path().join("media"),
+            &runner,
+        );
+
+        match media.mount() {
+            Err(Error::MountFailed { source, .. }) => {
+                assert!(matches!(*source, Error::CommandTimeout { .. }))
+            }
+            _ => panic!("A hung mount should fail once the step times out"),
+        }
+        // A stuck device isn't tried with the other filesystems.
+        assert_eq!(*hanging.timeouts.lock().unwrap(), [Duration::from_secs(5)]);
+    }
+
+    #[test]
+    fn test_dry_run_mounts_without_ejecting() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let mount_path = test_dir.path().join("media");
+        let recorder = Arc::new(RecordingCommandRunner::new());
+        let runner =
+            Runner::new().dry_run(true).command_runner(recorder.clone());
+
+        let mounted =
+            Media::new(PathBuf::from("/dev/sr0"), mount_path.clone(), &runner)
+                .mount()
+                .unwrap();
+        mounted.unmount().unwrap();
+
+        let commands: Vec<(String, Vec<String>)> = recorder
+            .commands()
+            .into_iter()
+            .map(|command| (command.program, command.args))
+            .collect();
+        let mount_point = mount_path.to_string_lossy().into_owned();
+        assert_eq!(
+            commands,
+            [
+                (
+                    "mount".to_string(),
+                    ["-t", "iso9660", "-o", "ro", "/dev/sr0", &mount_point]
+                        .map(String::from)
+                        .to_vec()
+                ),
+                ("umount".to_string(), vec![mount_point.clone()]),
+            ]
         );
     }
 
@@ -374,4 +1424,131 @@ mod tests {
             _ => panic!("Non-empty passwords aren't allowed"),
         };
     }
+
+    #[test]
+    fn test_get_ovf_env_missing_username() {
+        let ovf_body = ovf_with_settings("<HostName>myhostname</HostName>");
+
+        match parse_ovf_env(&ovf_body) {
+            Err(Error::OvfMissingField { field }) => assert_eq!(
+                field,
+                "ProvisioningSection.LinuxProvisioningConfigurationSet.UserName"
+            ),
+            _ => panic!("A missing username should be reported"),
+        };
+    }
+
+    #[test]
+    fn test_get_ovf_env_empty_username() {
+        let ovf_body = ovf_with_settings(
+            "<UserName> </UserName>
+            <HostName>myhostname</HostName>",
+        );
+
+        match parse_ovf_env(&ovf_body) {
+            Err(Error::OvfMissingField { field }) => {
+                assert!(field.ends_with(".UserName"))
+            }
+            _ => panic!("An empty username should be reported"),
+        };
+    }
+
+    #[test]
+    fn test_get_ovf_env_missing_hostname() {
+        let ovf_body = ovf_with_settings("<UserName>myusername</UserName>");
+
+        match parse_ovf_env(&ovf_body) {
+            Err(Error::OvfMissingField { field }) => assert_eq!(
+                field,
+                "ProvisioningSection.LinuxProvisioningConfigurationSet.HostName"
+            ),
+            _ => panic!("A missing hostname should be reported"),
+        };
+    }
+
+    #[test]
+    fn test_retry_mount_until_success() {
+        let retry = RetryConfig {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        };
+        let mut attempts = 0;
+
+        let result = retry_mount(&retry, "/dev/sr0", || {
+            attempts += 1;
+            match attempts {
+                3 => Ok(attempts),
+                _ => Err(Error::NoProvisioningMedia),
+            }
+        });
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn test_retry_mount_gives_up() {
+        let retry = RetryConfig {
+            max_attempts: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        };
+        let mut attempts = 0;
+
+        let result: Result<(), Error> = retry_mount(&retry, "/dev/sr0", || {
+            attempts += 1;
+            Err(Error::NoProvisioningMedia)
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+    }
+
+    #[test]
+    fn test_collect_within_returns_devices_found_before_timeout() {
+        let devices = ["/dev/sr0", "/dev/sr1"].into_iter().enumerate().map(
+            |(i, device)| {
+                if i > 0 {
+                    thread::sleep(Duration::from_secs(5));
+                }
+                Ok::<_, Error>(device)
+            },
+        );
+
+        let started = Instant::now();
+        let found = collect_within(devices, Duration::from_millis(200));
+
+        assert_eq!(found.unwrap(), ["/dev/sr0"]);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_collect_within_fails_on_error() {
+        let devices = vec![Ok("/dev/sr0"), Err(Error::NoProvisioningMedia)];
+
+        assert!(matches!(
+            collect_within(devices.into_iter(), Duration::from_secs(5)),
+            Err(Error::NoProvisioningMedia)
+        ));
+    }
+
+    #[test]
+    fn test_is_mount_point() {
+        let mounts = "proc /proc proc rw,nosuid 0 0\n\
+            /dev/sr0 /run/azure-init/media iso9660 ro,relatime 0 0\n";
+
+        assert!(is_mount_point(mounts, Path::new(PATH_MOUNT_POINT)));
+        assert!(!is_mount_point(mounts, Path::new("/run/azure-init")));
+    }
+
+    #[test]
+    fn test_clean_up_unmounted_mount_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let mount_path = dir.path().join("media");
+        fs::create_dir(&mount_path).unwrap();
+
+        clean_up_mount_point(&mount_path, &Runner::new()).unwrap();
+        assert!(!mount_path.exists());
+        clean_up_mount_point(&mount_path, &Runner::new()).unwrap();
+    }
 }
@@ -0,0 +1,74 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Detect whether we're running on Azure, before waiting on platform
+//! endpoints that only exist there.
+
+use std::fs;
+use std::path::Path;
+
+/// Where the kernel exposes the firmware's DMI identification.
+pub const PATH_DMI_ID: &str = "/sys/class/dmi/id";
+
+/// The chassis asset tag of every Azure VM.
+pub const AZURE_CHASSIS_ASSET_TAG: &str = "7783-7084-3265-9085-8269-3286-77";
+
+/// The system vendor of Hyper-V VMs, including Azure's.
+pub const MICROSOFT_SYS_VENDOR: &str = "Microsoft Corporation";
+
+/// Whether this machine looks like an Azure VM, judging by its DMI system
+/// vendor and chassis asset tag.
+///
+/// This only reads two small sysfs files, so it is cheap enough to call
+/// before anything else. Machines without DMI, such as containers, don't
+/// look like Azure.
+pub fn is_azure() -> bool {
+    is_azure_in(Path::new(PATH_DMI_ID))
+}
+
+fn is_azure_in(dmi_id: &Path) -> bool {
+    let read = |name: &str| {
+        fs::read_to_string(dmi_id.join(name))
+            .map(|value| value.trim().to_string())
+            .ok()
+    };
+    let sys_vendor = read("sys_vendor");
+    let asset_tag = read("chassis_asset_tag");
+
+    let is_azure = sys_vendor.as_deref() == Some(MICROSOFT_SYS_VENDOR)
+        && asset_tag.as_deref() == Some(AZURE_CHASSIS_ASSET_TAG);
+    if !is_azure {
+        tracing::debug!(?sys_vendor, ?asset_tag, "Not running on Azure");
+    }
+
+    is_azure
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{is_azure_in, AZURE_CHASSIS_ASSET_TAG, MICROSOFT_SYS_VENDOR};
+
+    #[test]
+    fn test_is_azure() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!is_azure_in(dir.path()));
+
+        fs::write(
+            dir.path().join("sys_vendor"),
+            format!("{MICROSOFT_SYS_VENDOR}\n"),
+        )
+        .unwrap();
+        // Hyper-V outside Azure has the same vendor.
+        fs::write(dir.path().join("chassis_asset_tag"), "None\n").unwrap();
+        assert!(!is_azure_in(dir.path()));
+
+        fs::write(
+            dir.path().join("chassis_asset_tag"),
+            format!("{AZURE_CHASSIS_ASSET_TAG}\n"),
+        )
+        .unwrap();
+        assert!(is_azure_in(dir.path()));
+    }
+}
@@ -0,0 +1,1667 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+#[cfg(feature = "imds")]
+use reqwest::Client;
+
+use crate::command::Runner;
+use crate::config::Config;
+use crate::distro::{Distribution, Distributions, ProvisionReport};
+use crate::error::{Error, ResourceError};
+use crate::media::{self, Environment, MediaConfig};
+#[cfg(feature = "imds")]
+use crate::report::OvfFallbackReason;
+use crate::report::ProvisioningReport;
+use crate::user::{self, PublicKeys, User};
+#[cfg(feature = "imds")]
+use crate::{goalstate, imds};
+use crate::{hostname, machine_id, ssh};
+
+// Without the imds feature there is no HTTP client; the steps take a unit
+// in its place so they share one signature.
+#[cfg(not(feature = "imds"))]
+type Client = ();
+
+/// Settings for a provisioning run that don't come from the [`Config`].
+#[derive(Clone, Default)]
+pub struct ProvisioningOptions {
+    /// Runs the commands provisioning needs; set it to dry-run mode to only
+    /// log what would change.
+    pub runner: Runner,
+    /// Provision for this distribution, overriding both
+    /// `config.distribution` and detection.
+    pub distribution: Option<Arc<dyn Distribution + Send + Sync>>,
+    // Read the OVF environment from this directory instead of mounting the
+    // provisioning media, so tests don't depend on the host's devices.
+    #[cfg(test)]
+    pub ovf_env_dir: Option<PathBuf>,
+    /// Save the IMDS response to this path after querying IMDS.
+    #[cfg(feature = "imds")]
+    pub imds_cache: Option<PathBuf>,
+    /// Read the IMDS response from `imds_cache` instead of querying IMDS.
+    #[cfg(feature = "imds")]
+    pub read_imds_cache: bool,
+}
+
+// A span for one provisioning phase, e.g. `user`, with the fields
+// `in_phase` fills in once the phase is over.
+macro_rules! phase_span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        tracing::info_span!(
+            $name,
+            outcome = tracing::field::Empty,
+            duration_ms = tracing::field::Empty
+            $(, $($fields)*)?
+        )
+    };
+}
+
+// Run `phase` within `span`, recording on the span whether it succeeded
+// and how long it took, so phase latency can be exported and analysed.
+async fn in_phase<T>(
+    span: tracing::Span,
+    phase: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let started = Instant::now();
+    let result = phase.instrument(span.clone()).await;
+
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    span.record(
+        "outcome",
+        match result {
+            Ok(_) => "success",
+            Err(_) => "failure",
+        },
+    );
+
+    result
+}
+
+/// Where the admin user, hostname and SSH keys are read from.
+///
+/// Without the `imds` feature, the OVF environment is always used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataSource {
+    /// Read everything from IMDS, except that the username comes from the
+    /// OVF environment when IMDS reports password authentication is
+    /// enabled. If waiting for IMDS is configured and IMDS doesn't respond
+    /// in time, the OVF environment is read instead.
+    #[default]
+    Auto,
+    /// Read everything from IMDS, never mounting provisioning media.
+    Imds,
+    /// Read the username, hostname and password policy from the OVF
+    /// environment without querying IMDS. No SSH keys are installed for
+    /// the admin user, since the OVF environment doesn't carry them.
+    Ovf,
+    /// Read everything from IMDS, and from the OVF environment, as with
+    /// [`MetadataSource::Ovf`], only if IMDS can't be queried.
+    ImdsWithOvfFallback,
+    /// Read the OVF environment, as with [`MetadataSource::Ovf`], from a
+    /// config drive the OS has already mounted at one of
+    /// `media.config_drive_paths`, rather than mounting media.
+    ConfigDrive,
+}
+
+/// Provisioning phases to leave out, e.g. for images that already have the
+/// admin user baked in. Skipped phases are recorded as skipped steps in
+/// the [`ProvisioningReport`].
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SkipOptions {
+    /// Don't create users, only install their SSH keys; the users must
+    /// already exist.
+    pub user: bool,
+    /// Don't set the password of users that are created.
+    pub password: bool,
+    /// Don't set the hostname or update `/etc/hosts`.
+    pub hostname: bool,
+    /// Don't install SSH keys or configure sshd, e.g. for VMs only reached
+    /// with a password on an isolated network. The provisioning data can
+    /// also disable SSH; see [`ProvisioningData::ssh_disabled`].
+    pub ssh: bool,
+}
+
+/// What the platform tells us about how to provision the VM. IMDS and the
+/// OVF environment both fill in every field, whichever was read.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProvisioningData {
+    /// The admin user.
+    pub username: String,
+    pub hostname: String,
+    /// The admin user's SSH public keys, including those from custom data
+    /// when `config.custom_data_ssh_keys` is set.
+    pub ssh_keys: Vec<PublicKeys>,
+    pub password_authentication_disabled: bool,
+    /// Whether SSH is to be left unconfigured, even if keys are given;
+    /// only the OVF environment can say so.
+    pub ssh_disabled: bool,
+    /// The tags assigned to the VM; only IMDS provides them.
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Read the metadata from the sources `config.metadata_source` selects,
+/// without provisioning anything.
+///
+/// Unlike [`provision`], nothing is recorded or reported to the platform,
+/// so this is safe to call on a VM that is already provisioned, e.g. to
+/// see what azure-init would do.
+pub async fn read_metadata(
+    #[cfg(feature = "imds")] client: &Client,
+    config: &Config,
+    options: &ProvisioningOptions,
+) -> Result<ProvisioningData, Error> {
+    #[cfg(not(feature = "imds"))]
+    let client = &();
+    let mut report = ProvisioningReport::new();
+
+    get_metadata(client, config, options, &mut report).await
+}
+
+/// Provision the VM with the default [`ProvisioningOptions`] and return the
+/// report of the run.
+///
+/// This runs the same steps as the azure-init binary: query IMDS, create
+/// the users and install their SSH keys, set the hostname, and report the
+/// VM ready to the wireserver. Without the `imds` feature, the admin user
+/// and hostname are read from the OVF environment instead and nothing is
+/// reported. Callers that need the report of a failed run, or different
+/// options, should use [`provision`].
+pub async fn run_provisioning(
+    #[cfg(feature = "imds")] client: &Client,
+    config: &Config,
+) -> Result<ProvisioningReport, Error> {
+    let mut report = ProvisioningReport::new();
+    let options = ProvisioningOptions::default();
+
+    #[cfg(feature = "imds")]
+    provision(client, config, &options, &mut report).await?;
+    #[cfg(not(feature = "imds"))]
+    provision(config, &options, &mut report).await?;
+
+    Ok(report)
+}
+
+/// Provision the VM, recording each step in `report`.
+///
+/// The run is aborted with [`Error::ProvisionTimeout`] once
+/// `config.provisioning_timeout()` passes. Unless this is a dry run, a
+/// failure is reported to the wireserver along with the step that failed.
+/// `report` is finished before returning either way.
+pub async fn provision(
+    #[cfg(feature = "imds")] client: &Client,
+    config: &Config,
+    options: &ProvisioningOptions,
+    report: &mut ProvisioningReport,
+) -> Result<(), Error> {
+    provision_until(
+        #[cfg(feature = "imds")]
+        client,
+        config,
+        options,
+        report,
+        std::future::pending(),
+    )
+    .await
+}
+
+/// Provision the VM like [`provision`], but stop early once `shutdown`
+/// completes, e.g. because azure-init was asked to exit.
+///
+/// The run then fails with [`Error::ProvisionCancelled`], holding the
+/// reason `shutdown` returns, and provisioning media left mounted is
+/// unmounted. As with any failure, the cancellation is reported to the
+/// wireserver unless this is a dry run. Commands already running on a
+/// blocking thread aren't interrupted, but nothing runs after them.
+pub async fn provision_until(
+    #[cfg(feature = "imds")] client: &Client,
+    config: &Config,
+    options: &ProvisioningOptions,
+    report: &mut ProvisioningReport,
+    shutdown: impl Future<Output = String>,
+) -> Result<(), Error> {
+    #[cfg(not(feature = "imds"))]
+    let client = &();
+    let dry_run = options.runner.is_dry_run();
+    report.dry_run = dry_run;
+
+    // Bound how long provisioning can delay boot, however it gets stuck.
+    let timeout = config.provisioning_timeout();
+    let outcome = tokio::select! {
+        result = tokio::time::timeout(
+            timeout,
+            provision_steps(client, config, options, report),
+        ) => Ok(result),
+        reason = shutdown => Err(reason),
+    };
+    let result = match outcome {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => {
+            report.record("provision", Err(Error::ProvisionTimeout { timeout }))
+        }
+        Err(reason) => {
+            tracing::warn!(reason, "Provisioning cancelled, cleaning up");
+            // The media is mounted in dry runs too, so it is always
+            // cleaned up.
+            let mount_point = Path::new(media::PATH_MOUNT_POINT);
+            if let Err(error) =
+                media::clean_up_mount_point(mount_point, &options.runner)
+            {
+                tracing::warn!(
+                    ?error,
+                    "Failed to clean up the provisioning media"
+                );
+            }
+            report
+                .record("provision", Err(Error::ProvisionCancelled { reason }))
+        }
+    };
+
+    #[cfg(feature = "imds")]
+    if let Err(error) = &result {
+        if !dry_run {
+            report_failure(client, config, report, error).await;
+        }
+    }
+    report.files = options.runner.written_files();
+    report.finish(result.is_ok());
+
+    result
+}
+
+async fn provision_steps(
+    client: &Client,
+    config: &Config,
+    options: &ProvisioningOptions,
+    report: &mut ProvisioningReport,
+) -> Result<(), Error> {
+    let runner = &options.runner;
+    let dry_run = runner.is_dry_run();
+
+    let ProvisioningData {
+        username,
+        hostname,
+        ssh_keys: admin_keys,
+        password_authentication_disabled,
+        ssh_disabled,
+        ..
+    } = get_metadata(client, config, options, report).await?;
+    report.username = Some(username.clone());
+
+    // SSH disabled by the platform is skipped as if the configuration said
+    // so.
+    let ssh_config;
+    let config = if ssh_disabled && !config.skip.ssh {
+        ssh_config = Config {
+            skip: SkipOptions {
+                ssh: true,
+                ..config.skip.clone()
+            },
+            ..config.clone()
+        };
+        &ssh_config
+    } else {
+        config
+    };
+    if config.skip.ssh {
+        tracing::info!(
+            platform = ssh_disabled,
+            keys = admin_keys.len(),
+            "SSH is disabled, not installing SSH keys or configuring sshd"
+        );
+    }
+
+    // The rest of provisioning only relies on the Distribution trait, so
+    // it works the same for distributions defined outside libazureinit.
+    // Its useradd, passwd and hostnamectl calls are blocking, so they run on
+    // the blocking thread pool rather than stalling the async runtime.
+    let distro: Arc<dyn Distribution + Send + Sync> =
+        match (&options.distribution, config.distribution) {
+            (Some(distro), _) => distro.clone(),
+            (None, Some(distro)) => Arc::new(distro),
+            (None, None) => Arc::new(
+                report
+                    .record("detect_distribution", Distributions::detect())?,
+            ),
+        };
+
+    // always pass an empty password to the admin user
+    let mut admin =
+        User::new(username.as_str()).with_home_base(&config.home_base);
+    if let Some(groups) = &config.groups {
+        admin = admin.with_groups(groups.clone());
+    }
+
+    // Keys from the metadata come first, so they keep their paths and
+    // options when the configuration repeats them.
+    let admin_keys =
+        user::merge_ssh_keys([admin_keys, config.admin_public_keys()]);
+    let mut users = vec![(admin, String::new(), admin_keys)];
+    users.extend(config.users.iter().map(|extra| {
+        (
+            extra.user().with_home_base(&config.home_base),
+            extra.password.clone(),
+            extra.public_keys(),
+        )
+    }));
+
+    // A resource that fails to provision doesn't stop the others, except
+    // those that depend on it; the run only fails once every resource has
+    // been attempted.
+    let mut failures = Vec::new();
+    let mut admin_created = true;
+    for (user, password, keys) in users {
+        let name = user.name.clone();
+        match provision_user(
+            config, &distro, runner, user, password, keys, report,
+        )
+        .await
+        {
+            Ok(backends) if name == username => report.backends = backends,
+            Ok(_) => {}
+            Err(error) => {
+                tracing::error!(
+                    username = name,
+                    ?error,
+                    "Failed to provision user"
+                );
+                admin_created &= name != username;
+                failures
+                    .push(ResourceError::new(format!("user:{name}"), error));
+            }
+        }
+    }
+
+    // Privileges are only granted to the admin user once it exists.
+    if admin_created {
+        let result = in_phase(phase_span!("user", username), async {
+            if config.sudoers.enabled {
+                report.record(
+                    "provision_sudoers",
+                    user::provision_sudoers(
+                        &User::new(username.as_str()),
+                        config.sudoers.nopasswd,
+                        runner,
+                    ),
+                )?;
+            }
+
+            // Only lock root once the admin user exists, so the VM is never
+            // left without a way to log in.
+            if config.lock_root {
+                report
+                    .record("lock_root", user::lock_password("root", runner))?;
+            }
+
+            Ok(())
+        })
+        .await;
+        if let Err(error) = result {
+            failures.push(ResourceError::new("privileges", error));
+        }
+    }
+
+    let result = in_phase(phase_span!("ssh"), async {
+        if config.skip.ssh {
+            if config.sshd.regenerate_host_keys {
+                report.skip("regenerate_host_keys");
+            }
+            if config.sshd.manage_password_authentication {
+                report.skip("configure_sshd");
+            }
+            return Ok(());
+        }
+
+        if config.sshd.regenerate_host_keys {
+            let keygen_runner = runner.clone();
+            report.record(
+                "regenerate_host_keys",
+                tokio::task::spawn_blocking(move || {
+                    ssh::regenerate_host_keys(&keygen_runner)
+                })
+                .await?,
+            )?;
+        }
+
+        if config.sshd.manage_password_authentication {
+            report.record(
+                "configure_sshd",
+                ssh::set_password_authentication(
+                    !password_authentication_disabled,
+                    runner,
+                ),
+            )?;
+        }
+
+        Ok(())
+    })
+    .await;
+    if let Err(error) = result {
+        failures.push(ResourceError::new("sshd", error));
+    }
+
+    // Reset before the hostname is set, which may renew the DHCP lease
+    // whose client ID is derived from the machine ID.
+    if config.machine_id.reset {
+        let result = in_phase(phase_span!("machine_id"), async {
+            let (provisioners, machine_id_runner) =
+                (config.machine_id.provisioners.clone(), runner.clone());
+            report.record(
+                "reset_machine_id",
+                tokio::task::spawn_blocking(move || {
+                    machine_id::reset_machine_id(
+                        &provisioners,
+                        &machine_id_runner,
+                    )
+                })
+                .await?,
+            )
+        })
+        .await;
+        match result {
+            Ok(backend) => report.backends.machine_id_backend = Some(backend),
+            Err(error) => {
+                failures.push(ResourceError::new("machine_id", error))
+            }
+        }
+    }
+
+    if config.skip.hostname {
+        report.skip("set_hostname");
+    } else if let Err(error) =
+        provision_hostname(config, &distro, runner, &hostname, report).await
+    {
+        failures.push(ResourceError::new("hostname", error));
+    }
+
+    // The VM is only reported ready once everything is in place.
+    if !failures.is_empty() {
+        return Err(Error::ProvisionAggregate(failures));
+    }
+
+    #[cfg(feature = "imds")]
+    in_phase(phase_span!("goalstate"), async {
+        let vm_goalstate = report.record(
+            "get_goalstate",
+            goalstate::get_goalstate(client, &config.wireserver).await,
+        )?;
+        if dry_run {
+            tracing::info!("Dry run, not reporting VM health");
+            return Ok(());
+        }
+
+        report.record(
+            "report_health",
+            goalstate::report_health(client, vm_goalstate, &config.wireserver)
+                .await,
+        )
+    })
+    .await?;
+
+    tracing::info!(
+        backends = ?report.backends,
+        dry_run,
+        "Provisioning completed"
+    );
+
+    Ok(())
+}
+
+// Set the hostname, and map it in /etc/hosts if configured to.
+async fn provision_hostname(
+    config: &Config,
+    distro: &Arc<dyn Distribution + Send + Sync>,
+    runner: &Runner,
+    hostname: &str,
+    report: &mut ProvisioningReport,
+) -> Result<(), Error> {
+    let hostname = config.hostname_case.normalize(hostname);
+    report.hostname = Some(hostname.clone());
+
+    let hostname_backend = in_phase(phase_span!("hostname", hostname), async {
+        let provisioners = config.hostname_provisioners.clone();
+        let (new_hostname, hostname_runner, distro) =
+            (hostname.clone(), runner.clone(), distro.clone());
+        let hostname_backend = report.record(
+            "set_hostname",
+            tokio::task::spawn_blocking(move || {
+                distro.set_hostname(
+                    &new_hostname,
+                    &provisioners,
+                    &hostname_runner,
+                )
+            })
+            .await?,
+        )?;
+
+        if config.update_etc_hosts {
+            report.record(
+                "update_etc_hosts",
+                hostname::update_etc_hosts(&hostname, runner),
+            )?;
+        }
+
+        Ok(hostname_backend)
+    })
+    .await?;
+    report.backends.hostname_backend = Some(hostname_backend);
+
+    Ok(())
+}
+
+// Read the metadata from the sources `config.metadata_source` selects.
+#[cfg(feature = "imds")]
+async fn get_metadata(
+    client: &Client,
+    config: &Config,
+    options: &ProvisioningOptions,
+    report: &mut ProvisioningReport,
+) -> Result<ProvisioningData, Error> {
+    match config.metadata_source {
+        MetadataSource::Auto => {
+            match get_imds_metadata(client, config, options, report, true).await
+            {
+                // IMDS may never come up, but the provisioning media
+                // could still be there.
+                Err(error @ Error::ImdsWaitTimeout { .. }) => {
+                    report.fall_back_to_ovf(
+                        OvfFallbackReason::Timeout,
+                        Some(&error),
+                    );
+                    get_ovf_metadata(config, options, report).await.map_err(
+                        |ovf_error| {
+                            tracing::error!(
+                                ?ovf_error,
+                                "Unable to read the OVF environment"
+                            );
+                            error
+                        },
+                    )
+                }
+                result => result,
+            }
+        }
+        MetadataSource::Imds => {
+            get_imds_metadata(client, config, options, report, false).await
+        }
+        MetadataSource::Ovf => get_ovf_metadata(config, options, report).await,
+        MetadataSource::ConfigDrive => {
+            get_config_drive_metadata(config, report).await
+        }
+        MetadataSource::ImdsWithOvfFallback => {
+            match get_imds_metadata(client, config, options, report, false)
+                .await
+            {
+                Ok(metadata) => Ok(metadata),
+                Err(error) => {
+                    report.fall_back_to_ovf(
+                        ovf_fallback_reason(&error),
+                        Some(&error),
+                    );
+                    get_ovf_metadata(config, options, report).await
+                }
+            }
+        }
+    }
+}
+
+// Without IMDS, the OVF environment is the only source of metadata.
+#[cfg(not(feature = "imds"))]
+async fn get_metadata(
+    _client: &Client,
+    config: &Config,
+    options: &ProvisioningOptions,
+    report: &mut ProvisioningReport,
+) -> Result<ProvisioningData, Error> {
+    match config.metadata_source {
+        MetadataSource::ConfigDrive => {
+            get_config_drive_metadata(config, report).await
+        }
+        _ => get_ovf_metadata(config, options, report).await,
+    }
+}
+
+// With `password_username_from_ovf`, the username is read from the OVF
+// environment when IMDS reports password authentication is enabled.
+#[cfg(feature = "imds")]
+async fn get_imds_metadata(
+    client: &Client,
+    config: &Config,
+    options: &ProvisioningOptions,
+    report: &mut ProvisioningReport,
+    password_username_from_ovf: bool,
+) -> Result<ProvisioningData, Error> {
+    in_phase(phase_span!("imds"), async {
+        let imds_body = report.record(
+            "query_imds",
+            get_imds_body(client, config, options).await,
+        )?;
+
+        let mut data = report
+            .record("parse_imds", imds::get_provisioning_data(&imds_body))?;
+
+        // IMDS doesn't provide the admin username when password
+        // authentication is enabled, so it comes from the OVF environment
+        // on the provisioning media.
+        if password_username_from_ovf && !data.password_authentication_disabled
+        {
+            report.fall_back_to_ovf(
+                OvfFallbackReason::PasswordAuthentication,
+                None,
+            );
+            let (media, options) = (config.media.clone(), options.clone());
+            let ovf = report.record(
+                "get_username",
+                tokio::task::spawn_blocking(move || {
+                    read_ovf_env(&media, &options).map(ProvisioningData::from)
+                })
+                .await?,
+            )?;
+            data.username = ovf.username;
+            // Only the OVF environment can disable SSH, which is meant for
+            // exactly these password users.
+            data.ssh_disabled = ovf.ssh_disabled;
+        }
+
+        if config.custom_data_ssh_keys {
+            data.ssh_keys = user::merge_ssh_keys([
+                std::mem::take(&mut data.ssh_keys),
+                get_custom_data_ssh_keys(imds_body),
+            ]);
+        }
+
+        Ok(data)
+    })
+    .await
+}
+
+// The OVF environment carries no SSH keys.
+async fn get_ovf_metadata(
+    config: &Config,
+    options: &ProvisioningOptions,
+    report: &mut ProvisioningReport,
+) -> Result<ProvisioningData, Error> {
+    let (media, options) = (config.media.clone(), options.clone());
+    let environment = in_phase(phase_span!("ovf"), async {
+        report.record(
+            "read_ovf_env",
+            tokio::task::spawn_blocking(move || read_ovf_env(&media, &options))
+                .await?,
+        )
+    })
+    .await?;
+
+    Ok(environment.into())
+}
+
+async fn get_config_drive_metadata(
+    config: &Config,
+    report: &mut ProvisioningReport,
+) -> Result<ProvisioningData, Error> {
+    let media = config.media.clone();
+    let environment = in_phase(phase_span!("config_drive"), async {
+        let (dir, environment) = report.record(
+            "read_config_drive",
+            tokio::task::spawn_blocking(move || {
+                media::find_config_drive_ovf_env(&media)
+            })
+            .await?,
+        )?;
+        tracing::info!(path = %dir.display(), "Read the config drive");
+
+        Ok(environment)
+    })
+    .await?;
+
+    Ok(environment.into())
+}
+
+// The OVF environment carries no SSH keys or tags.
+impl From<Environment> for ProvisioningData {
+    fn from(environment: Environment) -> Self {
+        let settings = environment.provisioning_section.linux_prov_conf_set;
+
+        Self {
+            username: settings.username,
+            hostname: settings.hostname,
+            ssh_keys: Vec::new(),
+            password_authentication_disabled: settings
+                .disable_ssh_password_authentication,
+            ssh_disabled: settings.disable_ssh,
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+// Keys in custom data only add to those from IMDS, so custom data that
+// can't be read is only logged.
+#[cfg(feature = "imds")]
+fn get_custom_data_ssh_keys(imds_body: String) -> Vec<PublicKeys> {
+    let keys = imds::get_custom_data(imds_body)
+        .and_then(|custom_data| imds::get_custom_data_ssh_keys(&custom_data));
+    match keys {
+        Ok(keys) => keys,
+        Err(error) => {
+            tracing::warn!(?error, "Unable to read SSH keys from custom data");
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(feature = "imds")]
+async fn get_imds_body(
+    client: &Client,
+    config: &Config,
+    options: &ProvisioningOptions,
+) -> Result<String, Error> {
+    if let (Some(path), true) = (&options.imds_cache, options.read_imds_cache) {
+        tracing::info!(path = %path.display(), "Reading the cached IMDS response");
+        return imds::read_imds_cache(path);
+    }
+
+    let imds_body = imds::query_imds_with_config(
+        client,
+        &config.imds,
+        &config.http_config(),
+    )
+    .await?;
+    if let Some(path) = &options.imds_cache {
+        if let Err(error) =
+            imds::write_imds_cache(path, &imds_body, &options.runner)
+        {
+            tracing::warn!(
+                path = %path.display(),
+                ?error,
+                "Failed to cache the IMDS response"
+            );
+        }
+    }
+
+    Ok(imds_body)
+}
+
+// Why IMDS failing with `error` led to reading the OVF environment.
+#[cfg(feature = "imds")]
+fn ovf_fallback_reason(error: &Error) -> OvfFallbackReason {
+    match error {
+        Error::ImdsWaitTimeout { .. } => OvfFallbackReason::Timeout,
+        Error::Http(error) if error.is_timeout() => OvfFallbackReason::Timeout,
+        Error::Http(error) if error.is_connect() => {
+            OvfFallbackReason::ConnectionFailed
+        }
+        _ => OvfFallbackReason::ImdsError,
+    }
+}
+
+// Read the OVF environment from the first provisioning media that has one.
+// Mounting the media is blocking, so this runs on the blocking thread pool.
+fn read_ovf_env(
+    config: &MediaConfig,
+    options: &ProvisioningOptions,
+) -> Result<Environment, Error> {
+    #[cfg(test)]
+    if let Some(dir) = &options.ovf_env_dir {
+        return media::parse_ovf_env_from_path(dir);
+    }
+
+    // list of CDROM devices that is available with possible filesystems.
+    let ovf_devices = media::get_mount_device(config.scan_timeout())?;
+    let (_, environment) = media::find_ovf_env_with_config(
+        &ovf_devices,
+        Path::new(media::PATH_MOUNT_POINT),
+        config,
+        &options.runner,
+    )?;
+
+    Ok(environment)
+}
+
+// Create `user` and install its SSH public keys, recording each step under
+// the user's name.
+async fn provision_user(
+    config: &Config,
+    distro: &Arc<dyn Distribution + Send + Sync>,
+    runner: &Runner,
+    user: User,
+    password: String,
+    keys: Vec<PublicKeys>,
+    report: &mut ProvisioningReport,
+) -> Result<ProvisionReport, Error> {
+    let username = user.name.clone();
+    let default_home = user.home_dir()?;
+
+    let backends = if config.skip.user {
+        report.skip(&format!("create_user:{username}"));
+        ProvisionReport::default()
+    } else {
+        if config.skip.password {
+            report.skip(&format!("set_password:{username}"));
+        }
+        let password = (!config.skip.password).then_some(password);
+        let user_runner = runner.clone();
+        let user_distro = distro.clone();
+        let user_provisioners = config.user_provisioners.clone();
+        in_phase(phase_span!("user", username), async {
+            report.record(
+                &format!("create_user:{username}"),
+                tokio::task::spawn_blocking(move || {
+                    user_distro.create_user(
+                        &user,
+                        password.as_deref(),
+                        &user_provisioners,
+                        &user_runner,
+                    )
+                })
+                .await?,
+            )
+        })
+        .await?
+    };
+
+    if config.skip.ssh {
+        report.skip(&format!("set_ssh_keys:{username}"));
+        return Ok(backends);
+    }
+
+    in_phase(phase_span!("ssh", username), async {
+        let dry_run = runner.is_dry_run();
+        let home = actual_home_dir(&username, default_home, runner)?;
+        // Keys written outside the home directory don't need a .ssh
+        // directory.
+        if !dry_run && config.ssh.authorized_keys_dir.is_none() {
+            report.record(
+                &format!("create_ssh_directory:{username}"),
+                user::create_ssh_directory(username.as_str(), &home, runner)
+                    .await,
+            )?;
+        }
+
+        let ssh_dir = home.join(".ssh");
+        if dry_run {
+            for key in &keys {
+                let path =
+                    runner.path(config.ssh.key_path(key, &username, &ssh_dir));
+                tracing::info!(
+                    path = %path.display(),
+                    "Dry run, not writing ssh public key"
+                );
+            }
+        } else {
+            let written = report.record(
+                &format!("set_ssh_keys:{username}"),
+                user::set_ssh_keys(
+                    keys,
+                    username.clone(),
+                    ssh_dir.to_string_lossy().into_owned(),
+                    &config.ssh,
+                    runner,
+                )
+                .await,
+            )?;
+            tracing::info!(username, written, "Wrote ssh public keys");
+        }
+
+        Ok(())
+    })
+    .await?;
+
+    Ok(backends)
+}
+
+// The home directory of `username` in the passwd database, which differs
+// from `default_home` if the user already existed or the user backend
+// placed it elsewhere. A user that doesn't exist yet, as in a dry run, is
+// assumed to get `default_home`.
+fn actual_home_dir(
+    username: &str,
+    default_home: PathBuf,
+    runner: &Runner,
+) -> Result<PathBuf, Error> {
+    match user::get_user_by_name(username, runner)? {
+        Some(entry) if entry.dir != default_home => {
+            tracing::info!(
+                username,
+                home = %entry.dir.display(),
+                "Using the user's existing home directory"
+            );
+            Ok(entry.dir)
+        }
+        Some(entry) => Ok(entry.dir),
+        None => Ok(default_home),
+    }
+}
+
+// Tell the wireserver provisioning failed, naming the step that failed.
+#[cfg(feature = "imds")]
+async fn report_failure(
+    client: &Client,
+    config: &Config,
+    report: &mut ProvisioningReport,
+    error: &Error,
+) {
+    let step = report.failed_step().unwrap_or("provision").to_string();
+    let details = format!("Provisioning failed at step '{step}': {error}");
+
+    let result =
+        match goalstate::get_goalstate(client, &config.wireserver).await {
+            Ok(vm_goalstate) => {
+                goalstate::report_failure(
+                    client,
+                    vm_goalstate,
+                    &details,
+                    &config.wireserver,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+    if let Err(error) = report.record("report_failure", result) {
+        tracing::warn!(?error, "Failed to report provisioning failure");
+    }
+}
+
+#[cfg(all(test, feature = "imds"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use reqwest::Client;
+
+    use super::{
+        actual_home_dir, get_metadata, provision, provision_until,
+        read_metadata, MetadataSource, ProvisioningOptions,
+    };
+    use crate::command::Runner;
+    use crate::config::{Config, UserConfig};
+    use crate::distro::{Distribution, ProvisionReport};
+    use crate::error::Error;
+    use crate::http::test_server::{http_response, serve_responses};
+    use crate::report::{OvfFallbackReason, ProvisioningReport};
+    use crate::user::{self, User};
+    use crate::{hostname, machine_id};
+
+    const IMDS_BODY: &str = r#"{
+        "compute": {
+            "osProfile": {
+                "adminUsername": "azureuser",
+                "computerName": "test-hostname",
+                "disablePasswordAuthentication": "true"
+            },
+            "publicKeys": []
+        }
+    }"#;
+
+    const GOALSTATE_XML: &str = "<Goalstate>
+        <Container>
+            <ContainerId>2</ContainerId>
+            <RoleInstanceList>
+                <RoleInstance>
+                    <InstanceId>test_instance_id</InstanceId>
+                </RoleInstance>
+            </RoleInstanceList>
+        </Container>
+        <Version>example_version</Version>
+        <Incarnation>1</Incarnation>
+    </Goalstate>";
+
+    // Records the users it is asked to create, failing for `fail_user`, and
+    // fails to set the hostname if `fail_hostname` is set.
+    #[derive(Default)]
+    struct RecordingDistribution {
+        users: Mutex<Vec<String>>,
+        fail_user: Option<String>,
+        fail_hostname: bool,
+    }
+
+    impl Distribution for RecordingDistribution {
+        fn create_user(
+            &self,
+            user: &User,
+            _password: Option<&str>,
+            provisioners: &[user::Provisioner],
+            _runner: &Runner,
+        ) -> Result<ProvisionReport, Error> {
+            self.users.lock().unwrap().push(user.name.clone());
+            if self.fail_user.as_ref() == Some(&user.name) {
+                return Err(Error::UserExists {
+                    user: user.name.clone(),
+                });
+            }
+            Ok(ProvisionReport {
+                user_backend: provisioners.first().copied(),
+                ..Default::default()
+            })
+        }
+
+        fn set_hostname(
+            &self,
+            _hostname: &str,
+            provisioners: &[hostname::Provisioner],
+            _runner: &Runner,
+        ) -> Result<hostname::Provisioner, Error> {
+            if self.fail_hostname {
+                return Err(Error::NoProvisioners {
+                    resource: "hostname".to_string(),
+                });
+            }
+            Ok(provisioners[0])
+        }
+    }
+
+    async fn test_config(with_goalstate: bool) -> Config {
+        let mut config = Config::default();
+        config.imds.endpoint =
+            serve_responses(vec![http_response("200 OK", IMDS_BODY)]).await;
+        let goalstate = match with_goalstate {
+            true => vec![http_response("200 OK", GOALSTATE_XML)],
+            false => Vec::new(),
+        };
+        config.wireserver.endpoint = serve_responses(goalstate).await;
+        config.users = vec![UserConfig {
+            name: "svc-backup".to_string(),
+            groups: None,
+            shell: None,
+            uid: None,
+            password: String::new(),
+            ssh_keys: Vec::new(),
+        }];
+        config
+    }
+
+    #[tokio::test]
+    async fn test_provision_dry_run() {
+        let mut config = test_config(true).await;
+        config.lock_root = true;
+        config.sshd.manage_password_authentication = true;
+        config.sshd.regenerate_host_keys = true;
+        config.machine_id.reset = true;
+        let distro = Arc::new(RecordingDistribution::default());
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            distribution: Some(distro.clone()),
+            ..Default::default()
+        };
+        let mut report = ProvisioningReport::new();
+
+        provision(&Client::new(), &config, &options, &mut report)
+            .await
+            .unwrap();
+
+        assert!(report.success);
+        assert!(report.dry_run);
+        assert_eq!(report.username.as_deref(), Some("azureuser"));
+        assert_eq!(report.hostname.as_deref(), Some("test-hostname"));
+        assert_eq!(
+            report.backends.user_backend,
+            Some(user::Provisioner::Useradd)
+        );
+        assert_eq!(
+            report.backends.machine_id_backend,
+            Some(machine_id::Provisioner::SystemdMachineIdSetup)
+        );
+        assert_eq!(*distro.users.lock().unwrap(), ["azureuser", "svc-backup"]);
+        let steps: Vec<&str> =
+            report.steps.iter().map(|step| step.name.as_str()).collect();
+        assert_eq!(
+            steps,
+            [
+                "query_imds",
+                "parse_imds",
+                "create_user:azureuser",
+                "create_user:svc-backup",
+                "lock_root",
+                "regenerate_host_keys",
+                "configure_sshd",
+                "reset_machine_id",
+                "set_hostname",
+                "get_goalstate",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_provision_skip_ssh() {
+        let mut config = test_config(true).await;
+        config.skip.ssh = true;
+        config.sshd.manage_password_authentication = true;
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            distribution: Some(Arc::new(RecordingDistribution::default())),
+            ..Default::default()
+        };
+        let mut report = ProvisioningReport::new();
+
+        provision(&Client::new(), &config, &options, &mut report)
+            .await
+            .unwrap();
+
+        assert!(report.success);
+        let skipped: Vec<&str> = report
+            .steps
+            .iter()
+            .filter(|step| step.skipped)
+            .map(|step| step.name.as_str())
+            .collect();
+        assert_eq!(
+            skipped,
+            [
+                "set_ssh_keys:azureuser",
+                "set_ssh_keys:svc-backup",
+                "configure_sshd",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_provision_continues_after_failures() {
+        let mut config = test_config(false).await;
+        config.lock_root = true;
+        let distro = Arc::new(RecordingDistribution {
+            fail_user: Some("azureuser".to_string()),
+            fail_hostname: true,
+            ..Default::default()
+        });
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            distribution: Some(distro.clone()),
+            ..Default::default()
+        };
+        let mut report = ProvisioningReport::new();
+
+        match provision(&Client::new(), &config, &options, &mut report).await {
+            Err(Error::ProvisionAggregate(failures)) => {
+                let resources: Vec<&str> = failures
+                    .iter()
+                    .map(|failure| failure.resource.as_str())
+                    .collect();
+                assert_eq!(resources, ["user:azureuser", "hostname"]);
+            }
+            _ => panic!("Failed resources should fail provisioning"),
+        }
+
+        assert!(!report.success);
+        assert_eq!(*distro.users.lock().unwrap(), ["azureuser", "svc-backup"]);
+        assert_eq!(report.failed_step(), Some("set_hostname"));
+        // Root stays unlocked when the admin user couldn't be created.
+        assert!(!report.steps.iter().any(|step| step.name == "lock_root"));
+    }
+
+    #[tokio::test]
+    async fn test_provision_until_shutdown() {
+        let config = test_config(false).await;
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            distribution: Some(Arc::new(RecordingDistribution::default())),
+            ..Default::default()
+        };
+        let mut report = ProvisioningReport::new();
+
+        let result = provision_until(
+            &Client::new(),
+            &config,
+            &options,
+            &mut report,
+            std::future::ready("received SIGTERM".to_string()),
+        )
+        .await;
+
+        match result {
+            Err(Error::ProvisionCancelled { reason }) => {
+                assert_eq!(reason, "received SIGTERM")
+            }
+            _ => panic!("Provisioning should stop on shutdown"),
+        }
+        assert!(!report.success);
+        assert_eq!(report.failed_step(), Some("provision"));
+    }
+
+    #[tokio::test]
+    async fn test_provision_lowercases_hostname() {
+        let mut config = test_config(true).await;
+        let imds_body = IMDS_BODY.replace("test-hostname", "Test-Hostname");
+        config.imds.endpoint =
+            serve_responses(vec![http_response("200 OK", &imds_body)]).await;
+        config.hostname_case = hostname::Case::Lowercase;
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            distribution: Some(Arc::new(RecordingDistribution::default())),
+            ..Default::default()
+        };
+        let mut report = ProvisioningReport::new();
+
+        provision(&Client::new(), &config, &options, &mut report)
+            .await
+            .unwrap();
+
+        assert_eq!(report.hostname.as_deref(), Some("test-hostname"));
+    }
+
+    #[tokio::test]
+    async fn test_provision_skips_phases() {
+        let mut config = test_config(true).await;
+        config.skip.user = true;
+        config.skip.hostname = true;
+        let distro = Arc::new(RecordingDistribution::default());
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            distribution: Some(distro.clone()),
+            ..Default::default()
+        };
+        let mut report = ProvisioningReport::new();
+
+        provision(&Client::new(), &config, &options, &mut report)
+            .await
+            .unwrap();
+
+        assert!(distro.users.lock().unwrap().is_empty());
+        assert_eq!(report.hostname, None);
+        assert_eq!(report.backends, ProvisionReport::default());
+        let skipped: Vec<&str> = report
+            .steps
+            .iter()
+            .filter(|step| step.skipped)
+            .map(|step| step.name.as_str())
+            .collect();
+        assert_eq!(
+            skipped,
+            [
+                "create_user:azureuser",
+                "create_user:svc-backup",
+                "set_hostname"
+            ]
+        );
+
+        // The test servers only answer once.
+        let mut config = test_config(true).await;
+        config.skip.password = true;
+        let mut report = ProvisioningReport::new();
+
+        provision(&Client::new(), &config, &options, &mut report)
+            .await
+            .unwrap();
+
+        let skipped: Vec<&str> = report
+            .steps
+            .iter()
+            .filter(|step| step.skipped)
+            .map(|step| step.name.as_str())
+            .collect();
+        assert_eq!(
+            skipped,
+            ["set_password:azureuser", "set_password:svc-backup"]
+        );
+    }
+
+    const PASSWORD_IMDS_BODY: &str = r#"{
+        "compute": {
+            "osProfile": {
+                "adminUsername": "azureuser",
+                "computerName": "test-hostname",
+                "disablePasswordAuthentication": "false"
+            },
+            "publicKeys": []
+        }
+    }"#;
+
+    // Provision in dry-run mode from `source`, returning the result and the
+    // steps that ran.
+    async fn provision_from(
+        source: MetadataSource,
+        imds_responses: Vec<String>,
+    ) -> (Result<(), Error>, Vec<String>) {
+        let config = Config {
+            metadata_source: source,
+            ..Default::default()
+        };
+        provision_with(config, imds_responses).await
+    }
+
+    async fn provision_with(
+        mut config: Config,
+        imds_responses: Vec<String>,
+    ) -> (Result<(), Error>, Vec<String>) {
+        config.imds.endpoint = serve_responses(imds_responses).await;
+        config.imds.retry.max_attempts = 1;
+        config.wireserver.endpoint =
+            serve_responses(vec![http_response("200 OK", GOALSTATE_XML)]).await;
+        // Without an OVF environment, reading it fails.
+        let media = tempfile::tempdir().unwrap();
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            distribution: Some(Arc::new(RecordingDistribution::default())),
+            ovf_env_dir: Some(media.path().into()),
+            ..Default::default()
+        };
+        let mut report = ProvisioningReport::new();
+
+        let result =
+            provision(&Client::new(), &config, &options, &mut report).await;
+        let steps = report.steps.into_iter().map(|step| step.name).collect();
+
+        (result, steps)
+    }
+
+    #[tokio::test]
+    async fn test_metadata_source_auto_reads_ovf_for_password_users() {
+        let (result, steps) = provision_from(
+            MetadataSource::Auto,
+            vec![http_response("200 OK", PASSWORD_IMDS_BODY)],
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(steps, ["query_imds", "parse_imds", "get_username"]);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_source_auto_keeps_ovf_disable_ssh() {
+        let media = tempfile::tempdir().unwrap();
+        std::fs::write(
+            media.path().join("ovf-env.xml"),
+            r#"<Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+                xmlns:wa="http://schemas.microsoft.com/windowsazure">
+                <wa:ProvisioningSection>
+                    <wa:Version>1.0</wa:Version>
+                    <LinuxProvisioningConfigurationSet>
+                        <UserName>ovfuser</UserName>
+                        <HostName>ovf-hostname</HostName>
+                        <DisableSsh>true</DisableSsh>
+                    </LinuxProvisioningConfigurationSet>
+                </wa:ProvisioningSection>
+                <wa:PlatformSettingsSection>
+                    <wa:Version>1.0</wa:Version>
+                    <PlatformSettings></PlatformSettings>
+                </wa:PlatformSettingsSection>
+            </Environment>"#,
+        )
+        .unwrap();
+        let mut config = Config {
+            metadata_source: MetadataSource::Auto,
+            ..Default::default()
+        };
+        config.imds.endpoint =
+            serve_responses(vec![http_response("200 OK", PASSWORD_IMDS_BODY)])
+                .await;
+        config.imds.retry.max_attempts = 1;
+        config.wireserver.endpoint =
+            serve_responses(vec![http_response("200 OK", GOALSTATE_XML)]).await;
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            distribution: Some(Arc::new(RecordingDistribution::default())),
+            ovf_env_dir: Some(media.path().into()),
+            ..Default::default()
+        };
+        let mut report = ProvisioningReport::new();
+
+        provision(&Client::new(), &config, &options, &mut report)
+            .await
+            .unwrap();
+
+        let ran: Vec<_> = report
+            .steps
+            .iter()
+            .filter(|step| !step.skipped)
+            .map(|step| step.name.as_str())
+            .collect();
+        assert!(ran.contains(&"get_username"));
+        assert!(ran.contains(&"create_user:ovfuser"));
+        let skipped: Vec<_> = report
+            .steps
+            .iter()
+            .filter(|step| step.skipped)
+            .map(|step| step.name.as_str())
+            .collect();
+        assert!(skipped.contains(&"set_ssh_keys:ovfuser"));
+    }
+
+    #[tokio::test]
+    async fn test_metadata_source_imds_never_reads_ovf() {
+        let (result, steps) = provision_from(
+            MetadataSource::Imds,
+            vec![http_response("200 OK", PASSWORD_IMDS_BODY)],
+        )
+        .await;
+
+        result.unwrap();
+        assert_eq!(steps[..2], ["query_imds", "parse_imds"]);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_source_ovf_never_queries_imds() {
+        let (result, steps) = provision_from(
+            MetadataSource::Ovf,
+            vec![http_response("200 OK", IMDS_BODY)],
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(steps, ["read_ovf_env"]);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_source_falls_back_to_ovf() {
+        let (result, steps) =
+            provision_from(MetadataSource::ImdsWithOvfFallback, Vec::new())
+                .await;
+
+        assert!(result.is_err());
+        assert_eq!(steps, ["query_imds", "read_ovf_env"]);
+
+        let (result, steps) = provision_from(
+            MetadataSource::ImdsWithOvfFallback,
+            vec![http_response("200 OK", PASSWORD_IMDS_BODY)],
+        )
+        .await;
+
+        result.unwrap();
+        assert!(!steps.iter().any(|step| step == "read_ovf_env"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_imds_falls_back_to_ovf() {
+        let mut config = Config::default();
+        config.imds.wait = Some(std::time::Duration::from_millis(50));
+
+        let (result, steps) = provision_with(config, Vec::new()).await;
+
+        match result {
+            Err(Error::ImdsWaitTimeout { .. }) => {}
+            _ => panic!("The IMDS timeout should be reported without OVF"),
+        }
+        assert_eq!(steps, ["query_imds", "read_ovf_env"]);
+    }
+
+    #[tokio::test]
+    async fn test_ovf_fallback_is_reported() {
+        // Nothing listens on a port that was just released.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config {
+            metadata_source: MetadataSource::ImdsWithOvfFallback,
+            ..Default::default()
+        };
+        config.imds.endpoint =
+            format!("http://{}", listener.local_addr().unwrap());
+        config.imds.retry.max_attempts = 1;
+        drop(listener);
+        // Without an OVF environment, reading it fails.
+        let media = tempfile::tempdir().unwrap();
+        let options = ProvisioningOptions {
+            runner: Runner::new().dry_run(true),
+            ovf_env_dir: Some(media.path().into()),
+            ..Default::default()
+        };
+
+        let mut report = ProvisioningReport::new();
+        let result =
+            get_metadata(&Client::new(), &config, &options, &mut report).await;
+        assert!(result.is_err());
+        assert_eq!(
+            report.ovf_fallback,
+            Some(OvfFallbackReason::ConnectionFailed)
+        );
+
+        config.metadata_source = MetadataSource::Auto;
+        config.imds.endpoint =
+            serve_responses(vec![http_response("200 OK", PASSWORD_IMDS_BODY)])
+                .await;
+        let mut report = ProvisioningReport::new();
+        let result =
+            get_metadata(&Client::new(), &config, &options, &mut report).await;
+        assert!(result.is_err());
+        assert_eq!(
+            report.ovf_fallback,
+            Some(OvfFallbackReason::PasswordAuthentication)
+        );
+
+        config.imds.endpoint =
+            serve_responses(vec![http_response("200 OK", IMDS_BODY)]).await;
+        let mut report = ProvisioningReport::new();
+        get_metadata(&Client::new(), &config, &options, &mut report)
+            .await
+            .unwrap();
+        assert_eq!(report.ovf_fallback, None);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_source_config_drive() {
+        let config_drive = tempfile::tempdir().unwrap();
+        std::fs::write(
+            config_drive.path().join("ovf-env.xml"),
+            r#"<Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+                xmlns:wa="http://schemas.microsoft.com/windowsazure">
+                <wa:ProvisioningSection>
+                    <wa:Version>1.0</wa:Version>
+                    <LinuxProvisioningConfigurationSet>
+                        <UserName>driveuser</UserName>
+                        <HostName>drive-hostname</HostName>
+                    </LinuxProvisioningConfigurationSet>
+                </wa:ProvisioningSection>
+                <wa:PlatformSettingsSection>
+                    <wa:Version>1.0</wa:Version>
+                    <PlatformSettings></PlatformSettings>
+                </wa:PlatformSettingsSection>
+            </Environment>"#,
+        )
+        .unwrap();
+        let mut config = Config {
+            metadata_source: MetadataSource::ConfigDrive,
+            ..Default::default()
+        };
+        config.media.config_drive_paths = vec![config_drive.path().into()];
+
+        let (result, steps) = provision_with(config, Vec::new()).await;
+
+        result.unwrap();
+        assert_eq!(steps[..2], ["read_config_drive", "create_user:driveuser"]);
+    }
+
+    #[tokio::test]
+    async fn test_read_metadata() {
+        let imds_body = IMDS_BODY.replace(
+            r#""publicKeys": []"#,
+            r#""publicKeys": [], "tagsList": [{"name": "env", "value": "test"}]"#,
+        );
+        let mut config = Config {
+            metadata_source: MetadataSource::Imds,
+            ..Default::default()
+        };
+        config.imds.endpoint =
+            serve_responses(vec![http_response("200 OK", &imds_body)]).await;
+
+        let metadata = read_metadata(
+            &Client::new(),
+            &config,
+            &ProvisioningOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(metadata.username, "azureuser");
+        assert_eq!(metadata.hostname, "test-hostname");
+        assert!(metadata.password_authentication_disabled);
+        assert_eq!(metadata.tags.get("env").map(String::as_str), Some("test"));
+    }
+
+    #[tokio::test]
+    async fn test_read_metadata_merges_custom_data_keys() {
+        use base64::Engine;
+
+        let custom_data = r#"{"ssh_authorized_keys": [
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA copy@example",
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIB custom@example"
+        ]}"#;
+        let imds_body = IMDS_BODY.replace(
+            r#""publicKeys": []"#,
+            &format!(
+                r#""publicKeys": [{{
+                    "keyData": "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA imds",
+                    "path": "/home/azureuser/.ssh/authorized_keys"
+                }}], "customData": "{}""#,
+                base64::engine::general_purpose::STANDARD.encode(custom_data)
+            ),
+        );
+        let mut config = Config {
+            metadata_source: MetadataSource::Imds,
+            custom_data_ssh_keys: true,
+            ..Default::default()
+        };
+        config.imds.endpoint =
+            serve_responses(vec![http_response("200 OK", &imds_body)]).await;
+
+        let metadata = read_metadata(
+            &Client::new(),
+            &config,
+            &ProvisioningOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        let keys: Vec<(&str, &str)> = metadata
+            .ssh_keys
+            .iter()
+            .map(|key| (key.key_data.as_str(), key.path.as_str()))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (
+                    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA imds",
+                    "/home/azureuser/.ssh/authorized_keys"
+                ),
+                ("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIB custom@example", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_actual_home_dir() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("etc")).unwrap();
+        std::fs::write(
+            root.path().join("etc/passwd"),
+            "existing:x:1000:1000::/srv/existing:/bin/sh\n",
+        )
+        .unwrap();
+        let runner = Runner::new().root_dir(root.path());
+
+        assert_eq!(
+            actual_home_dir("existing", "/home/existing".into(), &runner)
+                .unwrap(),
+            std::path::Path::new("/srv/existing")
+        );
+        assert_eq!(
+            actual_home_dir("azureuser", "/home/azureuser".into(), &runner)
+                .unwrap(),
+            std::path::Path::new("/home/azureuser")
+        );
+    }
+}
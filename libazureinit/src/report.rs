@@ -0,0 +1,291 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::distro::ProvisionReport;
+use crate::error::Error;
+use crate::util::WrittenFile;
+
+pub const DEFAULT_REPORT_PATH: &str = "/var/lib/azure-init/report.json";
+
+/// The outcome of a single provisioning step.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct StepReport {
+    pub name: String,
+    pub success: bool,
+    /// Whether the step was left out by configuration rather than run.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Why provisioning read the OVF environment instead of relying on IMDS.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OvfFallbackReason {
+    /// IMDS didn't respond in time.
+    Timeout,
+    /// IMDS couldn't be connected to, e.g. because the connection was
+    /// refused.
+    ConnectionFailed,
+    /// IMDS responded, but with an error or a response that couldn't be
+    /// used.
+    ImdsError,
+    /// Password authentication is enabled, and IMDS doesn't provide the
+    /// admin username in that case.
+    PasswordAuthentication,
+}
+
+impl Display for OvfFallbackReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OvfFallbackReason::Timeout => "timeout",
+            OvfFallbackReason::ConnectionFailed => "connection_failed",
+            OvfFallbackReason::ImdsError => "imds_error",
+            OvfFallbackReason::PasswordAuthentication => {
+                "password_authentication"
+            }
+        })
+    }
+}
+
+/// A machine-readable summary of a provisioning run, meant to be written to
+/// disk with [`ProvisioningReport::write`] and parsed by tooling.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvisioningReport {
+    /// When provisioning started, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub duration_ms: u64,
+    pub success: bool,
+    /// Whether this was a dry run, in which no changes were made.
+    pub dry_run: bool,
+    /// Identifies the run in logs, and in the platform's logs through the
+    /// `x-ms-client-request-id` header sent with each request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    pub username: Option<String>,
+    pub hostname: Option<String>,
+    pub backends: ProvisionReport,
+    /// Why the OVF environment was read in place of IMDS, if it was.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ovf_fallback: Option<OvfFallbackReason>,
+    pub steps: Vec<StepReport>,
+    /// Files provisioning created or modified, with their sizes and
+    /// checksums.
+    pub files: Vec<WrittenFile>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Default for ProvisioningReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProvisioningReport {
+    /// Start a report, recording the current time as the start of the run.
+    pub fn new() -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            timestamp,
+            duration_ms: 0,
+            success: false,
+            dry_run: false,
+            correlation_id: None,
+            username: None,
+            hostname: None,
+            backends: ProvisionReport::default(),
+            ovf_fallback: None,
+            steps: Vec::new(),
+            files: Vec::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Record the outcome of the step `name` and pass `result` through, so
+    /// callers can wrap a step without changing its control flow.
+    pub fn record<T, E: Display>(
+        &mut self,
+        name: &str,
+        result: Result<T, E>,
+    ) -> Result<T, E> {
+        self.steps.push(StepReport {
+            name: name.to_string(),
+            success: result.is_ok(),
+            skipped: false,
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+        });
+        result
+    }
+
+    /// Record that the step `name` was skipped. Skipped steps count as
+    /// successful.
+    pub fn skip(&mut self, name: &str) {
+        tracing::info!(step = name, "Skipping step");
+        self.steps.push(StepReport {
+            name: name.to_string(),
+            success: true,
+            skipped: true,
+            error: None,
+        });
+    }
+
+    /// Record that provisioning fell back from IMDS to the OVF environment
+    /// for `reason`, after `error` if IMDS failed.
+    ///
+    /// The fallback is logged as an `ovf_fallback` event, so fleets where
+    /// IMDS is often unavailable can be spotted.
+    pub fn fall_back_to_ovf(
+        &mut self,
+        reason: OvfFallbackReason,
+        error: Option<&Error>,
+    ) {
+        tracing::warn!(
+            ovf_fallback = %reason,
+            ?error,
+            "Falling back from IMDS to the OVF environment"
+        );
+        self.ovf_fallback = Some(reason);
+    }
+
+    /// The name of the most recent step that failed, if any.
+    pub fn failed_step(&self) -> Option<&str> {
+        self.steps
+            .iter()
+            .rev()
+            .find(|step| !step.success)
+            .map(|step| step.name.as_str())
+    }
+
+    /// Mark the run as finished, recording its duration and overall outcome.
+    pub fn finish(&mut self, success: bool) {
+        self.duration_ms = self.started.elapsed().as_millis() as u64;
+        self.success = success;
+    }
+
+    /// Write the report as JSON to `path`.
+    ///
+    /// The report is written to a temporary file in the same directory and
+    /// renamed into place, so readers never see a partially written report.
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file_name = path.file_name().unwrap_or_default().to_owned();
+        file_name.push(".tmp");
+        let tmp_path = path.with_file_name(file_name);
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{OvfFallbackReason, ProvisioningReport};
+    use crate::error::Error;
+    use crate::hostname::Provisioner;
+    use crate::util::WrittenFile;
+
+    #[test]
+    fn test_record_passes_result_through() {
+        let mut report = ProvisioningReport::new();
+
+        let ok: Result<u32, String> = report.record("first", Ok(1));
+        assert_eq!(ok, Ok(1));
+        let err: Result<u32, String> =
+            report.record("second", Err("boom".to_string()));
+        assert_eq!(err, Err("boom".to_string()));
+
+        assert_eq!(report.steps.len(), 2);
+        assert!(report.steps[0].success);
+        assert_eq!(report.steps[0].error, None);
+        assert!(!report.steps[1].success);
+        assert_eq!(report.steps[1].error.as_deref(), Some("boom"));
+        assert_eq!(report.failed_step(), Some("second"));
+    }
+
+    #[test]
+    fn test_skip() {
+        let mut report = ProvisioningReport::new();
+
+        report.skip("set_hostname");
+
+        assert!(report.steps[0].success);
+        assert!(report.steps[0].skipped);
+        assert_eq!(report.failed_step(), None);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["steps"][0]["skipped"], true);
+    }
+
+    #[test]
+    fn test_fall_back_to_ovf() {
+        let mut report = ProvisioningReport::new();
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json.get("ovf_fallback").is_none());
+
+        report.fall_back_to_ovf(
+            OvfFallbackReason::Timeout,
+            Some(&Error::ImdsWaitTimeout {
+                wait: std::time::Duration::from_secs(1),
+            }),
+        );
+
+        assert_eq!(report.ovf_fallback, Some(OvfFallbackReason::Timeout));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["ovf_fallback"], "timeout");
+    }
+
+    #[test]
+    fn test_write_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("report.json");
+
+        let mut report = ProvisioningReport::new();
+        report.username = Some("azureuser".to_string());
+        report.correlation_id = Some("test-correlation-id".to_string());
+        report.backends.hostname_backend = Some(Provisioner::EtcHostname);
+        let _ = report.record::<(), String>("set_hostname", Ok(()));
+        report.files.push(WrittenFile::new(
+            std::path::Path::new("/etc/hostname"),
+            b"vm\n",
+        ));
+        report.finish(true);
+        report.write(&path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["success"], true);
+        assert_eq!(json["username"], "azureuser");
+        assert_eq!(json["correlation_id"], "test-correlation-id");
+        assert_eq!(json["backends"]["hostname_backend"], "etc_hostname");
+        assert_eq!(json["steps"][0]["name"], "set_hostname");
+        assert!(json["steps"][0].get("error").is_none());
+        assert!(json["steps"][0].get("skipped").is_none());
+        assert_eq!(json["files"][0]["path"], "/etc/hostname");
+        assert_eq!(json["files"][0]["size"], 3);
+        assert_eq!(json["files"][0]["sha256"].as_str().unwrap().len(), 64);
+
+        // Only the report itself is left behind.
+        let entries: Vec<_> =
+            fs::read_dir(path.parent().unwrap()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::Deserialize;
+
+use crate::command::Runner;
+use crate::error::Error;
+
+/// The sshd drop-in file azure-init manages.
+///
+/// Drop-ins are only read if the main `sshd_config` includes
+/// `/etc/ssh/sshd_config.d/*.conf`, as most distributions' defaults do.
+pub const PATH_SSHD_DROP_IN: &str = "/etc/ssh/sshd_config.d/50-azure-init.conf";
+
+/// Where sshd's host keys are kept, relative to the root directory.
+const HOST_KEYS_DIR: &str = "etc/ssh";
+
+/// Controls how provisioning configures the SSH daemon.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshdOptions {
+    /// Set `PasswordAuthentication` in the sshd drop-in to match whether
+    /// the platform allows password authentication, rather than leaving it
+    /// to the image's default.
+    pub manage_password_authentication: bool,
+    /// Replace the image's host keys with new ones, so VMs cloned from the
+    /// same image don't share a host identity.
+    pub regenerate_host_keys: bool,
+}
+
+/// Remove the existing `/etc/ssh/ssh_host_*` keys and generate a new key
+/// of each default type, including ECDSA and ed25519, with
+/// `ssh-keygen -A`.
+///
+/// If ssh-keygen fails, the error holds its output.
+pub fn regenerate_host_keys(runner: &Runner) -> Result<(), Error> {
+    regenerate_host_keys_in(runner.root(), runner)
+}
+
+fn regenerate_host_keys_in(root: &Path, runner: &Runner) -> Result<(), Error> {
+    let dir = root.join(HOST_KEYS_DIR);
+    let mut host_keys = Vec::new();
+    match fs::read_dir(&dir) {
+        Ok(entries) => {
+            for entry in entries {
+                let path = entry?.path();
+                let is_host_key = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("ssh_host_"));
+                if is_host_key {
+                    host_keys.push(path);
+                }
+            }
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    if runner.is_dry_run() {
+        tracing::info!(?host_keys, "Dry run, not removing ssh host keys");
+    } else {
+        for path in &host_keys {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let mut keygen = Command::new("ssh-keygen");
+    keygen.arg("-A");
+    if root != Path::new("/") {
+        keygen.arg("-f").arg(root);
+    }
+    let output = runner.output(&mut keygen)?;
+    if !output.status.success() {
+        return Err(Error::subprocess_failed("ssh-keygen -A", &output));
+    }
+
+    tracing::info!(removed = host_keys.len(), "Regenerated ssh host keys");
+
+    Ok(())
+}
+
+/// Write [`PATH_SSHD_DROP_IN`] so sshd only allows password authentication
+/// if `enabled` is true, and return the path written.
+///
+/// Using a drop-in leaves the image's main `sshd_config` untouched. sshd
+/// must be started or reloaded afterwards for the setting to apply.
+pub fn set_password_authentication(
+    enabled: bool,
+    runner: &Runner,
+) -> Result<PathBuf, Error> {
+    let path = runner.path(PATH_SSHD_DROP_IN);
+
+    if runner.is_dry_run() {
+        tracing::info!(
+            path = %path.display(),
+            enabled,
+            "Dry run, not writing sshd configuration"
+        );
+        return Ok(path);
+    }
+
+    write_sshd_drop_in(&path, enabled, runner)?;
+    tracing::info!(
+        path = %path.display(),
+        enabled,
+        "Configured sshd password authentication"
+    );
+
+    Ok(path)
+}
+
+fn write_sshd_drop_in(
+    path: &Path,
+    password_authentication: bool,
+    runner: &Runner,
+) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let value = match password_authentication {
+        true => "yes",
+        false => "no",
+    };
+    let contents = format!(
+        "# Written by azure-init; changes will be overwritten.\n\
+         PasswordAuthentication {value}\n"
+    );
+
+    runner.write_file(path, contents.as_bytes(), 0o644, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    use super::{regenerate_host_keys_in, write_sshd_drop_in};
+    use crate::command::{RecordingCommandRunner, Runner};
+    use crate::error::Error;
+
+    fn host_keys_root() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("etc/ssh");
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "ssh_host_ed25519_key",
+            "ssh_host_ed25519_key.pub",
+            "ssh_host_ecdsa_key",
+            "sshd_config",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn test_regenerate_host_keys() {
+        let root = host_keys_root();
+        let recorder = Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        regenerate_host_keys_in(root.path(), &runner).unwrap();
+
+        let left: Vec<_> = fs::read_dir(root.path().join("etc/ssh"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, ["sshd_config"]);
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].program, "ssh-keygen");
+        assert_eq!(
+            commands[0].args,
+            ["-A", "-f", root.path().to_str().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_regenerate_host_keys_reports_keygen_output() {
+        let root = host_keys_root();
+        let recorder = Arc::new(RecordingCommandRunner::new());
+        recorder.fail("ssh-keygen", 1, "unable to write key");
+        let runner = Runner::new().command_runner(recorder);
+
+        match regenerate_host_keys_in(root.path(), &runner) {
+            Err(Error::SubprocessFailed { stderr, .. }) => {
+                assert_eq!(stderr, "unable to write key")
+            }
+            _ => panic!("A failed ssh-keygen should be reported"),
+        }
+    }
+
+    #[test]
+    fn test_regenerate_host_keys_dry_run() {
+        let root = host_keys_root();
+        let runner = Runner::new().dry_run(true);
+
+        regenerate_host_keys_in(root.path(), &runner).unwrap();
+
+        assert_eq!(
+            fs::read_dir(root.path().join("etc/ssh")).unwrap().count(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_write_sshd_drop_in() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sshd_config.d").join("50-azure-init.conf");
+
+        write_sshd_drop_in(&path, false, &Runner::new()).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents
+            .lines()
+            .any(|line| line == "PasswordAuthentication no"));
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+
+        // Rewriting replaces the setting rather than appending to it.
+        write_sshd_drop_in(&path, true, &Runner::new()).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let settings: Vec<&str> = contents
+            .lines()
+            .filter(|line| line.starts_with("PasswordAuthentication"))
+            .collect();
+        assert_eq!(settings, ["PasswordAuthentication yes"]);
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+}
@@ -3,73 +3,1492 @@
 
 use std::fs;
 use std::fs::create_dir;
+use std::fs::create_dir_all;
 use std::fs::File;
-use std::io::Write;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use base64::Engine;
+use nix::fcntl::{AtFlags, FcntlArg};
 use nix::unistd::{Gid, Uid};
+use serde::{Deserialize, Serialize};
 use std::ffi::CString;
 use std::os::unix::fs::PermissionsExt;
 
+use crate::command::Runner;
 use crate::error::Error;
-use crate::imds::PublicKeys;
+use crate::util::{self, WrittenFile};
 
+/// An SSH public key, as listed in the IMDS `publicKeys` array.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PublicKeys {
+    #[serde(rename = "keyData")]
+    pub key_data: String,
+    /// File the key should be written to, e.g.
+    /// `/home/user/.ssh/authorized_keys`. Empty if IMDS did not specify one.
+    #[serde(default, rename = "path")]
+    pub path: String,
+    /// `authorized_keys` options restricting the key, e.g.
+    /// `no-port-forwarding,from="10.0.0.0/8"`, written before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub options: Option<String>,
+}
+
+impl PublicKeys {
+    /// Parse a line in `authorized_keys` format, where options may come
+    /// before the key type, e.g. `no-pty ssh-ed25519 AAAA... comment`.
+    ///
+    /// The key is not validated; see [`validate_ssh_key`].
+    pub fn from_authorized_keys_line(line: &str) -> PublicKeys {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (options, key_data) =
+            match fields.iter().position(|f| SSH_KEY_TYPES.contains(f)) {
+                Some(start) if start > 0 => {
+                    (Some(fields[..start].join(" ")), fields[start..].join(" "))
+                }
+                _ => (None, line.trim().to_string()),
+            };
+
+        PublicKeys {
+            key_data,
+            path: String::new(),
+            options,
+        }
+    }
+
+    // The key as a line of an authorized_keys file.
+    fn authorized_keys_entry(&self) -> String {
+        match &self.options {
+            Some(options) if !options.is_empty() => {
+                format!("{options} {}", self.key_data)
+            }
+            _ => self.key_data.clone(),
+        }
+    }
+}
+
+/// GECOS comment given to users that don't specify one.
+pub const DEFAULT_COMMENT: &str =
+    "Provisioning agent created this user based on username provided in IMDS";
+
+/// Directory under which users' home directories are created unless
+/// another is given.
+pub const DEFAULT_HOME_BASE: &str = "/home";
+
+/// A user account to provision.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    /// Login shell; the system default is used when unset.
+    pub shell: Option<String>,
+    /// GECOS comment; [`DEFAULT_COMMENT`] is used when unset.
+    pub comment: Option<String>,
+    /// UID to create the user with; allocated by useradd when unset.
+    pub uid: Option<u32>,
+    /// Supplementary groups; the distribution's default groups are used
+    /// when unset.
+    pub groups: Option<Vec<String>>,
+    /// Directory the home directory is created in; [`DEFAULT_HOME_BASE`]
+    /// is used when unset.
+    pub home_base: Option<PathBuf>,
+}
+
+impl User {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            shell: None,
+            comment: None,
+            uid: None,
+            groups: None,
+            home_base: None,
+        }
+    }
+
+    pub fn with_shell(mut self, shell: impl Into<String>) -> Self {
+        self.shell = Some(shell.into());
+        self
+    }
+
+    pub fn with_comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn with_uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    pub fn with_groups(mut self, groups: Vec<String>) -> Self {
+        self.groups = Some(groups);
+        self
+    }
+
+    pub fn with_home_base(mut self, home_base: impl Into<PathBuf>) -> Self {
+        self.home_base = Some(home_base.into());
+        self
+    }
+
+    /// The GECOS comment to create the user with.
+    pub fn comment(&self) -> &str {
+        self.comment.as_deref().unwrap_or(DEFAULT_COMMENT)
+    }
+
+    /// The home directory to create the user with, the user's name under
+    /// its home base.
+    ///
+    /// Fails with [`Error::InvalidHomeDirectory`] unless the home base is
+    /// an absolute path without `.` or `..` components and the name is a
+    /// single path component, so the directory can't end up outside the
+    /// home base.
+    pub fn home_dir(&self) -> Result<PathBuf, Error> {
+        let base = self
+            .home_base
+            .as_deref()
+            .unwrap_or(Path::new(DEFAULT_HOME_BASE));
+        let home = base.join(&self.name);
+        let invalid = |reason: &str| {
+            Err(Error::InvalidHomeDirectory {
+                path: home.clone(),
+                reason: reason.to_string(),
+            })
+        };
+
+        if !base.is_absolute() {
+            return invalid("the home base is not an absolute path");
+        }
+        if base.components().any(|component| {
+            matches!(component, Component::CurDir | Component::ParentDir)
+        }) {
+            return invalid("the home base contains '.' or '..'");
+        }
+        if self.name.is_empty()
+            || self.name.contains('/')
+            || self.name == "."
+            || self.name == ".."
+        {
+            return invalid("the username is not a single path component");
+        }
+
+        Ok(home)
+    }
+}
+
+/// Directory holding the account databases edited by
+/// [`Provisioner::DirectEtcFiles`].
+pub const PATH_ETC: &str = "/etc";
+/// Files copied into the home directory of users created by
+/// [`Provisioner::DirectEtcFiles`] and [`Provisioner::Sysusers`].
+pub const PATH_SKEL: &str = "/etc/skel";
+/// The sysusers.d(5) snippet written by [`Provisioner::Sysusers`].
+pub const PATH_SYSUSERS_CONF: &str = "/etc/sysusers.d/azure-init.conf";
+
+// Users created without an explicit UID get the next free ID from this
+// range, matching the useradd defaults.
+const FIRST_ID: u32 = 1000;
+const LAST_ID: u32 = 60000;
+
+/// Backends able to create a user and set its password.
+///
+/// Provisioners are named in the configuration as in their
+/// [`FromStr`] implementation, e.g. `direct_etc_files` or
+/// `direct-etc-files`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", try_from = "String")]
+pub enum Provisioner {
+    /// Create the user with `useradd`, and set its password with `passwd`
+    /// or `chpasswd`.
+    Useradd,
+    /// Add the user to `/etc/passwd`, `/etc/shadow` and `/etc/group`
+    /// directly, create its home directory from `/etc/skel`. For images
+    /// that don't ship the shadow utilities.
+    DirectEtcFiles,
+    /// Declare the user and its group memberships in
+    /// `/etc/sysusers.d/azure-init.conf`, apply it with `systemd-sysusers`
+    /// and create its home directory from `/etc/skel`. For systemd-based
+    /// images managing their accounts with sysusers.d.
+    Sysusers,
+}
+
+/// Backends tried, in order, when the caller doesn't choose any.
+pub const DEFAULT_PROVISIONERS: &[Provisioner] = &[
+    Provisioner::Useradd,
+    Provisioner::Sysusers,
+    Provisioner::DirectEtcFiles,
+];
+
+impl FromStr for Provisioner {
+    type Err = Error;
+
+    /// Parse a provisioner's snake_case name, in which hyphens may stand in
+    /// for underscores.
+    fn from_str(name: &str) -> Result<Self, Error> {
+        match name.replace('-', "_").as_str() {
+            "useradd" => Ok(Provisioner::Useradd),
+            "direct_etc_files" => Ok(Provisioner::DirectEtcFiles),
+            "sysusers" => Ok(Provisioner::Sysusers),
+            _ => Err(Error::UnknownProvisioner {
+                name: name.to_string(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for Provisioner {
+    type Error = Error;
+
+    fn try_from(name: String) -> Result<Self, Error> {
+        name.parse()
+    }
+}
+
+impl Provisioner {
+    /// Create `user` with the home directory `home`, adding it to the
+    /// supplementary `groups`. The user is created without a password.
+    ///
+    /// Fails with [`Error::MissingGroup`], naming the first absent group,
+    /// if any of `groups` doesn't exist.
+    pub fn create(
+        &self,
+        user: &User,
+        home: &Path,
+        groups: &[String],
+        runner: &Runner,
+    ) -> Result<(), Error> {
+        for group in groups {
+            if !group_exists(group, runner)? {
+                return Err(Error::MissingGroup {
+                    group: group.clone(),
+                });
+            }
+        }
+
+        match self {
+            Provisioner::Useradd => useradd(user, home, groups, runner),
+            Provisioner::DirectEtcFiles if runner.is_dry_run() => {
+                tracing::info!(
+                    username = user.name,
+                    home = %home.display(),
+                    ?groups,
+                    "Dry run, not adding user to /etc/passwd, /etc/shadow \
+                    and /etc/group or creating its home directory"
+                );
+                Ok(())
+            }
+            Provisioner::DirectEtcFiles => {
+                let (uid, gid) = add_etc_entries(
+                    &runner.path(PATH_ETC),
+                    user,
+                    home,
+                    groups,
+                    runner,
+                )?;
+                create_home(
+                    &runner.path(home),
+                    &runner.path(PATH_SKEL),
+                    uid,
+                    gid,
+                )
+            }
+            Provisioner::Sysusers if runner.is_dry_run() => {
+                tracing::info!(
+                    username = user.name,
+                    home = %home.display(),
+                    ?groups,
+                    "Dry run, not running systemd-sysusers or creating the \
+                    user's home directory"
+                );
+                Ok(())
+            }
+            Provisioner::Sysusers => {
+                let (uid, gid) = sysusers(user, home, groups, runner)?;
+                create_home(
+                    &runner.path(home),
+                    &runner.path(PATH_SKEL),
+                    uid,
+                    gid,
+                )
+            }
+        }
+    }
+
+    /// Set the password of `username` to the crypt(3) hash `password`, or
+    /// remove its password if `password` is empty. Returns the name of
+    /// the backend that set it.
+    pub fn set_password(
+        &self,
+        username: &str,
+        password: &str,
+        runner: &Runner,
+    ) -> Result<&'static str, Error> {
+        match self {
+            Provisioner::Useradd if password.is_empty() => {
+                let output = runner.output(
+                    shadow_command("passwd", runner).arg("-d").arg(username),
+                )?;
+                if !output.status.success() {
+                    return Err(Error::subprocess_failed("passwd", &output));
+                }
+                Ok("passwd")
+            }
+            Provisioner::Useradd => {
+                let output = runner.output_with_input(
+                    shadow_command("chpasswd", runner).arg("-e"),
+                    format!("{username}:{password}\n").as_bytes(),
+                )?;
+                if !output.status.success() {
+                    return Err(Error::subprocess_failed("chpasswd", &output));
+                }
+                Ok("chpasswd")
+            }
+            // sysusers.d has no way to set a password, so both backends
+            // edit /etc/shadow.
+            Provisioner::DirectEtcFiles | Provisioner::Sysusers
+                if runner.is_dry_run() =>
+            {
+                tracing::info!(
+                    username,
+                    "Dry run, not setting the password in /etc/shadow"
+                );
+                Ok("shadow")
+            }
+            Provisioner::DirectEtcFiles | Provisioner::Sysusers => {
+                set_shadow_password(
+                    &runner.path(PATH_ETC),
+                    username,
+                    password,
+                    runner,
+                )?;
+                Ok("shadow")
+            }
+        }
+    }
+}
+
+/// Add the existing user `username` to those of `groups` it isn't already
+/// a member of, using the first of `provisioners` that succeeds, and
+/// return the provisioner that did.
+///
+/// Nothing is ever removed: the user keeps any groups it was added to by
+/// hand, and its other attributes are left as they are. Groups that don't
+/// exist are skipped.
+pub fn reconcile_user(
+    username: &str,
+    groups: &[String],
+    provisioners: &[Provisioner],
+    runner: &Runner,
+) -> Result<Provisioner, Error> {
+    let mut last_error = Error::NoProvisioners {
+        resource: "user".to_string(),
+    };
+
+    for provisioner in provisioners {
+        match reconcile_groups(
+            provisioner,
+            &runner.path(PATH_ETC),
+            username,
+            groups,
+            runner,
+        ) {
+            Ok(()) => return Ok(*provisioner),
+            Err(error) => {
+                tracing::warn!(
+                    ?provisioner,
+                    ?error,
+                    "Failed to reconcile user"
+                );
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+fn reconcile_groups(
+    provisioner: &Provisioner,
+    etc: &Path,
+    username: &str,
+    groups: &[String],
+    runner: &Runner,
+) -> Result<(), Error> {
+    let missing = missing_groups(
+        &fs::read_to_string(etc.join("group"))?,
+        groups,
+        username,
+    );
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    match provisioner {
+        Provisioner::Useradd => {
+            let output = runner.output(
+                shadow_command("usermod", runner)
+                    .arg("--append")
+                    .arg("--groups")
+                    .arg(missing.join(","))
+                    .arg(username),
+            )?;
+            if !output.status.success() {
+                return Err(Error::subprocess_failed("usermod", &output));
+            }
+        }
+        Provisioner::DirectEtcFiles if runner.is_dry_run() => {
+            tracing::info!(
+                username,
+                groups = ?missing,
+                "Dry run, not adding user to groups in /etc/group"
+            );
+            return Ok(());
+        }
+        Provisioner::DirectEtcFiles => {
+            let _lock = lock_etc_files(etc)?;
+            for file in ["group", "gshadow"] {
+                let path = etc.join(file);
+                if path.exists() {
+                    update_etc_file(&path, runner, |contents| {
+                        add_group_member(contents, &missing, username)
+                    })?;
+                }
+            }
+        }
+        Provisioner::Sysusers if runner.is_dry_run() => {
+            tracing::info!(
+                username,
+                groups = ?missing,
+                "Dry run, not adding user to groups with systemd-sysusers"
+            );
+            return Ok(());
+        }
+        Provisioner::Sysusers => {
+            apply_sysusers_conf(runner, |contents| {
+                missing
+                    .iter()
+                    .fold(contents.to_string(), |contents, group| {
+                        append_line(&contents, &format!("m {username} {group}"))
+                    })
+            })?;
+        }
+    }
+
+    tracing::info!(username, groups = ?missing, "Added user to groups");
+
+    Ok(())
+}
+
+// Those of `groups` listed in the group file `contents` which don't have
+// `username` as a member.
+fn missing_groups(
+    contents: &str,
+    groups: &[String],
+    username: &str,
+) -> Vec<String> {
+    groups
+        .iter()
+        .filter(|group| {
+            let entry = contents
+                .lines()
+                .find(|line| line.split(':').next() == Some(group.as_str()));
+            match entry {
+                Some(line) => !line
+                    .split(':')
+                    .nth(3)
+                    .unwrap_or_default()
+                    .split(',')
+                    .any(|member| member == username),
+                None => {
+                    tracing::warn!(
+                        group = group.as_str(),
+                        "Skipping missing group"
+                    );
+                    false
+                }
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+/// Lock the password of `username` with `passwd -l`, so it can no longer
+/// log in with a password.
+pub fn lock_password(username: &str, runner: &Runner) -> Result<(), Error> {
+    let output = runner
+        .output(shadow_command("passwd", runner).arg("-l").arg(username))?;
+    if !output.status.success() {
+        return Err(Error::subprocess_failed("passwd", &output));
+    }
+
+    Ok(())
+}
+
+/// Directory sudo reads drop-in policy files from.
+pub const PATH_SUDOERS_DIR: &str = "/etc/sudoers.d";
+
+/// Controls whether and how [`provision_sudoers`] grants the admin user
+/// sudo.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SudoersOptions {
+    /// Write a sudoers file for the admin user.
+    pub enabled: bool,
+    /// Allow sudo without a password, as cloud images usually do for
+    /// key-only users.
+    pub nopasswd: bool,
+}
+
+/// Allow `user` to run any command with sudo, by writing
+/// `/etc/sudoers.d/90-azure-init-<user>` with mode 0440.
+///
+/// The file is checked with `visudo -cf` before it is put in place, so an
+/// invalid policy never reaches the directory sudo reads.
+pub fn provision_sudoers(
+    user: &User,
+    nopasswd: bool,
+    runner: &Runner,
+) -> Result<PathBuf, Error> {
+    write_sudoers(&runner.path(PATH_SUDOERS_DIR), &user.name, nopasswd, runner)
+}
+
+fn write_sudoers(
+    dir: &Path,
+    username: &str,
+    nopasswd: bool,
+    runner: &Runner,
+) -> Result<PathBuf, Error> {
+    let path = dir.join(format!("90-azure-init-{username}"));
+    let rule = match nopasswd {
+        true => format!("{username} ALL=(ALL) NOPASSWD:ALL\n"),
+        false => format!("{username} ALL=(ALL) ALL\n"),
+    };
+
+    if runner.is_dry_run() {
+        tracing::info!(
+            path = %path.display(),
+            rule,
+            "Dry run, not writing sudoers file"
+        );
+        return Ok(path);
+    }
+
+    // sudo skips files in sudoers.d whose names contain a dot, so the
+    // unvalidated file is never read.
+    let tmp_path = dir.join(format!(".90-azure-init-{username}"));
+    let written = util::write_file(&tmp_path, rule.as_bytes(), 0o440, None)?;
+
+    let validated = runner
+        .output(Command::new("visudo").arg("-cf").arg(&tmp_path))
+        .and_then(|output| match output.status.success() {
+            true => Ok(()),
+            false => Err(Error::subprocess_failed("visudo", &output)),
+        });
+    if let Err(error) = validated {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(error);
+    }
+
+    fs::rename(&tmp_path, &path)?;
+    runner.record_written_file(WrittenFile {
+        path: path.clone(),
+        ..written
+    });
+
+    Ok(path)
+}
+
+/// Create `user` using the first of `provisioners` that succeeds, and
+/// return the provisioner that did.
+///
+/// If every provisioner fails, the error from the last one is returned.
+pub fn create_user(
+    user: &User,
+    home: &Path,
+    groups: &[String],
+    provisioners: &[Provisioner],
+    runner: &Runner,
+) -> Result<Provisioner, Error> {
+    let mut last_error = Error::NoProvisioners {
+        resource: "user".to_string(),
+    };
+
+    for provisioner in provisioners {
+        match provisioner.create(user, home, groups, runner) {
+            Ok(()) => {
+                tracing::info!(
+                    ?provisioner,
+                    username = user.name,
+                    "Created user"
+                );
+                return Ok(*provisioner);
+            }
+            Err(error) => {
+                tracing::warn!(?provisioner, ?error, "Failed to create user");
+                last_error = error;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+fn useradd(
+    user: &User,
+    home: &Path,
+    groups: &[String],
+    runner: &Runner,
+) -> Result<(), Error> {
+    let mut command = shadow_command("useradd", runner);
+    command.arg(&user.name).arg("--comment").arg(user.comment());
+    if !groups.is_empty() {
+        command.arg("--groups").arg(groups.join(","));
+    }
+    if let Some(shell) = &user.shell {
+        command.arg("--shell").arg(shell);
+    }
+    if let Some(uid) = user.uid {
+        command.arg("--uid").arg(uid.to_string());
+    }
+    // useradd -m only creates the home directory itself, so a home base
+    // other than /home may need creating first.
+    if let Some(base) = home.parent() {
+        if !runner.is_dry_run() {
+            create_dir_all(runner.path(base))?;
+        }
+    }
+    let output = runner.output(command.arg("-d").arg(home).arg("-m"))?;
+    if !output.status.success() {
+        return Err(Error::subprocess_failed("useradd", &output));
+    }
+
+    Ok(())
+}
+
+// A command from the shadow utilities, such as useradd, which edits the
+// account databases of the runner's root directory.
+fn shadow_command(program: &str, runner: &Runner) -> Command {
+    let mut command = Command::new(program);
+    if !runner.is_system_root() {
+        command.arg("--root").arg(runner.root());
+    }
+    command
+}
+
+/// Look up `username` in the passwd database of the system being
+/// provisioned.
+///
+/// The running system's database is read through NSS, so users from
+/// sources such as LDAP are found too; under another root directory only
+/// its `/etc/passwd` is read.
+pub fn get_user_by_name(
+    username: &str,
+    runner: &Runner,
+) -> Result<Option<nix::unistd::User>, Error> {
+    if runner.is_system_root() {
+        return Ok(nix::unistd::User::from_name(username)?);
+    }
+    find_passwd_entry(&runner.path(PATH_ETC), |user| user.name == username)
+}
+
+/// Like [`get_user_by_name`], but look the user up by its UID.
+pub fn get_user_by_uid(
+    uid: u32,
+    runner: &Runner,
+) -> Result<Option<nix::unistd::User>, Error> {
+    if runner.is_system_root() {
+        return Ok(nix::unistd::User::from_uid(Uid::from_raw(uid))?);
+    }
+    find_passwd_entry(&runner.path(PATH_ETC), |user| user.uid.as_raw() == uid)
+}
+
+/// Whether `group` exists in the group database of the system being
+/// provisioned; see [`get_user_by_name`].
+pub fn group_exists(group: &str, runner: &Runner) -> Result<bool, Error> {
+    if runner.is_system_root() {
+        return Ok(nix::unistd::Group::from_name(group)?.is_some());
+    }
+    let contents = fs::read_to_string(runner.path(PATH_ETC).join("group"))?;
+
+    Ok(contents
+        .lines()
+        .any(|line| line.split(':').next() == Some(group)))
+}
+
+// The first entry of `etc/passwd` matching `predicate`.
+fn find_passwd_entry(
+    etc: &Path,
+    predicate: impl Fn(&nix::unistd::User) -> bool,
+) -> Result<Option<nix::unistd::User>, Error> {
+    let contents = fs::read_to_string(etc.join("passwd"))?;
+
+    for line in contents.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        let [name, passwd, uid, gid, gecos, dir, shell] = fields[..] else {
+            continue;
+        };
+        let (Ok(uid), Ok(gid)) = (uid.parse(), gid.parse()) else {
+            continue;
+        };
+        let user = nix::unistd::User {
+            name: name.to_string(),
+            passwd: CString::new(passwd)?,
+            uid: Uid::from_raw(uid),
+            gid: Gid::from_raw(gid),
+            gecos: CString::new(gecos)?,
+            dir: PathBuf::from(dir),
+            shell: PathBuf::from(shell),
+        };
+        if predicate(&user) {
+            return Ok(Some(user));
+        }
+    }
+
+    Ok(None)
+}
+
+// Take the lock shadow utilities hold while editing the account databases,
+// as lckpwdf(3) does. It is released when the returned file is closed.
+fn lock_etc_files(etc: &Path) -> Result<File, Error> {
+    let lock = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(etc.join(".pwd.lock"))?;
+
+    // SAFETY: flock is a plain C struct for which all zeroes is valid.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = libc::F_WRLCK as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    nix::fcntl::fcntl(lock.as_raw_fd(), FcntlArg::F_SETLKW(&flock))?;
+
+    Ok(lock)
+}
+
+// Replace the contents of `path` with `update` applied to them, keeping the
+// file's permissions and ownership.
+fn update_etc_file(
+    path: &Path,
+    runner: &Runner,
+    update: impl FnOnce(&str) -> String,
+) -> Result<(), Error> {
+    let contents = fs::read_to_string(path)?;
+    let metadata = fs::metadata(path)?;
+
+    runner.write_file(
+        path,
+        update(&contents).as_bytes(),
+        metadata.mode() & 0o7777,
+        Some((metadata.uid(), metadata.gid())),
+    )
+}
+
+// Append `line` to `contents`, which may lack a trailing newline.
+fn append_line(contents: &str, line: &str) -> String {
+    let mut contents = contents.to_string();
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(line);
+    contents.push('\n');
+    contents
+}
+
+// The numeric IDs in the third field of the given passwd or group file.
+fn used_ids(contents: &str) -> Vec<u32> {
+    contents
+        .lines()
+        .filter_map(|line| line.split(':').nth(2)?.parse().ok())
+        .collect()
+}
+
+fn next_free_id(used: &[u32]) -> Option<u32> {
+    (FIRST_ID..=LAST_ID).find(|id| !used.contains(id))
+}
+
+// The GID of the group `name` in the given group file, if there is one.
+fn group_id(contents: &str, name: &str) -> Option<u32> {
+    contents.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next() != Some(name) {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+// The GID for a new group of the user with `uid`; like useradd, preferably
+// the same ID as the user.
+fn new_group_id(contents: &str, uid: u32) -> Result<u32, Error> {
+    let used = used_ids(contents);
+    if used.contains(&uid) {
+        next_free_id(&used).ok_or(Error::NoFreeId)
+    } else {
+        Ok(uid)
+    }
+}
+
+// Remove the entry for `name` from the given passwd-style file.
+fn remove_entry(contents: &str, name: &str) -> String {
+    contents
+        .lines()
+        .filter(|line| line.split(':').next() != Some(name))
+        .fold(String::new(), |kept, line| append_line(&kept, line))
+}
+
+// Add `user` and its primary group to the account databases in `etc`,
+// returning its UID and GID.
+//
+// The files are rewritten one at a time, so a failure part way through
+// leaves the earlier ones changed. passwd is written last, so the user
+// only exists once its other entries do, and a retry reuses or replaces
+// whatever an earlier attempt left behind.
+fn add_etc_entries(
+    etc: &Path,
+    user: &User,
+    home: &Path,
+    groups: &[String],
+    runner: &Runner,
+) -> Result<(u32, u32), Error> {
+    let _lock = lock_etc_files(etc)?;
+    let name = user.name.as_str();
+
+    let passwd = fs::read_to_string(etc.join("passwd"))?;
+    if passwd
+        .lines()
+        .any(|line| line.split(':').next() == Some(name))
+    {
+        return Err(Error::UserExists {
+            user: name.to_string(),
+        });
+    }
+    let uid = match user.uid {
+        Some(uid) => uid,
+        None => next_free_id(&used_ids(&passwd)).ok_or(Error::NoFreeId)?,
+    };
+
+    // Like useradd, give the user a group of its own. A group with its
+    // name, e.g. kept when an earlier user of that name was deleted, is
+    // reused rather than duplicated.
+    let group = fs::read_to_string(etc.join("group"))?;
+    let existing_gid = group_id(&group, name);
+    let gid = match existing_gid {
+        Some(gid) => gid,
+        None => new_group_id(&group, uid)?,
+    };
+
+    let shell = user.shell.as_deref().unwrap_or("/bin/sh");
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or_default();
+
+    update_etc_file(&etc.join("group"), runner, |contents| {
+        let contents = match existing_gid {
+            Some(_) => contents.to_string(),
+            None => append_line(contents, &format!("{name}:x:{gid}:")),
+        };
+        add_group_member(&contents, groups, name)
+    })?;
+    if etc.join("gshadow").exists() {
+        update_etc_file(&etc.join("gshadow"), runner, |contents| {
+            let exists = contents
+                .lines()
+                .any(|line| line.split(':').next() == Some(name));
+            let contents = if exists {
+                contents.to_string()
+            } else {
+                append_line(contents, &format!("{name}:!::"))
+            };
+            add_group_member(&contents, groups, name)
+        })?;
+    }
+    update_etc_file(&etc.join("shadow"), runner, |contents| {
+        append_line(
+            &remove_entry(contents, name),
+            &format!("{name}:!:{days}:0:99999:7:::"),
+        )
+    })?;
+    update_etc_file(&etc.join("passwd"), runner, |contents| {
+        append_line(
+            contents,
+            &format!(
+                "{name}:x:{uid}:{gid}:{}:{}:{shell}",
+                user.comment(),
+                home.display()
+            ),
+        )
+    })?;
+
+    Ok((uid, gid))
+}
+
+// Declare `user` in the sysusers.d snippet and apply it, returning its UID
+// and GID.
+//
+// The IDs are chosen here rather than left to systemd-sysusers, which would
+// pick them from the system account range.
+fn sysusers(
+    user: &User,
+    home: &Path,
+    groups: &[String],
+    runner: &Runner,
+) -> Result<(u32, u32), Error> {
+    let etc = runner.path(PATH_ETC);
+    let name = user.name.as_str();
+
+    let passwd = fs::read_to_string(etc.join("passwd"))?;
+    if passwd
+        .lines()
+        .any(|line| line.split(':').next() == Some(name))
+    {
+        return Err(Error::UserExists {
+            user: name.to_string(),
+        });
+    }
+    let uid = match user.uid {
+        Some(uid) => uid,
+        None => next_free_id(&used_ids(&passwd)).ok_or(Error::NoFreeId)?,
+    };
+
+    // systemd-sysusers ignores the group line if a group with the user's
+    // name exists and makes that its primary group, so use its GID too.
+    let group = fs::read_to_string(etc.join("group"))?;
+    let gid = match group_id(&group, name) {
+        Some(gid) => gid,
+        None => new_group_id(&group, uid)?,
+    };
+
+    let lines = sysusers_lines(user, uid, gid, home, groups);
+    apply_sysusers_conf(runner, |contents| {
+        // Replace any lines left from a previous attempt for this user.
+        let kept = contents
+            .lines()
+            .filter(|line| line.split_whitespace().nth(1) != Some(name))
+            .fold(String::new(), |kept, line| append_line(&kept, line));
+        lines
+            .iter()
+            .fold(kept, |contents, line| append_line(&contents, line))
+    })?;
+
+    Ok((uid, gid))
+}
+
+// The sysusers.d(5) lines declaring `user`, its primary group and its
+// membership of `groups`.
+fn sysusers_lines(
+    user: &User,
+    uid: u32,
+    gid: u32,
+    home: &Path,
+    groups: &[String],
+) -> Vec<String> {
+    let name = user.name.as_str();
+    let comment = user.comment().replace('\\', "\\\\").replace('"', "\\\"");
+    let shell = user.shell.as_deref().unwrap_or("-");
+
+    let mut lines = vec![
+        format!("g {name} {gid}"),
+        format!(
+            "u {name} {uid}:{name} \"{comment}\" {} {shell}",
+            home.display()
+        ),
+    ];
+    lines.extend(groups.iter().map(|group| format!("m {name} {group}")));
+    lines
+}
+
+// Replace the contents of the sysusers.d snippet with `update` applied to
+// them and run systemd-sysusers on it. If that fails, including when
+// systemd-sysusers isn't installed, the previous snippet is put back.
+fn apply_sysusers_conf(
+    runner: &Runner,
+    update: impl FnOnce(&str) -> String,
+) -> Result<(), Error> {
+    let path = runner.path(PATH_SYSUSERS_CONF);
+    let previous = match fs::read_to_string(&path) {
+        Ok(contents) => Some(contents),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+        Err(error) => return Err(error.into()),
+    };
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let contents = update(previous.as_deref().unwrap_or(""));
+    runner.write_file(&path, contents.as_bytes(), 0o644, None)?;
+
+    let mut command = Command::new("systemd-sysusers");
+    if !runner.is_system_root() {
+        command.arg("--root").arg(runner.root());
+    }
+    let result =
+        runner
+            .output(command.arg(PATH_SYSUSERS_CONF))
+            .and_then(|output| {
+                if output.status.success() {
+                    Ok(())
+                } else {
+                    Err(Error::subprocess_failed("systemd-sysusers", &output))
+                }
+            });
+
+    if result.is_err() {
+        let restored = match &previous {
+            Some(contents) => {
+                runner.write_file(&path, contents.as_bytes(), 0o644, None)
+            }
+            None => fs::remove_file(&path).map_err(Error::from),
+        };
+        if let Err(error) = restored {
+            tracing::warn!(
+                ?error,
+                path = %path.display(),
+                "Failed to restore the sysusers.d snippet"
+            );
+        }
+    }
+
+    result
+}
+
+// Add `member` to the member list, the last field, of each of `groups` in
+// a group or gshadow file.
+fn add_group_member(contents: &str, groups: &[String], member: &str) -> String {
+    let mut updated = String::with_capacity(contents.len());
+
+    for line in contents.lines() {
+        let is_target = line
+            .split(':')
+            .next()
+            .is_some_and(|group| groups.iter().any(|g| g == group));
+        updated.push_str(line);
+        if is_target {
+            if !line.ends_with(':') {
+                updated.push(',');
+            }
+            updated.push_str(member);
+        }
+        updated.push('\n');
+    }
+
+    for group in groups {
+        if !contents
+            .lines()
+            .any(|line| line.split(':').next() == Some(group.as_str()))
+        {
+            tracing::warn!(group, "Skipping missing group");
+        }
+    }
+
+    updated
+}
+
+// Set the password field of `username` in `etc/shadow`.
+fn set_shadow_password(
+    etc: &Path,
+    username: &str,
+    password: &str,
+    runner: &Runner,
+) -> Result<(), Error> {
+    let _lock = lock_etc_files(etc)?;
+    let mut found = false;
+
+    update_etc_file(&etc.join("shadow"), runner, |contents| {
+        let mut updated = String::with_capacity(contents.len());
+        for line in contents.lines() {
+            let mut fields: Vec<&str> = line.split(':').collect();
+            if fields.len() > 1 && fields[0] == username {
+                fields[1] = password;
+                found = true;
+            }
+            updated.push_str(&fields.join(":"));
+            updated.push('\n');
+        }
+        updated
+    })?;
+
+    if !found {
+        return Err(Error::UserMissing {
+            user: username.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// Create the home directory `home` from the skeleton directory `skel`,
+// owned by `uid` and `gid`.
+fn create_home(
+    home: &Path,
+    skel: &Path,
+    uid: u32,
+    gid: u32,
+) -> Result<(), Error> {
+    if let Some(parent) = home.parent() {
+        create_dir_all(parent)?;
+    }
+    create_dir(home)?;
+    fs::set_permissions(home, fs::Permissions::from_mode(0o700))?;
+    chown_no_follow(home, uid, gid)?;
+
+    if skel.is_dir() {
+        copy_skel(skel, home, uid, gid)?;
+    }
+
+    Ok(())
+}
+
+fn copy_skel(from: &Path, to: &Path, uid: u32, gid: u32) -> Result<(), Error> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let source = entry.path();
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            create_dir(&target)?;
+            fs::set_permissions(&target, fs::metadata(&source)?.permissions())?;
+            copy_skel(&source, &target, uid, gid)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&source)?, &target)?;
+        } else {
+            fs::copy(&source, &target)?;
+        }
+        chown_no_follow(&target, uid, gid)?;
+    }
+
+    Ok(())
+}
+
+fn chown_no_follow(path: &Path, uid: u32, gid: u32) -> Result<(), Error> {
+    nix::unistd::fchownat(
+        None,
+        path,
+        Some(Uid::from_raw(uid)),
+        Some(Gid::from_raw(gid)),
+        AtFlags::AT_SYMLINK_NOFOLLOW,
+    )?;
+
+    Ok(())
+}
+
+/// Public key algorithms accepted by [`validate_ssh_key`].
+pub const SSH_KEY_TYPES: &[&str] = &[
+    "ssh-rsa",
+    "ssh-dss",
+    "ssh-ed25519",
+    "ecdsa-sha2-nistp256",
+    "ecdsa-sha2-nistp384",
+    "ecdsa-sha2-nistp521",
+    "sk-ssh-ed25519@openssh.com",
+    "sk-ecdsa-sha2-nistp256@openssh.com",
+];
+
+/// Check that `key` is a well-formed OpenSSH public key of the form
+/// `<type> <base64 blob> [comment]`.
+///
+/// The type must be one of [`SSH_KEY_TYPES`], and the blob must be valid
+/// base64 that starts with the same key type, as every OpenSSH key blob
+/// does.
+pub fn validate_ssh_key(key: &str) -> Result<(), Error> {
+    let invalid = |reason: &str| {
+        Err(Error::InvalidSshKey {
+            reason: reason.to_string(),
+        })
+    };
+
+    // A line break would let the key add another authorized_keys entry.
+    if key.contains(['\n', '\r']) {
+        return invalid("the key contains a line break");
+    }
+
+    let mut fields = key.split_whitespace();
+    let (Some(key_type), Some(blob)) = (fields.next(), fields.next()) else {
+        return invalid("expected a key type followed by the key data");
+    };
+    if !SSH_KEY_TYPES.contains(&key_type) {
+        return invalid(&format!("unsupported key type '{key_type}'"));
+    }
+
+    let Ok(blob) = base64::engine::general_purpose::STANDARD.decode(blob)
+    else {
+        return invalid("the key data is not valid base64");
+    };
+
+    // The blob starts with the key type as a length-prefixed string.
+    let blob_type = blob
+        .get(..4)
+        .map(|len| u32::from_be_bytes([len[0], len[1], len[2], len[3]]))
+        .and_then(|len| blob.get(4..4 + len as usize));
+    if blob_type != Some(key_type.as_bytes()) {
+        return invalid(&format!(
+            "the key data does not hold a '{key_type}' key"
+        ));
+    }
+
+    Ok(())
+}
+
+// Check that the options of a key can't break out of its authorized_keys
+// line.
+fn validate_key_options(key: &PublicKeys) -> Result<(), Error> {
+    match &key.options {
+        Some(options) if options.contains(['\n', '\r']) => {
+            Err(Error::InvalidSshKey {
+                reason: "the key options contain a line break".to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// The base64 blob of an authorized_keys entry, which identifies the key
+// regardless of any options or comment around it.
+fn key_blob(entry: &str) -> Option<&str> {
+    let mut fields = entry.split_whitespace();
+    fields.find(|field| SSH_KEY_TYPES.contains(field))?;
+    fields.next()
+}
+
+/// Merge lists of SSH public keys from several sources into one, in the
+/// order given, dropping keys that an earlier list already had.
+///
+/// Keys are the same if their base64 blobs are, so the first copy of a key
+/// is kept with its options, comment and path. Keys without a recognizable
+/// blob are all kept, for [`set_ssh_keys`] to validate.
+pub fn merge_ssh_keys(
+    key_lists: impl IntoIterator<Item = Vec<PublicKeys>>,
+) -> Vec<PublicKeys> {
+    let mut merged: Vec<PublicKeys> = Vec::new();
+    for key in key_lists.into_iter().flatten() {
+        let blob = key_blob(&key.key_data);
+        let seen = blob.is_some()
+            && merged
+                .iter()
+                .any(|existing| key_blob(&existing.key_data) == blob);
+        if seen {
+            tracing::debug!(key = key.key_data, "Dropping duplicate SSH key");
+        } else {
+            merged.push(key);
+        }
+    }
+
+    merged
+}
+
+/// Directory sshd is commonly configured to read per-user keys from, with
+/// `AuthorizedKeysFile /etc/ssh/authorized_keys.d/%u`.
+pub const DEFAULT_AUTHORIZED_KEYS_DIR: &str = "/etc/ssh/authorized_keys.d";
+
+/// Controls how [`set_ssh_keys`] writes keys.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SshKeyOptions {
+    /// Fail on the first malformed key instead of skipping it.
+    pub strict: bool,
+    /// Replace existing authorized_keys files instead of merging the new
+    /// keys into them.
+    pub overwrite: bool,
+    /// Write all keys to `<dir>/<username>`, owned by root with mode
+    /// 0644, instead of the user's home directory. Useful where home
+    /// directories are read-only, such as with
+    /// [`DEFAULT_AUTHORIZED_KEYS_DIR`].
+    pub authorized_keys_dir: Option<PathBuf>,
+}
+
+impl SshKeyOptions {
+    /// The file `key` is written to for `username`, whose `.ssh` directory
+    /// is `ssh_dir`.
+    pub fn key_path(
+        &self,
+        key: &PublicKeys,
+        username: &str,
+        ssh_dir: &Path,
+    ) -> PathBuf {
+        match &self.authorized_keys_dir {
+            Some(dir) => dir.join(username),
+            None if key.path.is_empty() => ssh_dir.join("authorized_keys"),
+            None => PathBuf::from(&key.path),
+        }
+    }
+}
+
+/// Write the given public keys for `username`, returning how many of them
+/// were valid and are now authorized.
+///
+/// Keys are written to the file named by their `path`, or to
+/// `authorized_keys` inside `file_path` if no path was given. With
+/// `options.authorized_keys_dir` set, all keys go to the user's file in
+/// that directory instead. Keys that
+/// fail [`validate_ssh_key`] are logged and skipped; with
+/// `options.strict` any invalid key is an error and nothing is written.
+///
+/// Existing entries in each file are kept and new keys whose blob is
+/// already present are skipped, unless `options.overwrite` is set. Files
+/// are replaced atomically.
+///
+/// All paths are taken relative to the runner's root directory.
 pub async fn set_ssh_keys(
     keys: Vec<PublicKeys>,
     username: String,
     file_path: String,
-) -> Result<(), Error> {
-    let mut authorized_keys_path = file_path;
-    authorized_keys_path.push_str("/authorized_keys");
-
-    let mut authorized_keys = File::create(authorized_keys_path.clone())?;
+    options: &SshKeyOptions,
+    runner: &Runner,
+) -> Result<usize, Error> {
+    let mut valid_keys = Vec::with_capacity(keys.len());
     for key in keys {
-        writeln!(authorized_keys, "{}", key.key_data)?;
-    }
-    let metadata = fs::metadata(authorized_keys_path.clone())?;
-    let permissions = metadata.permissions();
-    let mut new_permissions = permissions.clone();
-    new_permissions.set_mode(0o600);
-    fs::set_permissions(authorized_keys_path.clone(), new_permissions)?;
-
-    let uid_username = CString::new(username.clone())?;
-    let uid_passwd = unsafe { libc::getpwnam(uid_username.as_ptr()) };
-    let uid = unsafe { (*uid_passwd).pw_uid };
-    let new_uid = Uid::from_raw(uid);
-
-    let gid_groupname = CString::new(username.clone())?;
-    let gid_group = unsafe { libc::getgrnam(gid_groupname.as_ptr()) };
-    let gid = unsafe { (*gid_group).gr_gid };
-    let new_gid = Gid::from_raw(gid);
-
-    let _set_ownership = nix::unistd::chown(
-        authorized_keys_path.as_str(),
-        Some(new_uid),
-        Some(new_gid),
-    );
+        match validate_ssh_key(&key.key_data)
+            .and_then(|()| validate_key_options(&key))
+        {
+            Ok(()) => valid_keys.push(key),
+            Err(error) if options.strict => return Err(error),
+            Err(error) => {
+                tracing::warn!(?error, path = key.path, "Skipping SSH key")
+            }
+        }
+    }
+    let count = valid_keys.len();
 
-    Ok(())
+    let user = get_user_by_name(&username, runner)?.ok_or_else(|| {
+        Error::UserMissing {
+            user: username.clone(),
+        }
+    })?;
+    let (new_uid, new_gid) = (user.uid, user.gid);
+
+    // Keys outside the home directory belong to root and must be readable
+    // by sshd after it drops privileges.
+    let (new_uid, new_gid, mode) = match options.authorized_keys_dir {
+        Some(_) => (Uid::from_raw(0), Gid::from_raw(0), 0o644),
+        None => (new_uid, new_gid, 0o600),
+    };
+
+    let mut keys_by_path: Vec<(PathBuf, Vec<PublicKeys>)> = Vec::new();
+    for key in valid_keys {
+        let path = runner.path(options.key_path(
+            &key,
+            &username,
+            Path::new(&file_path),
+        ));
+
+        match keys_by_path.iter_mut().find(|(p, _)| *p == path) {
+            Some((_, path_keys)) => path_keys.push(key),
+            None => keys_by_path.push((path, vec![key])),
+        }
+    }
+
+    for (authorized_keys_path, path_keys) in keys_by_path {
+        if let Some(parent) = authorized_keys_path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let mut entries = Vec::new();
+        if !options.overwrite {
+            if let Some(contents) = read_authorized_keys(&authorized_keys_path)?
+            {
+                entries.extend(contents.lines().map(String::from));
+            }
+        }
+        for key in path_keys {
+            let blob = key_blob(&key.key_data);
+            if entries.iter().any(|entry| key_blob(entry) == blob) {
+                tracing::info!(
+                    path = %authorized_keys_path.display(),
+                    "SSH key already authorized, skipping"
+                );
+                continue;
+            }
+            entries.push(key.authorized_keys_entry());
+        }
+
+        let contents: String =
+            entries.iter().map(|entry| format!("{entry}\n")).collect();
+        runner.write_file(
+            &authorized_keys_path,
+            contents.as_bytes(),
+            mode,
+            Some((new_uid.as_raw(), new_gid.as_raw())),
+        )?;
+    }
+
+    Ok(count)
+}
+
+// The contents of an existing authorized_keys file. The file may be
+// controlled by the user, so a symlink isn't followed: it could point root
+// at a file the user can't read, such as /etc/shadow. The link is replaced
+// by the new file instead.
+fn read_authorized_keys(path: &Path) -> Result<Option<String>, Error> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path);
+    match file {
+        Ok(mut file) => {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            Ok(Some(contents))
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) if error.raw_os_error() == Some(libc::ELOOP) => {
+            tracing::warn!(
+                path = %path.display(),
+                "Not reading authorized keys through a symlink, replacing it"
+            );
+            Ok(None)
+        }
+        Err(error) => Err(error.into()),
+    }
 }
 
+/// Create the `.ssh` directory in `home_path`, relative to the runner's
+/// root directory, owned by `username` with mode 0700.
 pub async fn create_ssh_directory(
     username: &str,
-    home_path: &String,
+    home_path: &Path,
+    runner: &Runner,
 ) -> Result<(), Error> {
-    let mut file_path = home_path.to_owned();
-    file_path.push_str("/.ssh");
+    let file_path = runner.path(home_path).join(".ssh");
 
-    create_dir(file_path.clone())?;
+    match create_dir(file_path.clone()) {
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+        result => result?,
+    }
 
     let user =
-        nix::unistd::User::from_name(username)?.ok_or(Error::UserMissing {
+        get_user_by_name(username, runner)?.ok_or(Error::UserMissing {
             user: username.to_string(),
         })?;
-    nix::unistd::chown(file_path.as_str(), Some(user.uid), Some(user.gid))?;
 
-    let metadata = fs::metadata(&file_path)?;
-    let permissions = metadata.permissions();
-    let mut new_permissions = permissions.clone();
-    new_permissions.set_mode(0o700);
-    fs::set_permissions(&file_path, new_permissions)?;
+    // An existing .ssh is under the user's control, so it is only changed
+    // through a descriptor that is known to be a real directory; a symlink
+    // could otherwise get root to chown e.g. /etc to the user.
+    let invalid = |reason: &str| Error::InvalidSshDirectory {
+        path: file_path.clone(),
+        reason: reason.to_string(),
+    };
+    let dir = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_DIRECTORY)
+        .open(&file_path)
+        .map_err(|error| match error.raw_os_error() {
+            Some(libc::ELOOP | libc::ENOTDIR) => {
+                match fs::symlink_metadata(&file_path) {
+                    Ok(metadata) if metadata.is_symlink() => {
+                        invalid("it is a symlink")
+                    }
+                    _ => invalid("it is not a directory"),
+                }
+            }
+            _ => error.into(),
+        })?;
+    nix::unistd::fchown(dir.as_raw_fd(), Some(user.uid), Some(user.gid))?;
+    dir.set_permissions(fs::Permissions::from_mode(0o700))?;
 
     Ok(())
 }
@@ -77,7 +1496,108 @@ pub async fn create_ssh_directory(
 #[cfg(test)]
 mod tests {
 
-    use super::create_ssh_directory;
+    use super::PublicKeys;
+    use super::{
+        add_etc_entries, create_home, create_ssh_directory, create_user,
+        get_user_by_name, get_user_by_uid, group_exists, lock_password,
+        merge_ssh_keys, reconcile_groups, set_shadow_password, set_ssh_keys,
+        sysusers, sysusers_lines, validate_ssh_key, write_sudoers, Provisioner,
+        SshKeyOptions, User, DEFAULT_COMMENT,
+    };
+    use crate::command::{RecordingCommandRunner, Runner};
+    use crate::error::Error;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    const TEST_KEY_1: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILxMIRh0Y6wJRCx8a8TJU16X5OKlpiUvjpESaRZ3xICs test_key_1";
+    const TEST_KEY_2: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICxFw5ZTiL2iaXBgTVrtBeuw78i45Os2L3lB7qMEczqP test_key_2";
+    const TEST_KEY_3: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEVjIfTj7Xs2k0Z1KQpPx61U+8H2a0/g7sfl+ddKNTe/ test_key_3";
+
+    #[test]
+    fn user_comment_defaults() {
+        let user = User::new("azureuser");
+        assert_eq!(user.comment(), DEFAULT_COMMENT);
+
+        let user = user.with_comment("Jane Doe,,,");
+        assert_eq!(user.comment(), "Jane Doe,,,");
+    }
+
+    #[test]
+    fn home_dir_uses_home_base() {
+        assert_eq!(
+            User::new("azureuser").home_dir().unwrap(),
+            std::path::Path::new("/home/azureuser")
+        );
+        assert_eq!(
+            User::new("azureuser")
+                .with_home_base("/var/home")
+                .home_dir()
+                .unwrap(),
+            std::path::Path::new("/var/home/azureuser")
+        );
+
+        for user in [
+            User::new("azureuser").with_home_base("var/home"),
+            User::new("azureuser").with_home_base("/home/../etc"),
+            User::new(".."),
+            User::new("../etc"),
+        ] {
+            assert!(matches!(
+                user.home_dir(),
+                Err(Error::InvalidHomeDirectory { .. })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn create_ssh_directory_is_idempotent() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let home_path = test_dir.path();
+        let current_user = nix::unistd::User::from_uid(nix::unistd::getuid())
+            .unwrap()
+            .unwrap();
+
+        create_ssh_directory(&current_user.name, home_path, &Runner::new())
+            .await
+            .unwrap();
+        create_ssh_directory(&current_user.name, home_path, &Runner::new())
+            .await
+            .unwrap();
+
+        assert!(test_dir.path().join(".ssh").is_dir());
+    }
+
+    #[tokio::test]
+    async fn create_ssh_directory_refuses_symlinks() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let home_path = test_dir.path().join("home");
+        let etc = test_dir.path().join("etc");
+        std::fs::create_dir_all(&home_path).unwrap();
+        std::fs::create_dir(&etc).unwrap();
+        std::fs::set_permissions(&etc, std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+        std::os::unix::fs::symlink(&etc, home_path.join(".ssh")).unwrap();
+
+        match create_ssh_directory("root", &home_path, &Runner::new()).await {
+            Err(Error::InvalidSshDirectory { reason, .. }) => {
+                assert_eq!(reason, "it is a symlink")
+            }
+            other => panic!("A symlinked .ssh should be refused: {other:?}"),
+        }
+        assert_eq!(
+            std::fs::metadata(&etc).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        std::fs::remove_file(home_path.join(".ssh")).unwrap();
+        std::fs::write(home_path.join(".ssh"), "").unwrap();
+        match create_ssh_directory("root", &home_path, &Runner::new()).await {
+            Err(Error::InvalidSshDirectory { reason, .. }) => {
+                assert_eq!(reason, "it is not a directory")
+            }
+            other => panic!("A .ssh file should be refused: {other:?}"),
+        }
+    }
 
     #[tokio::test]
     #[should_panic]
@@ -87,9 +1607,1069 @@ mod tests {
 
         create_ssh_directory(
             "i_sure_hope_this_user_doesnt_exist",
-            &dir_path.as_os_str().to_str().unwrap().to_string(),
+            dir_path,
+            &Runner::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn merge_ssh_keys_keeps_first_copy() {
+        let key = |line: &str| PublicKeys::from_authorized_keys_line(line);
+        let imds = vec![PublicKeys {
+            path: "/home/azureuser/.ssh/authorized_keys".to_string(),
+            ..key("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA imds")
+        }];
+        let custom_data = vec![
+            key("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIB custom"),
+            key("no-pty ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA copy"),
+        ];
+        let config = vec![
+            key("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIB copy"),
+            key("not a key"),
+            key("not a key"),
+            key("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC config"),
+        ];
+
+        let merged = merge_ssh_keys([imds.clone(), custom_data, config]);
+
+        let lines: Vec<&str> =
+            merged.iter().map(|key| key.key_data.as_str()).collect();
+        assert_eq!(
+            lines,
+            [
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA imds",
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIB custom",
+                "not a key",
+                "not a key",
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC config",
+            ]
+        );
+        assert_eq!(merged[0], imds[0]);
+    }
+
+    #[tokio::test]
+    async fn set_ssh_keys_honors_key_paths() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let ssh_dir = test_dir.path().join(".ssh");
+        let custom_path = test_dir.path().join("custom/authorized_keys");
+
+        let keys = vec![
+            PublicKeys {
+                key_data: TEST_KEY_1.to_owned(),
+                path: String::new(),
+                options: None,
+            },
+            PublicKeys {
+                key_data: TEST_KEY_2.to_owned(),
+                path: custom_path.to_str().unwrap().to_owned(),
+                options: None,
+            },
+            PublicKeys {
+                key_data: TEST_KEY_3.to_owned(),
+                path: String::new(),
+                options: None,
+            },
+        ];
+
+        let written = set_ssh_keys(
+            keys,
+            "root".to_owned(),
+            ssh_dir.to_str().unwrap().to_owned(),
+            &SshKeyOptions {
+                strict: true,
+                ..Default::default()
+            },
+            &Runner::new(),
+        )
+        .await
+        .unwrap();
+
+        let default_keys =
+            std::fs::read_to_string(ssh_dir.join("authorized_keys")).unwrap();
+        let custom_keys = std::fs::read_to_string(custom_path).unwrap();
+
+        assert_eq!(written, 3);
+        assert_eq!(default_keys, format!("{TEST_KEY_1}\n{TEST_KEY_3}\n"));
+        assert_eq!(custom_keys, format!("{TEST_KEY_2}\n"));
+    }
+
+    #[test]
+    fn test_validate_ssh_key() {
+        validate_ssh_key(TEST_KEY_1).unwrap();
+
+        let invalid = [
+            "",
+            "ssh-ed25519",
+            "ssh-foo AAAAC3NzaC1lZDI1NTE5AAAAILxMIRh0Y6wJRCx8a8TJU16X5OKlpiUvjpESaRZ3xICs",
+            "ssh-ed25519 not-base64!",
+            // Truncated before the key type inside the blob ends.
+            "ssh-ed25519 AAAAC3NzaC1l",
+            // An ed25519 blob claiming to be an RSA key.
+            "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAILxMIRh0Y6wJRCx8a8TJU16X5OKlpiUvjpESaRZ3xICs",
+        ];
+        for key in invalid {
+            match validate_ssh_key(key) {
+                Err(Error::InvalidSshKey { .. }) => {}
+                _ => panic!("'{key}' should be rejected"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn set_ssh_keys_skips_invalid_keys() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let ssh_dir = test_dir.path().join(".ssh");
+        let keys = vec![
+            PublicKeys {
+                key_data: TEST_KEY_1.to_owned(),
+                path: String::new(),
+                options: None,
+            },
+            PublicKeys {
+                key_data: "ssh-rsa truncated".to_owned(),
+                path: String::new(),
+                options: None,
+            },
+        ];
+
+        let written = set_ssh_keys(
+            keys.clone(),
+            "root".to_owned(),
+            ssh_dir.to_str().unwrap().to_owned(),
+            &SshKeyOptions::default(),
+            &Runner::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, 1);
+        assert_eq!(
+            std::fs::read_to_string(ssh_dir.join("authorized_keys")).unwrap(),
+            format!("{TEST_KEY_1}\n")
+        );
+
+        let strict_dir = test_dir.path().join("strict");
+        match set_ssh_keys(
+            keys,
+            "root".to_owned(),
+            strict_dir.to_str().unwrap().to_owned(),
+            &SshKeyOptions {
+                strict: true,
+                ..Default::default()
+            },
+            &Runner::new(),
+        )
+        .await
+        {
+            Err(Error::InvalidSshKey { .. }) => {}
+            _ => panic!("Strict mode should reject invalid keys"),
+        }
+        assert!(!strict_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn set_ssh_keys_writes_key_options() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let ssh_dir = test_dir.path().join(".ssh");
+        let options = r#"no-port-forwarding,from="10.0.0.0/8""#;
+        let keys = vec![
+            PublicKeys {
+                key_data: TEST_KEY_1.to_owned(),
+                path: String::new(),
+                options: Some(options.to_owned()),
+            },
+            PublicKeys {
+                key_data: TEST_KEY_2.to_owned(),
+                path: String::new(),
+                options: Some(format!("no-pty\n{TEST_KEY_3}")),
+            },
+            PublicKeys {
+                key_data: format!("{TEST_KEY_3}\n{TEST_KEY_2}"),
+                path: String::new(),
+                options: None,
+            },
+        ];
+
+        let written = set_ssh_keys(
+            keys,
+            "root".to_owned(),
+            ssh_dir.to_str().unwrap().to_owned(),
+            &SshKeyOptions::default(),
+            &Runner::new(),
+        )
+        .await
+        .unwrap();
+
+        // Keys whose options or data hold line breaks are skipped.
+        assert_eq!(written, 1);
+        assert_eq!(
+            std::fs::read_to_string(ssh_dir.join("authorized_keys")).unwrap(),
+            format!("{options} {TEST_KEY_1}\n")
+        );
+    }
+
+    #[test]
+    fn public_keys_from_authorized_keys_line() {
+        let key = PublicKeys::from_authorized_keys_line(&format!(
+            "no-pty,no-agent-forwarding {TEST_KEY_1}"
+        ));
+        assert_eq!(key.options.as_deref(), Some("no-pty,no-agent-forwarding"));
+        assert_eq!(key.key_data, TEST_KEY_1);
+
+        let key = PublicKeys::from_authorized_keys_line(TEST_KEY_1);
+        assert_eq!(key.options, None);
+        assert_eq!(key.key_data, TEST_KEY_1);
+    }
+
+    #[tokio::test]
+    async fn set_ssh_keys_merges_existing_keys() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let ssh_dir = test_dir.path().join(".ssh");
+        let keys_path = ssh_dir.join("authorized_keys");
+        std::fs::create_dir(&ssh_dir).unwrap();
+        let baked_in = format!("# Baked into the image\n{TEST_KEY_1}\n");
+        std::fs::write(&keys_path, &baked_in).unwrap();
+
+        let keys = vec![
+            PublicKeys {
+                // The same key with a different comment is a duplicate.
+                key_data: TEST_KEY_1.replace("test_key_1", "azure"),
+                path: String::new(),
+                options: None,
+            },
+            PublicKeys {
+                key_data: TEST_KEY_2.to_owned(),
+                path: String::new(),
+                options: None,
+            },
+        ];
+        let ssh_dir = ssh_dir.to_str().unwrap().to_owned();
+
+        for _ in 0..2 {
+            set_ssh_keys(
+                keys.clone(),
+                "root".to_owned(),
+                ssh_dir.clone(),
+                &SshKeyOptions::default(),
+                &Runner::new(),
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            std::fs::read_to_string(&keys_path).unwrap(),
+            format!("{baked_in}{TEST_KEY_2}\n")
+        );
+
+        set_ssh_keys(
+            keys[1..].to_vec(),
+            "root".to_owned(),
+            ssh_dir,
+            &SshKeyOptions {
+                overwrite: true,
+                ..Default::default()
+            },
+            &Runner::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&keys_path).unwrap(),
+            format!("{TEST_KEY_2}\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn set_ssh_keys_ignores_planted_symlinks() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let ssh_dir = test_dir.path().join(".ssh");
+        std::fs::create_dir(&ssh_dir).unwrap();
+        let shadow = test_dir.path().join("shadow");
+        std::fs::write(&shadow, "root:secret\n").unwrap();
+        for name in ["authorized_keys", "authorized_keys.tmp"] {
+            std::os::unix::fs::symlink(&shadow, ssh_dir.join(name)).unwrap();
+        }
+
+        set_ssh_keys(
+            vec![PublicKeys {
+                key_data: TEST_KEY_1.to_owned(),
+                path: String::new(),
+                options: None,
+            }],
+            "root".to_owned(),
+            ssh_dir.to_str().unwrap().to_owned(),
+            &SshKeyOptions::default(),
+            &Runner::new(),
+        )
+        .await
+        .unwrap();
+
+        // The target is neither read into the keys nor written through.
+        assert_eq!(std::fs::read_to_string(&shadow).unwrap(), "root:secret\n");
+        let keys_path = ssh_dir.join("authorized_keys");
+        assert!(!keys_path.symlink_metadata().unwrap().is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(&keys_path).unwrap(),
+            format!("{TEST_KEY_1}\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn set_ssh_keys_authorized_keys_dir() {
+        let test_dir = tempfile::tempdir().unwrap();
+        let keys_dir = test_dir.path().join("authorized_keys.d");
+        let keys = vec![
+            PublicKeys {
+                key_data: TEST_KEY_1.to_owned(),
+                path: String::new(),
+                options: None,
+            },
+            PublicKeys {
+                key_data: TEST_KEY_2.to_owned(),
+                path: "/home/root/.ssh/authorized_keys".to_owned(),
+                options: None,
+            },
+        ];
+
+        set_ssh_keys(
+            keys,
+            "root".to_owned(),
+            test_dir.path().join(".ssh").to_str().unwrap().to_owned(),
+            &SshKeyOptions {
+                authorized_keys_dir: Some(keys_dir.clone()),
+                ..Default::default()
+            },
+            &Runner::new(),
         )
         .await
         .unwrap();
+
+        let keys_path = keys_dir.join("root");
+        assert_eq!(
+            std::fs::read_to_string(&keys_path).unwrap(),
+            format!("{TEST_KEY_1}\n{TEST_KEY_2}\n")
+        );
+        assert_eq!(
+            std::fs::metadata(&keys_path).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+        assert!(!test_dir.path().join(".ssh").exists());
+    }
+
+    fn test_etc_dir() -> tempfile::TempDir {
+        let etc = tempfile::tempdir().unwrap();
+        std::fs::write(
+            etc.path().join("passwd"),
+            "root:x:0:0:root:/root:/bin/bash\n\
+            existing:x:1000:1000::/home/existing:/bin/sh\n",
+        )
+        .unwrap();
+        std::fs::write(
+            etc.path().join("shadow"),
+            "root:*:19000:0:99999:7:::\nexisting:!:19000:0:99999:7:::\n",
+        )
+        .unwrap();
+        std::fs::write(
+            etc.path().join("group"),
+            "root:x:0:\nsudo:x:27:existing\nadm:x:4:\nexisting:x:1000:\n",
+        )
+        .unwrap();
+        etc
+    }
+
+    #[test]
+    fn add_etc_entries_appends_user() {
+        let etc = test_etc_dir();
+        let user = User::new("azureuser").with_shell("/bin/bash");
+        let groups = ["sudo".to_string(), "adm".to_string(), "nope".into()];
+        let runner = Runner::new();
+
+        let (uid, gid) = add_etc_entries(
+            etc.path(),
+            &user,
+            std::path::Path::new("/home/azureuser"),
+            &groups,
+            &runner,
+        )
+        .unwrap();
+        assert_eq!((uid, gid), (1001, 1001));
+
+        let written: Vec<_> = runner
+            .written_files()
+            .into_iter()
+            .map(|file| file.path)
+            .collect();
+        assert_eq!(
+            written,
+            [
+                etc.path().join("group"),
+                etc.path().join("shadow"),
+                etc.path().join("passwd")
+            ]
+        );
+
+        let passwd =
+            std::fs::read_to_string(etc.path().join("passwd")).unwrap();
+        assert!(passwd.ends_with(&format!(
+            "azureuser:x:1001:1001:{DEFAULT_COMMENT}:/home/azureuser:/bin/bash\n"
+        )));
+        let shadow =
+            std::fs::read_to_string(etc.path().join("shadow")).unwrap();
+        assert!(shadow.lines().last().unwrap().starts_with("azureuser:!:"));
+        let group = std::fs::read_to_string(etc.path().join("group")).unwrap();
+        assert_eq!(
+            group,
+            "root:x:0:\nsudo:x:27:existing,azureuser\nadm:x:4:azureuser\n\
+            existing:x:1000:\nazureuser:x:1001:\n"
+        );
+
+        match add_etc_entries(
+            etc.path(),
+            &user,
+            std::path::Path::new("/home/azureuser"),
+            &[],
+            &runner,
+        ) {
+            Err(Error::UserExists { user }) => assert_eq!(user, "azureuser"),
+            _ => panic!("Adding a user twice should fail"),
+        }
+    }
+
+    #[test]
+    fn add_etc_entries_with_uid() {
+        let etc = test_etc_dir();
+        let user = User::new("azureuser").with_uid(1000);
+
+        // The UID is taken as given, but GID 1000 belongs to another group.
+        let (uid, gid) = add_etc_entries(
+            etc.path(),
+            &user,
+            std::path::Path::new("/home/azureuser"),
+            &[],
+            &Runner::new(),
+        )
+        .unwrap();
+        assert_eq!((uid, gid), (1000, 1001));
+    }
+
+    #[test]
+    fn add_etc_entries_reuses_existing_group() {
+        let etc = test_etc_dir();
+        // The group of a deleted user, and the shadow entry of an attempt
+        // that failed before writing passwd.
+        std::fs::write(
+            etc.path().join("group"),
+            "root:x:0:\nsudo:x:27:\nazureuser:x:1500:\n",
+        )
+        .unwrap();
+        std::fs::write(
+            etc.path().join("gshadow"),
+            "root:*::\nsudo:*::\nazureuser:!::\n",
+        )
+        .unwrap();
+        std::fs::write(
+            etc.path().join("shadow"),
+            "root:*:19000:0:99999:7:::\nazureuser:!:19000:0:99999:7:::\n",
+        )
+        .unwrap();
+
+        let (uid, gid) = add_etc_entries(
+            etc.path(),
+            &User::new("azureuser"),
+            std::path::Path::new("/home/azureuser"),
+            &["sudo".to_string()],
+            &Runner::new(),
+        )
+        .unwrap();
+        assert_eq!((uid, gid), (1001, 1500));
+
+        let read = |file| std::fs::read_to_string(etc.path().join(file));
+        assert_eq!(
+            read("group").unwrap(),
+            "root:x:0:\nsudo:x:27:azureuser\nazureuser:x:1500:\n"
+        );
+        assert_eq!(
+            read("gshadow").unwrap(),
+            "root:*::\nsudo:*::azureuser\nazureuser:!::\n"
+        );
+        let shadow = read("shadow").unwrap();
+        assert_eq!(shadow.matches("azureuser:").count(), 1);
+        assert!(read("passwd").unwrap().contains("azureuser:x:1001:1500:"));
+    }
+
+    #[test]
+    fn set_shadow_password_replaces_field() {
+        let etc = test_etc_dir();
+
+        let runner = Runner::new();
+        set_shadow_password(etc.path(), "existing", "$6$salt$hash", &runner)
+            .unwrap();
+
+        let shadow =
+            std::fs::read_to_string(etc.path().join("shadow")).unwrap();
+        assert_eq!(
+            shadow,
+            "root:*:19000:0:99999:7:::\n\
+            existing:$6$salt$hash:19000:0:99999:7:::\n"
+        );
+        match set_shadow_password(etc.path(), "missing", "", &runner) {
+            Err(Error::UserMissing { user }) => assert_eq!(user, "missing"),
+            _ => panic!("Setting the password of a missing user should fail"),
+        }
+    }
+
+    #[test]
+    fn create_home_copies_skel() {
+        let tmp = tempfile::tempdir().unwrap();
+        let skel = tmp.path().join("skel");
+        std::fs::create_dir_all(skel.join(".config")).unwrap();
+        std::fs::write(skel.join(".bashrc"), "# bashrc\n").unwrap();
+        std::fs::write(skel.join(".config").join("app"), "setting\n").unwrap();
+        let home = tmp.path().join("home").join("azureuser");
+
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        create_home(&home, &skel, uid, gid).unwrap();
+
+        assert_eq!(
+            std::fs::metadata(&home).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        assert_eq!(
+            std::fs::read_to_string(home.join(".bashrc")).unwrap(),
+            "# bashrc\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(home.join(".config").join("app")).unwrap(),
+            "setting\n"
+        );
+    }
+
+    // A root directory whose /etc holds the databases of `test_etc_dir`.
+    fn test_root_dir() -> tempfile::TempDir {
+        let root = tempfile::tempdir().unwrap();
+        let etc = test_etc_dir();
+        std::fs::create_dir(root.path().join("etc")).unwrap();
+        for file in ["passwd", "shadow", "group"] {
+            std::fs::copy(
+                etc.path().join(file),
+                root.path().join("etc").join(file),
+            )
+            .unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn sysusers_snippet_format() {
+        let user = User::new("azureuser")
+            .with_comment("Azure \"admin\" user")
+            .with_shell("/bin/bash");
+        let lines = sysusers_lines(
+            &user,
+            1001,
+            1002,
+            std::path::Path::new("/home/azureuser"),
+            &["sudo".to_string(), "adm".to_string()],
+        );
+
+        assert_eq!(
+            lines,
+            [
+                "g azureuser 1002",
+                r#"u azureuser 1001:azureuser "Azure \"admin\" user" /home/azureuser /bin/bash"#,
+                "m azureuser sudo",
+                "m azureuser adm",
+            ]
+        );
+
+        let lines = sysusers_lines(
+            &User::new("azureuser").with_comment(""),
+            1001,
+            1001,
+            std::path::Path::new("/home/azureuser"),
+            &[],
+        );
+        assert_eq!(
+            lines[1],
+            r#"u azureuser 1001:azureuser "" /home/azureuser -"#
+        );
+    }
+
+    #[test]
+    fn sysusers_writes_snippet_and_runs_systemd_sysusers() {
+        let root = test_root_dir();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir(root.path());
+        let user = User::new("azureuser").with_comment("");
+        let groups = ["sudo".to_string()];
+
+        let ids = sysusers(
+            &user,
+            std::path::Path::new("/home/azureuser"),
+            &groups,
+            &runner,
+        )
+        .unwrap();
+
+        assert_eq!(ids, (1001, 1001));
+        assert_eq!(
+            std::fs::read_to_string(
+                root.path().join("etc/sysusers.d/azure-init.conf")
+            )
+            .unwrap(),
+            "g azureuser 1001\n\
+            u azureuser 1001:azureuser \"\" /home/azureuser -\n\
+            m azureuser sudo\n"
+        );
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].program, "systemd-sysusers");
+        assert_eq!(
+            commands[0].args,
+            [
+                "--root".to_string(),
+                root.path().display().to_string(),
+                "/etc/sysusers.d/azure-init.conf".to_string(),
+            ]
+        );
+
+        // Adding groups later extends the snippet.
+        reconcile_groups(
+            &Provisioner::Sysusers,
+            &root.path().join("etc"),
+            "existing",
+            &["adm".to_string()],
+            &runner,
+        )
+        .unwrap();
+        assert!(std::fs::read_to_string(
+            root.path().join("etc/sysusers.d/azure-init.conf")
+        )
+        .unwrap()
+        .ends_with("m azureuser sudo\nm existing adm\n"));
+    }
+
+    #[test]
+    fn sysusers_uses_existing_group() {
+        let root = test_root_dir();
+        std::fs::write(
+            root.path().join("etc/group"),
+            "root:x:0:\nazureuser:x:1500:\n",
+        )
+        .unwrap();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir(root.path());
+
+        let ids = sysusers(
+            &User::new("azureuser"),
+            std::path::Path::new("/home/azureuser"),
+            &[],
+            &runner,
+        )
+        .unwrap();
+
+        assert_eq!(ids, (1001, 1500));
+        assert!(std::fs::read_to_string(
+            root.path().join("etc/sysusers.d/azure-init.conf")
+        )
+        .unwrap()
+        .starts_with("g azureuser 1500\n"));
+    }
+
+    #[test]
+    fn sysusers_failure_removes_snippet() {
+        let root = test_root_dir();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        recorder.fail("systemd-sysusers", 1, "Failed to parse");
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir(root.path());
+
+        match sysusers(
+            &User::new("azureuser"),
+            std::path::Path::new("/home/azureuser"),
+            &[],
+            &runner,
+        ) {
+            Err(Error::SubprocessFailed { .. }) => {}
+            other => panic!("systemd-sysusers failed, got {other:?}"),
+        }
+        assert!(!root.path().join("etc/sysusers.d/azure-init.conf").exists());
+
+        // An existing snippet is put back as it was.
+        std::fs::write(
+            root.path().join("etc/sysusers.d/azure-init.conf"),
+            "g other 1500\n",
+        )
+        .unwrap();
+        assert!(sysusers(
+            &User::new("azureuser"),
+            std::path::Path::new("/home/azureuser"),
+            &[],
+            &runner,
+        )
+        .is_err());
+        assert_eq!(
+            std::fs::read_to_string(
+                root.path().join("etc/sysusers.d/azure-init.conf")
+            )
+            .unwrap(),
+            "g other 1500\n"
+        );
+    }
+
+    #[test]
+    fn create_with_existing_group() {
+        let runner = Runner::new().dry_run(true);
+
+        Provisioner::Useradd
+            .create(
+                &User::new("i_sure_hope_this_user_doesnt_exist"),
+                std::path::Path::new(
+                    "/home/i_sure_hope_this_user_doesnt_exist",
+                ),
+                &["root".to_string()],
+                &runner,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn create_with_missing_group() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        match Provisioner::Useradd.create(
+            &User::new("i_sure_hope_this_user_doesnt_exist"),
+            std::path::Path::new("/home/i_sure_hope_this_user_doesnt_exist"),
+            &[
+                "root".to_string(),
+                "i_sure_hope_this_group_doesnt_exist".into(),
+            ],
+            &runner,
+        ) {
+            Err(Error::MissingGroup { group }) => {
+                assert_eq!(group, "i_sure_hope_this_group_doesnt_exist")
+            }
+            other => {
+                panic!("A missing group should be rejected, got {other:?}")
+            }
+        }
+        assert!(recorder.commands().is_empty());
+    }
+
+    #[test]
+    fn create_user_without_provisioners() {
+        match create_user(
+            &User::new("azureuser"),
+            std::path::Path::new("/home/azureuser"),
+            &[],
+            &[],
+            &Runner::new(),
+        ) {
+            Err(Error::NoProvisioners { resource }) => {
+                assert_eq!(resource, "user")
+            }
+            _ => panic!("Creating a user needs at least one provisioner"),
+        }
+    }
+
+    #[test]
+    fn provisioner_from_str() {
+        assert_eq!(
+            "useradd".parse::<Provisioner>().unwrap(),
+            Provisioner::Useradd
+        );
+        assert_eq!(
+            "direct-etc-files".parse::<Provisioner>().unwrap(),
+            Provisioner::DirectEtcFiles
+        );
+        assert_eq!(
+            "sysusers".parse::<Provisioner>().unwrap(),
+            Provisioner::Sysusers
+        );
+        match "user_add".parse::<Provisioner>() {
+            Err(Error::UnknownProvisioner { name }) => {
+                assert_eq!(name, "user_add")
+            }
+            _ => panic!("Misspelled provisioners should be rejected"),
+        }
+    }
+
+    #[test]
+    fn useradd_creates_home_base() {
+        let root = tempfile::tempdir().unwrap();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir(root.path());
+        let user = User::new("azureuser").with_home_base("/var/home");
+
+        create_user(
+            &user,
+            &user.home_dir().unwrap(),
+            &[],
+            &[Provisioner::Useradd],
+            &runner,
+        )
+        .unwrap();
+
+        assert!(root.path().join("var/home").is_dir());
+        let args = &recorder.commands()[0].args;
+        let home_arg = args.iter().position(|arg| arg == "-d").unwrap();
+        assert_eq!(args[home_arg + 1..], ["/var/home/azureuser", "-m"]);
+    }
+
+    #[test]
+    fn useradd_arguments() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+        let user = User::new("azureuser")
+            .with_shell("/bin/bash")
+            .with_uid(1234);
+        let groups = ["adm".to_string(), "sudo".to_string()];
+
+        Provisioner::Useradd
+            .create(
+                &user,
+                std::path::Path::new("/home/azureuser"),
+                &groups,
+                &runner,
+            )
+            .unwrap();
+
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].program, "useradd");
+        assert_eq!(
+            commands[0].args,
+            [
+                "azureuser",
+                "--comment",
+                DEFAULT_COMMENT,
+                "--groups",
+                "adm,sudo",
+                "--shell",
+                "/bin/bash",
+                "--uid",
+                "1234",
+                "-d",
+                "/home/azureuser",
+                "-m",
+            ]
+        );
+    }
+
+    #[test]
+    fn useradd_root_dir() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new()
+            .command_runner(recorder.clone())
+            .root_dir("/mnt/image");
+
+        Provisioner::Useradd
+            .create(
+                &User::new("azureuser"),
+                std::path::Path::new("/home/azureuser"),
+                &[],
+                &runner,
+            )
+            .unwrap();
+        lock_password("root", &runner).unwrap();
+
+        let commands = recorder.commands();
+        assert_eq!(commands[0].program, "useradd");
+        assert_eq!(commands[0].args[..2], ["--root", "/mnt/image"]);
+        // The home directory is as seen from the image.
+        assert!(commands[0].args.ends_with(&[
+            "-d".to_string(),
+            "/home/azureuser".to_string(),
+            "-m".to_string()
+        ]));
+        assert_eq!(commands[1].args, ["--root", "/mnt/image", "-l", "root"]);
+    }
+
+    #[test]
+    fn get_user_under_root_dir() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("etc")).unwrap();
+        std::fs::write(
+            root.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\n\
+             azureuser:x:1000:1001:,,,:/home/azureuser:/bin/sh\n",
+        )
+        .unwrap();
+        std::fs::write(root.path().join("etc/group"), "sudo:x:27:\n").unwrap();
+        let runner = Runner::new().root_dir(root.path());
+
+        let user = get_user_by_name("azureuser", &runner).unwrap().unwrap();
+        assert_eq!((user.uid.as_raw(), user.gid.as_raw()), (1000, 1001));
+        assert_eq!(user.dir, std::path::Path::new("/home/azureuser"));
+        assert_eq!(get_user_by_uid(0, &runner).unwrap().unwrap().name, "root");
+        assert!(get_user_by_name("nobody", &runner).unwrap().is_none());
+        assert!(group_exists("sudo", &runner).unwrap());
+        assert!(!group_exists("wheel", &runner).unwrap());
+    }
+
+    #[test]
+    fn useradd_set_password() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        assert_eq!(
+            Provisioner::Useradd
+                .set_password("azureuser", "", &runner)
+                .unwrap(),
+            "passwd"
+        );
+        assert_eq!(
+            Provisioner::Useradd
+                .set_password("azureuser", "$6$salt$hash", &runner)
+                .unwrap(),
+            "chpasswd"
+        );
+
+        let commands = recorder.commands();
+        assert_eq!(commands[0].program, "passwd");
+        assert_eq!(commands[0].args, ["-d", "azureuser"]);
+        assert_eq!(commands[1].program, "chpasswd");
+        assert_eq!(commands[1].args, ["-e"]);
+        assert_eq!(
+            commands[1].input.as_deref(),
+            Some(&b"azureuser:$6$salt$hash\n"[..])
+        );
+    }
+
+    #[test]
+    fn lock_password_arguments() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        lock_password("root", &runner).unwrap();
+
+        let commands = recorder.commands();
+        assert_eq!(commands[0].program, "passwd");
+        assert_eq!(commands[0].args, ["-l", "root"]);
+    }
+
+    #[test]
+    fn write_sudoers_validates_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+
+        let path =
+            write_sudoers(dir.path(), "azureuser", true, &runner).unwrap();
+
+        assert_eq!(path, dir.path().join("90-azure-init-azureuser"));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "azureuser ALL=(ALL) NOPASSWD:ALL\n"
+        );
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o440
+        );
+        let commands = recorder.commands();
+        assert_eq!(commands[0].program, "visudo");
+        assert_eq!(commands[0].args[0], "-cf");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn write_sudoers_removes_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        recorder.fail("visudo", 1, "syntax error");
+        let runner = Runner::new().command_runner(recorder);
+
+        match write_sudoers(dir.path(), "azureuser", false, &runner) {
+            Err(Error::SubprocessFailed { command, .. }) => {
+                assert_eq!(command, "visudo")
+            }
+            _ => panic!("An invalid sudoers file should be rejected"),
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn useradd_failure_includes_stderr() {
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        recorder.fail("useradd", 9, "useradd: user 'azureuser' already exists");
+        let runner = Runner::new().command_runner(recorder);
+
+        match create_user(
+            &User::new("azureuser"),
+            std::path::Path::new("/home/azureuser"),
+            &[],
+            &[Provisioner::Useradd],
+            &runner,
+        ) {
+            Err(Error::SubprocessFailed {
+                command, stderr, ..
+            }) => {
+                assert_eq!(command, "useradd");
+                assert!(stderr.contains("already exists"));
+            }
+            _ => panic!("A failing useradd should fail to create the user"),
+        }
+    }
+
+    #[test]
+    fn reconcile_groups_appends_missing_groups() {
+        let etc = test_etc_dir();
+        let recorder = std::sync::Arc::new(RecordingCommandRunner::new());
+        let runner = Runner::new().command_runner(recorder.clone());
+        let groups = ["sudo".to_string(), "adm".to_string(), "nope".into()];
+
+        reconcile_groups(
+            &Provisioner::Useradd,
+            etc.path(),
+            "existing",
+            &groups,
+            &runner,
+        )
+        .unwrap();
+
+        let commands = recorder.commands();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].program, "usermod");
+        assert_eq!(
+            commands[0].args,
+            ["--append", "--groups", "adm", "existing"]
+        );
+
+        reconcile_groups(
+            &Provisioner::DirectEtcFiles,
+            etc.path(),
+            "existing",
+            &groups,
+            &runner,
+        )
+        .unwrap();
+
+        let group = std::fs::read_to_string(etc.path().join("group")).unwrap();
+        assert_eq!(
+            group,
+            "root:x:0:\nsudo:x:27:existing\nadm:x:4:existing\n\
+            existing:x:1000:\n"
+        );
+
+        // Once the user is in every group, there is nothing left to do.
+        reconcile_groups(
+            &Provisioner::Useradd,
+            etc.path(),
+            "existing",
+            &groups,
+            &runner,
+        )
+        .unwrap();
+        assert_eq!(recorder.commands().len(), 1);
     }
 }
@@ -0,0 +1,204 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Writing files with an audit trail. Every file provisioning creates or
+//! modifies goes through [`write_file`], which logs its path, size and
+//! SHA-256, so what azure-init left on the filesystem can be checked
+//! afterwards.
+
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{Gid, Uid};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+
+/// A file written by provisioning, as listed in the provisioning report.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WrittenFile {
+    pub path: PathBuf,
+    /// Size in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA-256 of the contents.
+    pub sha256: String,
+}
+
+impl WrittenFile {
+    /// Describe `bytes` written to `path`.
+    pub fn new(path: &Path, bytes: &[u8]) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            size: bytes.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(bytes)),
+        }
+    }
+}
+
+/// Replace the contents of `path` with `bytes`, giving it the permission
+/// bits `mode` and, if set, the `(uid, gid)` ownership `owner`.
+///
+/// The file is written to a temporary file in the same directory and
+/// renamed into place, so readers never see it partially written. The
+/// write is logged with the file's size and SHA-256, which are returned.
+///
+/// The directory may belong to a user, as `~/.ssh` does, so the temporary
+/// file gets an unpredictable name and is only ever created, never opened
+/// through a symlink or an existing file, and its mode and owner are set
+/// on the open file rather than by path.
+pub fn write_file(
+    path: &Path,
+    bytes: &[u8],
+    mode: u32,
+    owner: Option<(u32, u32)>,
+) -> Result<WrittenFile, Error> {
+    let tmp_path = tmp_path(path);
+    let mut file = match create_tmp_file(&tmp_path, mode) {
+        Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+            // Removing a symlink removes the link, not its target.
+            fs::remove_file(&tmp_path)?;
+            create_tmp_file(&tmp_path, mode)?
+        }
+        result => result?,
+    };
+
+    let result = fill_tmp_file(&mut file, bytes, mode, owner)
+        .and_then(|()| Ok(fs::rename(&tmp_path, path)?));
+    if let Err(error) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(error);
+    }
+
+    let written = WrittenFile::new(path, bytes);
+    tracing::info!(
+        path = %path.display(),
+        size = written.size,
+        sha256 = written.sha256,
+        "Wrote file"
+    );
+
+    Ok(written)
+}
+
+// A hidden file next to `path` with a random suffix, so its name can't be
+// guessed in advance.
+fn tmp_path(path: &Path) -> PathBuf {
+    let random = RandomState::new().build_hasher().finish();
+    let mut file_name = std::ffi::OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(format!(".{random:016x}.tmp"));
+
+    path.with_file_name(file_name)
+}
+
+fn create_tmp_file(path: &Path, mode: u32) -> std::io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(mode)
+        .open(path)
+}
+
+fn fill_tmp_file(
+    file: &mut fs::File,
+    bytes: &[u8],
+    mode: u32,
+    owner: Option<(u32, u32)>,
+) -> Result<(), Error> {
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    // The mode given to open is filtered through the umask.
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    if let Some((uid, gid)) = owner {
+        nix::unistd::fchown(
+            file.as_raw_fd(),
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use super::{create_tmp_file, tmp_path, write_file};
+
+    #[test]
+    fn test_write_file_reports_size_and_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hostname");
+
+        let written = write_file(&path, b"hello\n", 0o640, None).unwrap();
+
+        assert_eq!(written.path, path);
+        assert_eq!(written.size, 6);
+        assert_eq!(
+            written.sha256,
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"hello\n");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+        // Nothing but the file itself is left behind.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_file_tmp_names_differ() {
+        let path = std::path::Path::new("/etc/hostname");
+
+        let (first, second) = (tmp_path(path), tmp_path(path));
+
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), path.parent());
+        assert!(first
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".hostname."));
+    }
+
+    #[test]
+    fn test_create_tmp_file_refuses_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("shadow");
+        fs::write(&target, "root:secret\n").unwrap();
+        let link = dir.path().join(".authorized_keys.tmp");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        assert!(create_tmp_file(&link, 0o600).is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "root:secret\n");
+    }
+
+    #[test]
+    fn test_write_file_replaces_contents_and_sets_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("passwd");
+        fs::write(&path, "old contents that are longer\n").unwrap();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+
+        let written = write_file(&path, b"", 0o644, Some((uid, gid))).unwrap();
+
+        assert_eq!(written.size, 0);
+        assert_eq!(
+            written.sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        assert_eq!(fs::metadata(&path).unwrap().uid(), uid);
+    }
+}
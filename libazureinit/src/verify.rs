@@ -0,0 +1,151 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Checks that a provisioned VM ended up in the expected state.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::user::{self, PublicKeys, SshKeyOptions};
+
+/// Check that `username` exists and return its home directory.
+pub fn verify_user(username: &str) -> Result<PathBuf, Error> {
+    let user =
+        nix::unistd::User::from_name(username)?.ok_or(Error::UserMissing {
+            user: username.to_string(),
+        })?;
+
+    Ok(user.dir)
+}
+
+/// Check that the file sshd reads `username`'s keys from holds at least one
+/// valid key, and return how many it holds.
+///
+/// The file is `authorized_keys` in the `.ssh` directory under `home`, or
+/// the user's file in `options.authorized_keys_dir` when that is set.
+pub fn verify_authorized_keys(
+    username: &str,
+    home: &Path,
+    options: &SshKeyOptions,
+) -> Result<usize, Error> {
+    let path = match &options.authorized_keys_dir {
+        Some(dir) => dir.join(username),
+        None => home.join(".ssh").join("authorized_keys"),
+    };
+
+    let count = match fs::read_to_string(&path) {
+        Ok(contents) => count_valid_keys(&contents),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(error) => return Err(error.into()),
+    };
+
+    match count {
+        0 => Err(Error::NoAuthorizedKeys { path }),
+        count => Ok(count),
+    }
+}
+
+/// Check that the running hostname is `expected`.
+pub fn verify_hostname(expected: &str) -> Result<(), Error> {
+    let actual = nix::unistd::gethostname()?;
+
+    check_hostname(expected, &actual.to_string_lossy())
+}
+
+// Hostnames are case-insensitive.
+fn check_hostname(expected: &str, actual: &str) -> Result<(), Error> {
+    match expected.eq_ignore_ascii_case(actual) {
+        true => Ok(()),
+        false => Err(Error::HostnameMismatch {
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        }),
+    }
+}
+
+// Count the authorized_keys entries holding a valid key, ignoring any
+// options in front of the key type.
+fn count_valid_keys(contents: &str) -> usize {
+    contents
+        .lines()
+        .filter(|entry| {
+            let key = PublicKeys::from_authorized_keys_line(entry);
+            user::validate_ssh_key(&key.key_data).is_ok()
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{check_hostname, count_valid_keys, verify_authorized_keys};
+    use crate::error::Error;
+    use crate::user::SshKeyOptions;
+
+    const ED25519_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILxMIRh0Y6wJRCx8a8TJU16X5OKlpiUvjpESaRZ3xICs test_key_1";
+
+    #[test]
+    fn test_count_valid_keys() {
+        let contents = format!(
+            "# a comment\n\n{ED25519_KEY}\nno-pty {ED25519_KEY}\nssh-rsa notbase64\n"
+        );
+
+        assert_eq!(count_valid_keys(&contents), 2);
+        assert_eq!(count_valid_keys(""), 0);
+    }
+
+    #[test]
+    fn test_verify_authorized_keys() {
+        let home = tempfile::tempdir().unwrap();
+        let options = SshKeyOptions::default();
+
+        match verify_authorized_keys("azureuser", home.path(), &options) {
+            Err(Error::NoAuthorizedKeys { path }) => {
+                assert_eq!(path, home.path().join(".ssh/authorized_keys"))
+            }
+            _ => panic!("A missing authorized_keys file should fail"),
+        }
+
+        fs::create_dir(home.path().join(".ssh")).unwrap();
+        fs::write(
+            home.path().join(".ssh/authorized_keys"),
+            format!("{ED25519_KEY}\n"),
+        )
+        .unwrap();
+        assert_eq!(
+            verify_authorized_keys("azureuser", home.path(), &options).unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_verify_authorized_keys_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("azureuser"), "ssh-ed25519 AAAA\n").unwrap();
+        let options = SshKeyOptions {
+            authorized_keys_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+
+        match verify_authorized_keys("azureuser", dir.path(), &options) {
+            Err(Error::NoAuthorizedKeys { path }) => {
+                assert_eq!(path, dir.path().join("azureuser"))
+            }
+            _ => panic!("A file without valid keys should fail"),
+        }
+    }
+
+    #[test]
+    fn test_check_hostname() {
+        assert!(check_hostname("myvm", "MyVM").is_ok());
+        match check_hostname("myvm", "localhost") {
+            Err(Error::HostnameMismatch { expected, actual }) => {
+                assert_eq!(expected, "myvm");
+                assert_eq!(actual, "localhost");
+            }
+            _ => panic!("A different hostname should fail"),
+        }
+    }
+}
@@ -1,137 +1,607 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-use std::path::PathBuf;
+// Log through tracing; only subcommands whose output is their result print.
+#![warn(clippy::print_stdout, clippy::print_stderr)]
+
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+#[cfg(feature = "imds")]
+use std::time::Duration;
 
 use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use tracing::Instrument;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+use uuid::Uuid;
 
-use libazureinit::distro::{Distribution, Distributions};
 use libazureinit::{
+    command::Runner,
+    config,
+    config::Config,
     error::Error as LibError,
-    goalstate, imds, media,
-    media::{Environment, Media},
+    platform, provision,
+    provision::{MetadataSource, ProvisioningData, ProvisioningOptions},
+    report,
+    report::ProvisioningReport,
+};
+#[cfg(feature = "imds")]
+use libazureinit::{
+    http, imds,
     reqwest::{header, Client},
-    user,
+    verify,
 };
 
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "progress")]
+mod progress;
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-// Mount the given device, get OVF environment data, return it.
-fn mount_parse_ovf_env(dev: String) -> Result<Environment, anyhow::Error> {
-    let mount_media =
-        Media::new(PathBuf::from(dev), PathBuf::from(media::PATH_MOUNT_POINT));
-    let mounted = mount_media
-        .mount()
-        .with_context(|| "Failed to mount media.")?;
+/// Provision a Linux VM on Azure from its instance metadata.
+#[derive(Parser, Debug)]
+#[command(version = VERSION)]
+struct Args {
+    /// Path to write the JSON provisioning report to.
+    #[arg(long, default_value = report::DEFAULT_REPORT_PATH)]
+    report_path: PathBuf,
+
+    /// Format of the logs written to stderr.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Path to the TOML configuration file. Defaults to
+    /// /etc/azure-init.toml, if it exists.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Maximum level of log events to emit, overriding the configuration
+    /// file.
+    #[arg(long)]
+    log_level: Option<tracing::Level>,
+
+    /// Log the commands and file writes provisioning would perform
+    /// without making any changes.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Save the IMDS response to this path after querying IMDS. Defaults to
+    /// /var/lib/azure-init/imds.json if no path is given.
+    #[cfg(feature = "imds")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = imds::DEFAULT_IMDS_CACHE_PATH
+    )]
+    imds_cache: Option<PathBuf>,
+
+    /// Read the IMDS response from the --imds-cache path instead of
+    /// querying IMDS.
+    #[cfg(feature = "imds")]
+    #[arg(long, requires = "imds_cache")]
+    from_imds_cache: bool,
+
+    /// Keep querying IMDS until it responds or this much time, e.g. 120s or
+    /// 2m, has passed, then fall back to the provisioning media. Counts
+    /// towards the provisioning timeout.
+    #[cfg(feature = "imds")]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    wait_for_imds: Option<Duration>,
+
+    /// Comma-separated supplementary groups for the admin user, overriding
+    /// the configuration file and the distribution's default groups.
+    #[arg(long, value_name = "GROUPS", value_delimiter = ',')]
+    groups: Option<Vec<String>>,
 
-    let ovf_body = mounted.read_ovf_env_to_string()?;
-    let environment = media::parse_ovf_env(ovf_body.as_str())?;
+    /// Run even if this doesn't look like an Azure VM. Without it,
+    /// azure-init exits with 71 (EX_OSERR) on other machines rather than
+    /// waiting for platform endpoints that aren't there.
+    #[arg(long)]
+    force: bool,
 
-    mounted
-        .unmount()
-        .with_context(|| "Failed to remove media.")?;
+    /// Provision the system whose root filesystem is mounted at this path,
+    /// such as an image being built, overriding the configuration file.
+    #[arg(long, value_name = "PATH")]
+    root_dir: Option<PathBuf>,
 
-    Ok(environment)
+    /// Listen on a Unix socket at this path and send each client JSON
+    /// lines as provisioning phases start, complete or fail. Defaults to
+    /// /run/azure-init/progress.sock if no path is given.
+    #[cfg(feature = "progress")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = progress::DEFAULT_SOCKET_PATH
+    )]
+    progress_socket: Option<PathBuf>,
+
+    /// Comma-separated provisioning phases to leave out, in addition to
+    /// those skipped in the configuration file.
+    #[arg(long, value_name = "PHASES", value_delimiter = ',', value_enum)]
+    skip: Vec<Phase>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn get_username(imds_body: String) -> Result<String, anyhow::Error> {
-    if imds::is_password_authentication_disabled(&imds_body)? {
-        // password authentication is disabled
-        Ok(imds::get_username(imds_body.clone())?)
-    } else {
-        // password authentication is enabled
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check that provisioning succeeded: the admin user from IMDS exists,
+    /// has at least one valid authorized SSH key, and the hostname matches
+    /// IMDS.
+    ///
+    /// Exits with 67 (EX_NOUSER) if the user is missing, 66 (EX_NOINPUT)
+    /// if it has no valid keys, 65 (EX_DATAERR) if the hostname differs,
+    /// or 69 (EX_UNAVAILABLE) if IMDS can't be read; the first failed check
+    /// decides the exit code.
+    #[cfg(feature = "imds")]
+    Verify,
+    /// Print the metadata provisioning would use as JSON, without changing
+    /// anything.
+    ///
+    /// Exits with 69 (EX_UNAVAILABLE) if the metadata can't be read.
+    Query {
+        /// Where to read the metadata from. Defaults to the configured
+        /// metadata source.
+        #[arg(long, value_enum)]
+        source: Option<QuerySource>,
+    },
+}
 
-        // list of CDROM devices that is available with possible filesystems.
-        let ovf_devices = media::get_mount_device()?;
-        let mut environment: Option<Environment> = None;
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum QuerySource {
+    #[cfg(feature = "imds")]
+    Imds,
+    Ovf,
+    ConfigDrive,
+}
 
-        // loop until it finds a correct device.
-        for dev in ovf_devices {
-            environment = match mount_parse_ovf_env(dev) {
-                Ok(env) => Some(env),
-                Err(_) => continue,
-            }
+impl From<QuerySource> for MetadataSource {
+    fn from(source: QuerySource) -> Self {
+        match source {
+            #[cfg(feature = "imds")]
+            QuerySource::Imds => MetadataSource::Imds,
+            QuerySource::Ovf => MetadataSource::Ovf,
+            QuerySource::ConfigDrive => MetadataSource::ConfigDrive,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Phase {
+    /// Don't create users; they must already exist.
+    User,
+    /// Don't set the password of created users.
+    Password,
+    /// Don't set the hostname.
+    Hostname,
+    /// Don't install SSH keys or configure sshd.
+    Ssh,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per event, for ingestion by log pipelines.
+    Json,
+}
+
+fn init_logging(
+    format: LogFormat,
+    level: tracing::Level,
+    #[cfg(feature = "progress")] progress: Option<progress::ProgressLayer>,
+) {
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let fmt = match format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+    // Each layer has its own filter, so progress is reported from the
+    // info-level phase spans however quiet the logs are.
+    let level = LevelFilter::from_level(level);
+    let subscriber =
+        tracing_subscriber::registry().with(fmt.with_filter(level));
+    #[cfg(feature = "progress")]
+    let subscriber = subscriber.with(progress.with_filter(LevelFilter::INFO));
+
+    #[cfg(feature = "otel")]
+    {
+        let (layer, error) = match otel::layer() {
+            Ok(layer) => (layer, None),
+            Err(error) => (None, Some(error)),
+        };
+        subscriber.with(layer.with_filter(level)).init();
+        if let Some(error) = error {
+            tracing::warn!(
+                error = format!("{error:#}"),
+                "Failed to set up OpenTelemetry export"
+            );
         }
+    }
+    #[cfg(not(feature = "otel"))]
+    subscriber.init();
+}
+
+// Parse a number of seconds, minutes or hours, e.g. "90", "90s" or "2m".
+#[cfg(feature = "imds")]
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{value}'"))?;
+    let secs = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        _ => return Err(format!("unknown unit '{unit}', expected s, m or h")),
+    };
+
+    Ok(Duration::from_secs(secs))
+}
+
+fn load_config(path: Option<&Path>) -> Result<Config, anyhow::Error> {
+    let default_path = Path::new(config::DEFAULT_CONFIG_PATH);
+    let path = match path {
+        Some(path) => path,
+        None if default_path.exists() => default_path,
+        None => return Ok(Config::default()),
+    };
+
+    Config::load(path).with_context(|| {
+        format!("Failed to load configuration from {}", path.display())
+    })
+}
+
+fn log_level(
+    args: &Args,
+    config: &Config,
+) -> Result<tracing::Level, anyhow::Error> {
+    if let Some(level) = args.log_level {
+        return Ok(level);
+    }
 
-        Ok(environment
-            .ok_or_else(|| {
-                anyhow::anyhow!("Unable to get list of block devices")
-            })?
-            .provisioning_section
-            .linux_prov_conf_set
-            .username)
+    match &config.log_level {
+        Some(level) => level
+            .parse()
+            .with_context(|| format!("Invalid log level '{level}'")),
+        None => Ok(tracing::Level::INFO),
     }
 }
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    match provision().await {
-        Ok(_) => ExitCode::SUCCESS,
+    let code = run().await;
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+    #[cfg(feature = "progress")]
+    progress::shutdown();
+
+    code
+}
+
+async fn run() -> ExitCode {
+    let args = Args::parse();
+    let config: u8 = exitcode::CONFIG
+        .try_into()
+        .expect("Error code must be less than 256");
+
+    // Progress is only reported, so a socket that can't be created doesn't
+    // stop provisioning.
+    #[cfg(feature = "progress")]
+    let (progress, progress_error) =
+        match args.progress_socket.as_deref().map(progress::bind) {
+            Some(Ok(layer)) => (Some(layer), None),
+            Some(Err(error)) => (None, Some(error)),
+            None => (None, None),
+        };
+
+    let mut settings = match load_config(args.config.as_deref())
+        .and_then(|settings| Ok((log_level(&args, &settings)?, settings)))
+    {
+        Ok((level, settings)) => {
+            init_logging(
+                args.log_format,
+                level,
+                #[cfg(feature = "progress")]
+                progress,
+            );
+            settings
+        }
         Err(e) => {
-            eprintln!("{:?}", e);
-            let config: u8 = exitcode::CONFIG
-                .try_into()
-                .expect("Error code must be less than 256");
-            match e.root_cause().downcast_ref::<LibError>() {
-                Some(LibError::UserMissing { user: _ }) => {
-                    ExitCode::from(config)
-                }
-                Some(LibError::NonEmptyPassword) => ExitCode::from(config),
-                Some(_) | None => ExitCode::FAILURE,
-            }
+            init_logging(
+                args.log_format,
+                tracing::Level::INFO,
+                #[cfg(feature = "progress")]
+                progress,
+            );
+            tracing::error!(error = format!("{e:#}"), "Invalid configuration");
+            return ExitCode::from(config);
+        }
+    };
+    #[cfg(feature = "progress")]
+    if let Some(error) = progress_error {
+        tracing::warn!(
+            ?error,
+            path = ?args.progress_socket,
+            "Failed to create the progress socket"
+        );
+    }
+    if let Some(groups) = &args.groups {
+        settings.groups = Some(groups.clone());
+    }
+    if let Some(root_dir) = &args.root_dir {
+        settings.root_dir = root_dir.clone();
+    }
+    for phase in &args.skip {
+        match phase {
+            Phase::User => settings.skip.user = true,
+            Phase::Password => settings.skip.password = true,
+            Phase::Hostname => settings.skip.hostname = true,
+            Phase::Ssh => settings.skip.ssh = true,
         }
     }
+    #[cfg(feature = "imds")]
+    if args.wait_for_imds.is_some() {
+        settings.imds.wait = args.wait_for_imds;
+    }
+
+    if !args.force && !platform::is_azure() {
+        tracing::error!(
+            "This doesn't look like an Azure VM, its DMI system vendor or \
+            chassis asset tag don't match; pass --force to run anyway"
+        );
+        return exit_code(exitcode::OSERR);
+    }
+
+    // Identifies this run in our logs and in the platform services' logs.
+    let correlation_id = Uuid::new_v4().to_string();
+    // Azure services log this header, so their traces of our requests can
+    // be matched with this run.
+    #[cfg(feature = "imds")]
+    settings.http.headers.insert(
+        "x-ms-client-request-id",
+        header::HeaderValue::from_str(&correlation_id)
+            .expect("A UUID is a valid header value"),
+    );
+    let span = tracing::info_span!("azure_init", correlation_id);
+    run_with_settings(&args, &settings, &correlation_id)
+        .instrument(span)
+        .await
 }
 
-async fn provision() -> Result<(), anyhow::Error> {
-    let mut default_headers = header::HeaderMap::new();
-    let user_agent = header::HeaderValue::from_str(
-        format!("azure-init v{VERSION}").as_str(),
-    )?;
-    default_headers.insert(header::USER_AGENT, user_agent);
-    let client = Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .default_headers(default_headers)
-        .build()?;
-    let imds_body = imds::query_imds(&client).await?;
-    let username = get_username(imds_body.clone())
-        .with_context(|| "Failed to retrieve the admin username.")?;
+async fn run_with_settings(
+    args: &Args,
+    settings: &Config,
+    correlation_id: &str,
+) -> ExitCode {
+    #[cfg(feature = "imds")]
+    let client = match http::build_client(&settings.http_config()) {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!(
+                error = format!("{e:#}"),
+                "Failed to build the HTTP client"
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let options = ProvisioningOptions {
+        runner: Runner::new()
+            .dry_run(args.dry_run)
+            .root_dir(&settings.root_dir),
+        #[cfg(feature = "imds")]
+        imds_cache: args.imds_cache.clone(),
+        #[cfg(feature = "imds")]
+        read_imds_cache: args.from_imds_cache,
+        ..Default::default()
+    };
+
+    match args.command {
+        #[cfg(feature = "imds")]
+        Some(Command::Verify) => {
+            return run_verify(&client, args, settings).await;
+        }
+        Some(Command::Query { source }) => {
+            let mut settings = settings.clone();
+            if let Some(source) = source {
+                settings.metadata_source = source.into();
+            }
+            #[cfg(feature = "imds")]
+            let metadata =
+                provision::read_metadata(&client, &settings, &options).await;
+            #[cfg(not(feature = "imds"))]
+            let metadata = provision::read_metadata(&settings, &options).await;
+            return print_metadata(metadata);
+        }
+        None => {}
+    }
+    let mut report = ProvisioningReport::new();
+    report.correlation_id = Some(correlation_id.to_string());
+    // Boot may be torn down while provisioning runs; stop cleanly then,
+    // so the media isn't left mounted and the platform hears about it.
+    let received_signal = std::sync::OnceLock::new();
+    let shutdown = async {
+        let (name, code) = shutdown_signal().await;
+        let _ = received_signal.set(code);
+        format!("received {name}")
+    };
+    let result = provision::provision_until(
+        #[cfg(feature = "imds")]
+        &client,
+        settings,
+        &options,
+        &mut report,
+        shutdown,
+    )
+    .await;
+    if let Err(error) = report.write(&args.report_path) {
+        tracing::warn!(
+            path = %args.report_path.display(),
+            ?error,
+            "Failed to write provisioning report"
+        );
+    }
 
-    let mut file_path = "/home/".to_string();
-    file_path.push_str(username.as_str());
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e @ LibError::ProvisionCancelled { .. }) => {
+            tracing::error!(error = format!("{e:#}"), "Provisioning cancelled");
+            received_signal
+                .get()
+                .map_or(ExitCode::FAILURE, |&code| ExitCode::from(code))
+        }
+        Err(e) => {
+            tracing::error!(error = format!("{e:#}"), "Provisioning failed");
+            // Errors caused by bad input are reported as configuration
+            // errors, and trouble with the provisioning media, often a
+            // platform issue, as I/O errors, even when wrapped by another
+            // error.
+            let code = std::iter::successors(
+                Some(&e as &(dyn std::error::Error + 'static)),
+                |e| e.source(),
+            )
+            .find_map(|e| match e.downcast_ref::<LibError>() {
+                Some(LibError::UserMissing { .. })
+                | Some(LibError::NonEmptyPassword) => Some(exitcode::CONFIG),
+                Some(LibError::MountFailed { .. })
+                | Some(LibError::NoProvisioningMedia) => Some(exitcode::IOERR),
+                _ => None,
+            });
+            match code {
+                Some(code) => exit_code(code),
+                None => ExitCode::FAILURE,
+            }
+        }
+    }
+}
 
-    // always pass an empty password
-    Distributions::from("ubuntu")
-        .create_user(username.as_str(), "")
-        .with_context(|| format!("Unabled to create user '{username}'"))?;
+// The metadata is the command's output, so it goes to stdout.
+#[allow(clippy::print_stdout)]
+fn print_metadata(metadata: Result<ProvisioningData, LibError>) -> ExitCode {
+    let json = metadata
+        .map_err(anyhow::Error::from)
+        .and_then(|metadata| Ok(serde_json::to_string_pretty(&metadata)?));
 
-    user::create_ssh_directory(username.as_str(), &file_path)
-        .await
-        .with_context(|| "Failed to create ssh directory.")?;
+    match json {
+        Ok(json) => {
+            println!("{json}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            tracing::error!(
+                error = format!("{e:#}"),
+                "Failed to read the metadata"
+            );
+            exit_code(exitcode::UNAVAILABLE)
+        }
+    }
+}
 
-    let keys = imds::get_ssh_keys(imds_body.clone())
-        .with_context(|| "Failed to get ssh public keys.")?;
+// Check the provisioned state against IMDS, printing one line per check.
+// The summary is the command's output, so it goes to stdout.
+#[cfg(feature = "imds")]
+#[allow(clippy::print_stdout)]
+async fn run_verify(
+    client: &Client,
+    args: &Args,
+    settings: &Config,
+) -> ExitCode {
+    let imds_body = match (&args.imds_cache, args.from_imds_cache) {
+        (Some(path), true) => imds::read_imds_cache(path),
+        _ => {
+            imds::query_imds_with_config(
+                client,
+                &settings.imds,
+                &settings.http_config(),
+            )
+            .await
+        }
+    };
+    let metadata = imds_body.and_then(|body| {
+        Ok((
+            imds::get_username(body.clone())?,
+            imds::get_hostname(body, false)?,
+        ))
+    });
+    let (username, hostname) = match metadata {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            println!("imds: FAILED: {e:#}");
+            return exit_code(exitcode::UNAVAILABLE);
+        }
+    };
 
-    file_path.push_str("/.ssh");
+    let mut failures = Vec::new();
+    match verify::verify_user(&username) {
+        Ok(home) => {
+            println!("user: ok ({username})");
+            match verify::verify_authorized_keys(
+                &username,
+                &home,
+                &settings.ssh,
+            ) {
+                Ok(count) => println!("ssh_keys: ok ({count} keys)"),
+                Err(e) => {
+                    println!("ssh_keys: FAILED: {e:#}");
+                    failures.push(exitcode::NOINPUT);
+                }
+            }
+        }
+        Err(e) => {
+            println!("user: FAILED: {e:#}");
+            println!("ssh_keys: skipped");
+            failures.push(exitcode::NOUSER);
+        }
+    }
+    match verify::verify_hostname(&hostname) {
+        Ok(()) => println!("hostname: ok ({hostname})"),
+        Err(e) => {
+            println!("hostname: FAILED: {e:#}");
+            failures.push(exitcode::DATAERR);
+        }
+    }
 
-    user::set_ssh_keys(keys, username.to_string(), file_path.clone())
-        .await
-        .with_context(|| "Failed to write ssh public keys.")?;
+    match failures.first() {
+        Some(&code) => exit_code(code),
+        None => ExitCode::SUCCESS,
+    }
+}
 
-    let hostname = imds::get_hostname(imds_body.clone())
-        .with_context(|| "Failed to get the configured hostname")?;
+// Wait for SIGTERM or SIGINT, returning the signal's name and the exit
+// code that conventionally reports being stopped by it, 128 plus its
+// number. Without signal handlers, this never returns.
+async fn shutdown_signal() -> (&'static str, u8) {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    Distributions::from("ubuntu")
-        .set_hostname(hostname.as_str())
-        .with_context(|| "Failed to set hostname.")?;
+    let (terminate, interrupt) =
+        (SignalKind::terminate(), SignalKind::interrupt());
+    let (mut sigterm, mut sigint) = match (signal(terminate), signal(interrupt))
+    {
+        (Ok(sigterm), Ok(sigint)) => (sigterm, sigint),
+        (Err(error), _) | (_, Err(error)) => {
+            tracing::warn!(?error, "Unable to handle shutdown signals");
+            return std::future::pending().await;
+        }
+    };
+    let code = |kind: SignalKind| 128 + kind.as_raw_value() as u8;
 
-    let vm_goalstate = goalstate::get_goalstate(&client)
-        .await
-        .with_context(|| "Failed to get desired goalstate.")?;
-    goalstate::report_health(&client, vm_goalstate)
-        .await
-        .with_context(|| "Failed to report VM health.")?;
+    tokio::select! {
+        _ = sigterm.recv() => ("SIGTERM", code(terminate)),
+        _ = sigint.recv() => ("SIGINT", code(interrupt)),
+    }
+}
 
-    Ok(())
+fn exit_code(code: exitcode::ExitCode) -> ExitCode {
+    ExitCode::from(u8::try_from(code).expect("Exit codes are less than 256"))
 }
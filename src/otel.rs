@@ -0,0 +1,65 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Export tracing spans to an OpenTelemetry collector over OTLP/HTTP.
+
+use std::sync::OnceLock;
+
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Spans are only exported when this is set to the collector's base URL,
+/// e.g. `http://localhost:4318`.
+pub const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+/// A layer exporting spans to the collector named by [`ENDPOINT_ENV`], or
+/// `None` if it isn't set.
+pub fn layer<S>(
+) -> Result<Option<OpenTelemetryLayer<S, SdkTracer>>, anyhow::Error>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if std::env::var_os(ENDPOINT_ENV).is_none() {
+        return Ok(None);
+    }
+
+    // The exporter's blocking HTTP client can't be created on a runtime
+    // thread.
+    let provider =
+        std::thread::spawn(build_provider).join().map_err(|_| {
+            anyhow::anyhow!("Building the OTLP exporter panicked")
+        })??;
+    let tracer = provider.tracer("azure-init");
+    let _ = PROVIDER.set(provider);
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+fn build_provider() -> Result<SdkTracerProvider, anyhow::Error> {
+    // The exporter reads the endpoint from the environment itself.
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder().with_service_name("azure-init").build(),
+        )
+        .build())
+}
+
+/// Export any spans that are still buffered. Call this before exiting.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(error) = provider.shutdown() {
+            tracing::warn!(?error, "Failed to export OpenTelemetry spans");
+        }
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Stream provisioning progress as JSON lines over a Unix domain socket.
+//!
+//! Each provisioning phase, such as `imds` or `user`, produces a `started`
+//! event when it begins and a `completed` or `failed` event when it ends,
+//! e.g.
+//!
+//! ```text
+//! {"status":"started","phase":"user","username":"azureuser"}
+//! {"status":"completed","phase":"user","username":"azureuser","duration_ms":412}
+//! ```
+//!
+//! A client connecting mid-run first receives the events so far, then new
+//! ones as they happen. Clients that stop reading are disconnected rather
+//! than holding up provisioning.
+
+use std::fs::Permissions;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Where the socket is created when `--progress-socket` is given without a
+/// path.
+pub const DEFAULT_SOCKET_PATH: &str = "/run/azure-init/progress.sock";
+
+// Phase spans are those carrying this field, which is recorded when the
+// phase ends; see `phase_span!` in libazureinit.
+const OUTCOME_FIELD: &str = "outcome";
+
+static SOCKET_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Clients {
+    // Every event so far, replayed to clients that connect late.
+    history: Vec<String>,
+    streams: Vec<UnixStream>,
+}
+
+impl Clients {
+    fn send(&mut self, line: String) {
+        self.streams
+            .retain_mut(|stream| write_line(stream, &line).is_ok());
+        self.history.push(line);
+    }
+
+    fn connect(&mut self, mut stream: UnixStream) {
+        // A client that can't take the backlog without blocking is dropped.
+        if stream.set_nonblocking(true).is_err() {
+            return;
+        }
+        if self
+            .history
+            .iter()
+            .all(|line| write_line(&mut stream, line).is_ok())
+        {
+            self.streams.push(stream);
+        }
+    }
+}
+
+fn write_line(stream: &mut UnixStream, line: &str) -> std::io::Result<()> {
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\n")
+}
+
+/// A tracing layer sending phase events to the clients of a progress
+/// socket.
+#[derive(Debug, Clone)]
+pub struct ProgressLayer {
+    clients: Arc<Mutex<Clients>>,
+}
+
+/// Listen on a Unix socket at `path`, replacing any stale socket there, and
+/// return the layer whose events are sent to its clients.
+///
+/// Whatever is at `path` is removed first, so it must be in a directory only
+/// root can write to, such as the default `/run/azure-init`. The socket is
+/// only accessible by its owner, since events carry usernames.
+///
+/// Connections are accepted on a background thread. Call [`shutdown`]
+/// before exiting to remove the socket.
+pub fn bind(path: &Path) -> std::io::Result<ProgressLayer> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() != ErrorKind::NotFound => return Err(error),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    let _ = SOCKET_PATH.set(path.to_path_buf());
+
+    let layer = ProgressLayer {
+        clients: Arc::default(),
+    };
+    let clients = layer.clients.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            clients.lock().unwrap().connect(stream);
+        }
+    });
+
+    Ok(layer)
+}
+
+/// Remove the progress socket, if one was created.
+pub fn shutdown() {
+    if let Some(path) = SOCKET_PATH.get() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+// The fields of a phase span, kept until the phase ends.
+#[derive(Debug, Default)]
+struct PhaseFields(Map<String, Value>);
+
+impl Visit for PhaseFields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+impl ProgressLayer {
+    fn send(&self, status: &str, phase: &str, fields: &Map<String, Value>) {
+        let mut event = Map::new();
+        event.insert("status".to_string(), status.into());
+        event.insert("phase".to_string(), phase.into());
+        for (name, value) in fields {
+            if name != OUTCOME_FIELD {
+                event.insert(name.clone(), value.clone());
+            }
+        }
+
+        let line = Value::Object(event).to_string();
+        self.clients.lock().unwrap().send(line);
+    }
+}
+
+impl<S> Layer<S> for ProgressLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &Attributes<'_>,
+        id: &Id,
+        ctx: Context<'_, S>,
+    ) {
+        if attrs.metadata().fields().field(OUTCOME_FIELD).is_none() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut fields = PhaseFields::default();
+        attrs.record(&mut fields);
+        self.send("started", span.name(), &fields.0);
+        span.extensions_mut().insert(fields);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<PhaseFields>()
+            {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(fields) = extensions.get::<PhaseFields>() else {
+            return;
+        };
+
+        let status = match fields.0.get(OUTCOME_FIELD) {
+            Some(Value::String(outcome)) if outcome == "success" => "completed",
+            _ => "failed",
+        };
+        self.send(status, span.name(), &fields.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    use serde_json::Value;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::bind;
+
+    fn read_events(reader: &mut impl BufRead, count: usize) -> Vec<Value> {
+        (0..count)
+            .map(|_| {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                serde_json::from_str(&line).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_phase_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run").join("progress.sock");
+        let layer = bind(&path).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+
+        let early = UnixStream::connect(&path).unwrap();
+        // Wait for the listener to take the connection.
+        std::thread::sleep(std::time::Duration::from_millis(50));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "user",
+                outcome = tracing::field::Empty,
+                duration_ms = tracing::field::Empty,
+                username = "azureuser"
+            );
+            span.record("duration_ms", 12u64);
+            span.record("outcome", "success");
+            drop(span);
+
+            let span =
+                tracing::info_span!("ssh", outcome = tracing::field::Empty);
+            span.record("outcome", "failure");
+            drop(span);
+
+            // Spans other than phases are ignored.
+            drop(tracing::info_span!("azure_init"));
+        });
+
+        let expected = [
+            r#"{"status":"started","phase":"user","username":"azureuser"}"#,
+            r#"{"status":"completed","phase":"user","username":"azureuser","duration_ms":12}"#,
+            r#"{"status":"started","phase":"ssh"}"#,
+            r#"{"status":"failed","phase":"ssh"}"#,
+        ]
+        .map(|line| serde_json::from_str::<Value>(line).unwrap());
+        assert_eq!(read_events(&mut BufReader::new(early), 4), expected);
+
+        // Clients connecting later get the events so far.
+        let late = UnixStream::connect(&path).unwrap();
+        assert_eq!(read_events(&mut BufReader::new(late), 4), expected);
+    }
+
+    #[test]
+    fn test_socket_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("progress.sock");
+        bind(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -1,12 +1,17 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
+// Exercises IMDS and the wireserver, so there is nothing to run without them.
+#![cfg(feature = "imds")]
+
 use libazureinit::distro::{Distribution, Distributions};
 use libazureinit::imds::PublicKeys;
 use libazureinit::{
-    goalstate,
+    command::Runner,
+    goalstate, hostname,
     reqwest::{header, Client},
     user,
+    user::User,
 };
 
 use std::env;
@@ -23,93 +28,111 @@ async fn main() {
         .build()
         .unwrap();
 
-    println!();
-    println!("**********************************");
-    println!("* Beginning functional testing");
-    println!("**********************************");
-    println!();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
-    println!("Querying wireserver for Goalstate");
+    tracing::info!("Beginning functional testing");
+    tracing::info!("Querying wireserver for Goalstate");
 
-    let get_goalstate_result = goalstate::get_goalstate(&client).await;
+    let wireserver = goalstate::WireserverConfig::default();
+    let get_goalstate_result =
+        goalstate::get_goalstate(&client, &wireserver).await;
     let vm_goalstate = match get_goalstate_result {
         Ok(vm_goalstate) => vm_goalstate,
-        Err(_err) => return,
+        Err(error) => {
+            tracing::error!(?error, "Failed to get the goalstate");
+            return;
+        }
     };
 
-    println!("Goalstate successfully received");
-    println!();
-    println!("Reporting VM Health to wireserver");
+    tracing::info!("Goalstate successfully received");
+    tracing::info!("Reporting VM Health to wireserver");
 
     let report_health_result =
-        goalstate::report_health(&client, vm_goalstate).await;
+        goalstate::report_health(&client, vm_goalstate, &wireserver).await;
     match report_health_result {
         Ok(report_health) => report_health,
-        Err(_err) => return,
+        Err(error) => {
+            tracing::error!(?error, "Failed to report VM health");
+            return;
+        }
     };
 
-    println!("VM Health successfully reported");
+    tracing::info!("VM Health successfully reported");
 
     let username = &cli_args[1];
 
-    let mut file_path = "/home/".to_string();
-    file_path.push_str(username.as_str());
+    let home = User::new(username.as_str())
+        .home_dir()
+        .expect("Invalid home directory");
 
-    println!();
-    println!(
-        "Attempting to create user {} without password",
-        username.as_str()
-    );
+    tracing::info!(username, "Attempting to create user without password");
 
     Distributions::from("ubuntu")
-        .create_user(username.as_str(), "")
+        .create_user(
+            &User::new(username.as_str()),
+            Some(""),
+            user::DEFAULT_PROVISIONERS,
+            &Runner::new(),
+        )
         .expect("Failed to create user");
 
-    println!("User {} was successfully created", username.as_str());
-
-    println!();
-    println!("Attempting to create user's SSH directory");
+    tracing::info!(username, "User was successfully created");
+    tracing::info!("Attempting to create user's SSH directory");
 
     let _create_directory =
-        user::create_ssh_directory(username.as_str(), &file_path).await;
+        user::create_ssh_directory(username.as_str(), &home, &Runner::new())
+            .await;
     match _create_directory {
         Ok(create_directory) => create_directory,
-        Err(_err) => return,
+        Err(error) => {
+            tracing::error!(?error, "Failed to create the SSH directory");
+            return;
+        }
     };
-    println!("User's SSH directory was successfully created");
+    tracing::info!("User's SSH directory was successfully created");
 
     let keys: Vec<PublicKeys> = vec![
         PublicKeys {
-            path: "/path/to/.ssh/keys/".to_owned(),
-            key_data: "ssh-rsa test_key_1".to_owned(),
+            path: String::new(),
+            key_data: "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILxMIRh0Y6wJRCx8a8TJU16X5OKlpiUvjpESaRZ3xICs test_key_1".to_owned(),
+            options: None,
         },
         PublicKeys {
-            path: "/path/to/.ssh/keys/".to_owned(),
-            key_data: "ssh-rsa test_key_2".to_owned(),
+            path: String::new(),
+            key_data: "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICxFw5ZTiL2iaXBgTVrtBeuw78i45Os2L3lB7qMEczqP test_key_2".to_owned(),
+            options: None,
         },
         PublicKeys {
-            path: "/path/to/.ssh/keys/".to_owned(),
-            key_data: "ssh-rsa test_key_3".to_owned(),
+            path: String::new(),
+            key_data: "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEVjIfTj7Xs2k0Z1KQpPx61U+8H2a0/g7sfl+ddKNTe/ test_key_3".to_owned(),
+            options: None,
         },
     ];
 
-    file_path.push_str("/.ssh");
-
-    user::set_ssh_keys(keys, username.to_string(), file_path.clone())
-        .await
-        .unwrap();
+    user::set_ssh_keys(
+        keys,
+        username.to_string(),
+        home.join(".ssh").to_string_lossy().into_owned(),
+        &user::SshKeyOptions {
+            strict: true,
+            ..Default::default()
+        },
+        &Runner::new(),
+    )
+    .await
+    .unwrap();
 
-    println!();
-    println!("Attempting to set the VM hostname");
+    tracing::info!("Attempting to set the VM hostname");
 
     Distributions::from("ubuntu")
-        .set_hostname("test-hostname-set")
+        .set_hostname(
+            "test-hostname-set",
+            hostname::DEFAULT_PROVISIONERS,
+            &Runner::new(),
+        )
         .expect("Failed to set hostname");
-    println!("VM hostname successfully set");
-    println!();
-
-    println!("**********************************");
-    println!("* Functional testing completed successfully!");
-    println!("**********************************");
-    println!();
+    tracing::info!("VM hostname successfully set");
+    tracing::info!("Functional testing completed successfully");
 }
@@ -0,0 +1,245 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Run the azure-init binary against the real useradd, passwd and hostname
+//! backends, isolated from the host.
+//!
+//! The binary runs in new mount and UTS namespaces, with a copy of `/etc`
+//! and an empty directory bind-mounted over `/etc` and `/home`, so the
+//! users and hostname it sets only land in a temporary directory. It reads
+//! its metadata from a config drive directory and reports health to a
+//! wireserver served by the test, with `--force` since the test machine
+//! needn't be an Azure VM.
+//!
+//! The same provisioning is also run with `--root-dir` instead of the
+//! namespaces, as when customizing an image.
+//!
+//! This needs root and unshare(1), so it is ignored by default:
+//!
+//! ```sh
+//! sudo cargo test --test provision_namespace -- --ignored
+//! ```
+
+#![cfg(feature = "imds")]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const ADMIN_USERNAME: &str = "azinit-admin";
+const EXTRA_USERNAME: &str = "azinit-svc";
+const HOSTNAME: &str = "azinit-namespace-test";
+const SSH_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIIG/fjRn7DlF3oOaAj/rA0K/8NiUv4Ky0zQ32gw6ySUD svc@example";
+
+const GOALSTATE_XML: &str = "<Goalstate>
+    <Container>
+        <ContainerId>2</ContainerId>
+        <RoleInstanceList>
+            <RoleInstance>
+                <InstanceId>test_instance_id</InstanceId>
+            </RoleInstance>
+        </RoleInstanceList>
+    </Container>
+    <Version>example_version</Version>
+    <Incarnation>1</Incarnation>
+</Goalstate>";
+
+fn ovf_env() -> String {
+    format!(
+        r#"<Environment xmlns="http://schemas.dmtf.org/ovf/environment/1"
+    xmlns:wa="http://schemas.microsoft.com/windowsazure">
+    <wa:ProvisioningSection>
+        <wa:Version>1.0</wa:Version>
+        <LinuxProvisioningConfigurationSet xmlns="http://schemas.microsoft.com/windowsazure">
+            <ConfigurationSetType>LinuxProvisioningConfiguration</ConfigurationSetType>
+            <UserName>{ADMIN_USERNAME}</UserName>
+            <DisableSshPasswordAuthentication>true</DisableSshPasswordAuthentication>
+            <HostName>{HOSTNAME}</HostName>
+        </LinuxProvisioningConfigurationSet>
+    </wa:ProvisioningSection>
+    <wa:PlatformSettingsSection>
+        <wa:Version>1.0</wa:Version>
+        <PlatformSettings xmlns="http://schemas.microsoft.com/windowsazure">
+            <PreprovisionedVm>false</PreprovisionedVm>
+        </PlatformSettings>
+    </wa:PlatformSettingsSection>
+</Environment>"#
+    )
+}
+
+// Answer every request with the goalstate, which is also an acceptable
+// response to the health report, returning the wireserver's URL.
+fn serve_wireserver() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream);
+        }
+    });
+
+    url
+}
+
+fn respond(stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    reader.read_exact(&mut vec![0; content_length])?;
+
+    write!(
+        reader.get_mut(),
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        GOALSTATE_XML.len(),
+        GOALSTATE_XML
+    )
+}
+
+fn run(command: &mut Command) {
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{command:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn passwd_has_user(etc: &Path, username: &str) -> bool {
+    fs::read_to_string(etc.join("passwd"))
+        .unwrap()
+        .lines()
+        .any(|line| line.starts_with(&format!("{username}:")))
+}
+
+// Lay out a root directory with a copy of /etc, an empty /home and a
+// config drive, returning the configuration file to provision it with.
+fn prepare_root(root: &Path) -> PathBuf {
+    run(Command::new("cp")
+        .arg("-a")
+        .arg("/etc")
+        .arg(root.join("etc")));
+    fs::create_dir(root.join("home")).unwrap();
+
+    let config_drive = root.join("configdrive");
+    fs::create_dir(&config_drive).unwrap();
+    fs::write(config_drive.join("ovf-env.xml"), ovf_env()).unwrap();
+
+    let config_path = root.join("azure-init.toml");
+    fs::write(
+        &config_path,
+        format!(
+            r#"
+            metadata_source = "config_drive"
+            hostname_provisioners = ["etc_hostname"]
+            user_provisioners = ["useradd"]
+            groups = []
+
+            [media]
+            config_drive_paths = ["{}"]
+
+            [wireserver]
+            endpoint = "{}"
+
+            [[users]]
+            name = "{EXTRA_USERNAME}"
+            ssh_keys = ["{SSH_KEY}"]
+            "#,
+            config_drive.display(),
+            serve_wireserver(),
+        ),
+    )
+    .unwrap();
+
+    config_path
+}
+
+fn assert_provisioned(root: &Path, output: Output) {
+    assert!(
+        output.status.success(),
+        "Provisioning failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let etc = root.join("etc");
+    assert!(passwd_has_user(&etc, ADMIN_USERNAME));
+    assert!(passwd_has_user(&etc, EXTRA_USERNAME));
+    assert!(!passwd_has_user(Path::new("/etc"), EXTRA_USERNAME));
+    assert_eq!(
+        fs::read_to_string(etc.join("hostname")).unwrap().trim(),
+        HOSTNAME
+    );
+
+    let authorized_keys = fs::read_to_string(
+        root.join("home")
+            .join(EXTRA_USERNAME)
+            .join(".ssh/authorized_keys"),
+    )
+    .unwrap();
+    assert_eq!(authorized_keys.trim(), SSH_KEY);
+
+    let report: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(root.join("report.json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(report["success"], true);
+}
+
+#[test]
+#[ignore = "needs root and unshare(1)"]
+fn test_provision_in_namespace() {
+    let root = tempfile::tempdir().unwrap();
+    let config_path = prepare_root(root.path());
+
+    let output = Command::new("unshare")
+        .args(["--mount", "--uts", "--propagation", "private", "--"])
+        .args([
+            "sh",
+            "-c",
+            r#"mount --bind "$1/etc" /etc && mount --bind "$1/home" /home \
+                && exec "$2" --force --config "$3" \
+                --report-path "$1/report.json""#,
+            "sh",
+        ])
+        .arg(root.path())
+        .arg(env!("CARGO_BIN_EXE_azure-init"))
+        .arg(&config_path)
+        .output()
+        .unwrap();
+
+    assert_provisioned(root.path(), output);
+}
+
+#[test]
+#[ignore = "needs root"]
+fn test_provision_root_dir() {
+    let root = tempfile::tempdir().unwrap();
+    let config_path = prepare_root(root.path());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_azure-init"))
+        .arg("--force")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("--root-dir")
+        .arg(root.path())
+        .arg("--report-path")
+        .arg(root.path().join("report.json"))
+        .output()
+        .unwrap();
+
+    assert_provisioned(root.path(), output);
+}